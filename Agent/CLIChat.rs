@@ -1,17 +1,31 @@
 use std::io::{self, Write};
-use crate::completion::{Chat, Message, PromptError};
+use std::{future::Future, pin::Pin};
 
-/// Creates a simple REPL (Read-Eval-Print Loop) CLI chatbot using a type that implements the `Chat` trait.
+use futures::stream;
+
+use crate::{
+    agent::Agent,
+    approval::{Approval, ApprovalHandler},
+    completion::{Chat, CompletionError, CompletionModel, Message, PromptError},
+    memory::ConversationError,
+    streaming::{stream_to_stdout, StreamingChat, StreamingChoice, StreamingResult},
+};
+
+/// Creates a simple REPL (Read-Eval-Print Loop) CLI chatbot using a type that implements the `StreamingChat` trait.
 /// 
 /// This function runs an interactive chatbot session where the user can type prompts,
-/// receive responses, and see the chat log evolve. Type 'exit' to quit the session.
+/// see the response printed token by token as it is received from the model, and see the
+/// chat log evolve. Type 'exit' to quit the session.
+///
+/// Tool calls requested by the model are printed once the response is complete. Types that
+/// only implement `Chat` can be used through [WholeResponse].
 /// 
 /// # Arguments
-/// - `chatbot`: An instance of a type that implements the `Chat` trait.
+/// - `chatbot`: An instance of a type that implements the `StreamingChat` trait.
 /// 
 /// # Returns
 /// - `Result<(), PromptError>`: Returns `Ok` on successful completion or an error of type `PromptError`.
-pub async fn cli_chatbot(chatbot: impl Chat) -> Result<(), PromptError> {
+pub async fn cli_chatbot(chatbot: impl StreamingChat) -> Result<(), PromptError> {
     let stdin = io::stdin(); // Standard input for user prompts
     let mut stdout = io::stdout(); // Standard output for displaying messages
     let mut chat_log = vec![]; // Chat history log to keep track of interactions
@@ -25,52 +39,241 @@ pub async fn cli_chatbot(chatbot: impl Chat) -> Result<(), PromptError> {
         stdout.flush().unwrap(); // Ensure the prompt is displayed immediately
 
         let mut input = String::new();
-        
+
         // Read user input from the standard input
-        match stdin.read_line(&mut input) {
-            Ok(_) => {
-                let input = input.trim(); // Remove leading and trailing whitespace
-
-                // Exit condition
-                if input.eq_ignore_ascii_case("exit") {
-                    println!("Goodbye!");
-                    break;
-                }
+        if let Err(error) = stdin.read_line(&mut input) {
+            // Handle errors reading user input
+            eprintln!("Error reading input: {}", error);
+            continue;
+        }
+        let input = input.trim(); // Remove leading and trailing whitespace
 
-                tracing::info!("Prompt:\n{}\n", input); // Log the user's input for debugging
+        // Exit condition
+        if input.eq_ignore_ascii_case("exit") {
+            println!("Goodbye!");
+            break;
+        }
 
-                // Obtain chatbot response asynchronously
-                match chatbot.chat(input, chat_log.clone()).await {
-                    Ok(response) => {
-                        // Add user input and chatbot response to the chat log
-                        chat_log.push(Message {
-                            role: "user".to_string(),
-                            content: input.to_string(),
-                        });
-                        chat_log.push(Message {
-                            role: "assistant".to_string(),
-                            content: response.clone(),
-                        });
+        tracing::info!("Prompt:\n{}\n", input); // Log the user's input for debugging
 
-                        // Display the chatbot's response in a formatted block
-                        println!("========================== Response ============================");
-                        println!("{}", response);
-                        println!("================================================================\n");
+        // Open the response stream, then print each delta as it arrives
+        let response = match chatbot.stream_chat(input, chat_log.clone()).await {
+            Ok(stream) => {
+                println!("========================== Response ============================");
+                let response = stream_to_stdout(stream).await;
+                println!("\n================================================================\n");
+                response
+            }
+            Err(error) => Err(error),
+        };
 
-                        tracing::info!("Response:\n{}\n", response); // Log the chatbot's response for debugging
-                    }
-                    Err(error) => {
-                        // Handle errors from the chatbot
-                        eprintln!("Error generating response: {}", error);
-                    }
+        match response {
+            Ok(response) => {
+                for call in &response.tool_calls {
+                    println!("[tool call] {}({})", call.name, call.arguments);
                 }
+
+                // Add user input and chatbot response to the chat log
+                chat_log.push(Message {
+                    role: "user".to_string(),
+                    content: input.to_string(),
+                });
+                chat_log.push(Message {
+                    role: "assistant".to_string(),
+                    content: response.text.clone(),
+                });
+
+                tracing::info!("Response:\n{}\n", response.text); // Log the chatbot's response for debugging
+            }
+            Err(error) => {
+                // Handle errors from the chatbot
+                eprintln!("Error generating response: {}", error);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Adapter letting [cli_chatbot] drive a type that only implements the `Chat` trait: the
+/// response is delivered as a single chunk once it is complete.
+pub struct WholeResponse<C: Chat>(pub C);
+
+impl<C: Chat> StreamingChat for WholeResponse<C> {
+    async fn stream_chat(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<StreamingResult, CompletionError> {
+        let response = self
+            .0
+            .chat(prompt, chat_history)
+            .await
+            .map_err(|e| CompletionError::RequestError(Box::new(e)))?;
+        Ok(Box::pin(stream::once(async move {
+            Ok(StreamingChoice::Message(response))
+        })))
+    }
+}
+
+/// Same REPL as [cli_chatbot], but the chat history is kept in the agent's conversation
+/// memory (see [AgentBuilder::memory](crate::agent::AgentBuilder::memory)) under
+/// `conversation_id` instead of an in-process log, so the session can be resumed later.
+///
+/// # Arguments
+/// - `agent`: An agent, preferably built with a memory.
+/// - `conversation_id`: Identifier of the conversation in the agent's memory.
+///
+/// # Returns
+/// - `Result<(), ConversationError>`: Returns `Ok` on successful completion or an error of type `ConversationError`.
+pub async fn cli_chatbot_with_memory<M: CompletionModel>(
+    agent: Agent<M>,
+    conversation_id: &str,
+) -> Result<(), ConversationError> {
+    let stdin = io::stdin(); // Standard input for user prompts
+    let mut stdout = io::stdout(); // Standard output for displaying messages
+
+    // Initial welcome message
+    println!("Welcome to the chatbot! Type 'exit' to quit.");
+
+    // Main loop for REPL
+    loop {
+        print!("> "); // Prompt symbol for user input
+        stdout.flush().unwrap(); // Ensure the prompt is displayed immediately
+
+        let mut input = String::new();
+
+        // Read user input from the standard input
+        if let Err(error) = stdin.read_line(&mut input) {
+            // Handle errors reading user input
+            eprintln!("Error reading input: {}", error);
+            continue;
+        }
+        let input = input.trim(); // Remove leading and trailing whitespace
+
+        // Exit condition
+        if input.eq_ignore_ascii_case("exit") {
+            println!("Goodbye!");
+            break;
+        }
+
+        tracing::info!("Prompt:\n{}\n", input); // Log the user's input for debugging
+
+        // The agent loads and records the conversation itself
+        match agent.converse(conversation_id, input).await {
+            Ok(response) => {
+                // Display the chatbot's response in a formatted block
+                println!("========================== Response ============================");
+                println!("{}", response);
+                println!("================================================================\n");
+
+                tracing::info!("Response:\n{}\n", response); // Log the chatbot's response for debugging
             }
             Err(error) => {
-                // Handle errors reading user input
-                eprintln!("Error reading input: {}", error);
+                // Handle errors from the chatbot
+                eprintln!("Error generating response: {}", error);
             }
         }
     }
 
     Ok(())
 }
+
+/// Default [ApprovalHandler] for terminal sessions: prints the tool call and asks the user
+/// to approve it, reject it (optionally giving a reason), or replace its arguments.
+///
+/// Calls dispatched concurrently are asked about one at a time, and stdin is read on a
+/// blocking thread so the runtime keeps serving other tasks while waiting for an answer.
+///
+/// A rejection is sent back to the model as the tool's result, so give the agent room to
+/// react to it with [AgentBuilder::max_turns](crate::agent::AgentBuilder::max_turns): with
+/// the default of a single turn, the prompt fails with a
+/// [MaxTurnsError](crate::agent::MaxTurnsError) after any tool call.
+///
+/// # Example
+/// ```
+/// let agent = AgentBuilder::new(model)
+///     .tool_requiring_approval(TransferFunds)
+///     .approval_handler(TerminalApprovalHandler)
+///     .max_turns(3)
+///     .build();
+/// ```
+pub struct TerminalApprovalHandler;
+
+/// Held while a tool call is being presented, so concurrent prompts do not interleave
+static APPROVAL_PROMPT: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Print `prompt` and read a line from stdin on a blocking thread. The end of stdin (e.g.:
+/// Ctrl-D or exhausted piped input) is an [io::ErrorKind::UnexpectedEof] error.
+async fn ask(prompt: &str) -> io::Result<String> {
+    print!("{}", prompt);
+    io::stdout().flush()?; // Ensure the question is displayed immediately
+
+    tokio::task::spawn_blocking(|| {
+        let mut answer = String::new();
+        match io::stdin().read_line(&mut answer)? {
+            0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            _ => Ok(answer),
+        }
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+impl ApprovalHandler for TerminalApprovalHandler {
+    fn approve<'a>(
+        &'a self,
+        toolname: &'a str,
+        args: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Approval> + Send + 'a>> {
+        Box::pin(async move {
+            let _prompt = APPROVAL_PROMPT.lock().await;
+
+            println!("=================== Tool call requires approval ================");
+            println!("Tool: {}", toolname);
+            println!("Arguments: {}", args);
+            println!("================================================================");
+
+            loop {
+                let answer = match ask("Approve? [y]es / [n]o / [e]dit arguments: ").await {
+                    Ok(answer) => answer,
+                    Err(error) => {
+                        // Never execute a sensitive tool without an explicit answer
+                        eprintln!("Error reading input: {}", error);
+                        return Approval::Reject("the approval could not be read".into());
+                    }
+                };
+
+                match answer.trim().to_lowercase().as_str() {
+                    "y" | "yes" => return Approval::Approve,
+                    "n" | "no" => {
+                        // A reason that cannot be read is no reason
+                        let reason = ask("Reason (optional): ").await.unwrap_or_default();
+                        let reason = reason.trim();
+                        return Approval::Reject(if reason.is_empty() {
+                            "no reason given".into()
+                        } else {
+                            reason.into()
+                        });
+                    }
+                    "e" | "edit" => {
+                        let edited = match ask("New arguments (JSON): ").await {
+                            Ok(edited) => edited,
+                            Err(error) => {
+                                eprintln!("Error reading input: {}", error);
+                                return Approval::Reject(
+                                    "the edited arguments could not be read".into(),
+                                );
+                            }
+                        };
+                        match serde_json::from_str::<serde_json::Value>(edited.trim()) {
+                            Ok(edited) => return Approval::Edit(edited.to_string()),
+                            Err(error) => eprintln!("Invalid JSON: {}", error),
+                        }
+                    }
+                    _ => println!("Please answer 'y', 'n' or 'e'."),
+                }
+            }
+        })
+    }
+}
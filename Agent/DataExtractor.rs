@@ -1,13 +1,15 @@
-use std::marker::PhantomData;
+use std::{collections::BTreeMap, marker::PhantomData};
 
+use futures::{stream, StreamExt};
 use schemars::{schema_for, JsonSchema};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
 
 use crate::{
-    agent::{Agent, AgentBuilder},
-    completion::{CompletionModel, Prompt, PromptError, ToolDefinition},
-    tool::Tool,
+    agent::{tool_call_message, Agent, AgentBuilder},
+    completion::{Chat, CompletionModel, Message, ModelChoice, PromptError, ToolDefinition},
+    json_schema::{self, ValidationError},
+    tool::{Tool, ToolCall, ToolSetError},
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -20,11 +22,65 @@ pub enum ExtractionError {
 
     #[error("PromptError: {0}")]
     PromptError(#[from] PromptError),
+
+    /// The extracted data does not match the JSON schema of the target type
+    #[error("ValidationError: {0}")]
+    ValidationError(#[from] ValidationError),
+
+    /// The extracted data was rejected by the extractor's validation hook
+    #[error("InvalidData: {0}")]
+    InvalidData(String),
+}
+
+/// Semantic check of the extracted data, returning the reason it is invalid
+pub type Validator<T> = Box<dyn Fn(&T) -> Result<(), String> + Send + Sync>;
+
+/// Combination of the data extracted from the chunks of a text, in the order of the chunks
+pub type MergeStrategy<T> = Box<dyn Fn(Vec<T>) -> T + Send + Sync>;
+
+/// A completion model which can be constrained to answer with JSON matching a schema
+/// (e.g.: OpenAI's `json_schema` response format)
+pub trait StructuredOutputModel: CompletionModel {
+    /// Additional request parameters constraining the answer to a JSON document matching
+    /// `schema`, or `None` if the model does not support native structured outputs
+    fn json_schema_params(&self, name: &str, schema: &Value) -> Option<Value>;
+}
+
+/// Additional parameters of an OpenAI compatible `json_schema` response format
+pub fn openai_response_format(name: &str, schema: &Value) -> Value {
+    json!({
+        "response_format": {
+            "type": "json_schema",
+            "json_schema": {
+                "name": name,
+                "schema": schema,
+            }
+        }
+    })
+}
+
+/// How the extractor gets structured data out of the model
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    /// The model's answer is constrained to the schema by the provider
+    NativeSchema,
+    /// The model is asked to call a `submit` tool with the data as arguments
+    SubmitTool,
 }
 
 /// Extractor for structured data from text
 pub struct Extractor<M: CompletionModel, T: JsonSchema + for<'a> Deserialize<'a> + Send + Sync> {
     agent: Agent<M>,
+    mode: OutputMode,
+    schema: Value,
+    /// Whether the model answers with the data wrapped in a `value` property
+    wrapped: bool,
+    max_attempts: usize,
+    validator: Option<Validator<T>>,
+    chunk_size: usize,
+    chunk_overlap: usize,
+    concurrency: usize,
+    merge: MergeStrategy<T>,
     _t: PhantomData<T>,
 }
 
@@ -32,22 +88,275 @@ impl<T: JsonSchema + for<'a> Deserialize<'a> + Send + Sync, M: CompletionModel>
 where
     M: Sync,
 {
+    /// Extract a `T` from `text`. The extracted data is validated against the JSON schema of
+    /// `T` whatever the output mode, then by the validation hook if any.
+    ///
+    /// Invalid data is sent back to the model with the error, asking it to fix it, for at most
+    /// `max_attempts` completions. The error of the last attempt is returned if none succeeds.
     pub async fn extract(&self, text: &str) -> Result<T, ExtractionError> {
-        let summary = self.agent.prompt(text).await?;
+        let mut chat_history = vec![];
+        let mut prompt = text.to_string();
+
+        for attempt in 1..=self.max_attempts {
+            let (output, answer) = self.request_data(&prompt, chat_history.clone()).await?;
+            let error = match self.parse(&output) {
+                Ok(data) => return Ok(data),
+                Err(error) if attempt < self.max_attempts => error,
+                Err(error) => return Err(error),
+            };
 
-        if summary.is_empty() {
+            tracing::debug!(target: "rig",
+                "Extraction attempt {attempt}/{} failed: {error}",
+                self.max_attempts
+            );
+            chat_history.push(Message {
+                role: "user".into(),
+                content: prompt,
+            });
+            chat_history.push(answer);
+            prompt = self.repair_prompt(&error);
+        }
+
+        unreachable!("max_attempts is at least 1")
+    }
+
+    /// Ask the model for the data, returning the extracted JSON and the model's answer to
+    /// record in the chat history. In submit-tool mode, the extracted JSON is the arguments
+    /// of the `submit` call, so that invalid arguments are sent back to the model as it
+    /// made them.
+    async fn request_data(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<(String, Message), PromptError> {
+        let output = match self.mode {
+            OutputMode::NativeSchema => self.agent.chat(prompt, chat_history).await?,
+            OutputMode::SubmitTool => {
+                match self
+                    .agent
+                    .send_completion(prompt, chat_history)
+                    .await?
+                    .choice
+                {
+                    ModelChoice::ToolCall(toolname, args) if toolname == "submit" => {
+                        let call = ToolCall {
+                            id: "call_0".into(),
+                            name: toolname,
+                            arguments: args.to_string(),
+                        };
+                        let answer = tool_call_message(&call);
+                        return Ok((call.arguments, answer));
+                    }
+                    ModelChoice::ToolCall(toolname, _) => {
+                        return Err(ToolSetError::ToolNotFoundError(toolname).into())
+                    }
+                    ModelChoice::Message(output) => output,
+                }
+            }
+        };
+        let answer = Message {
+            role: "assistant".into(),
+            content: output.clone(),
+        };
+        Ok((output, answer))
+    }
+
+    fn parse(&self, output: &str) -> Result<T, ExtractionError> {
+        if output.is_empty() {
             return Err(ExtractionError::NoData);
         }
 
-        Ok(serde_json::from_str(&summary)?)
+        let mut data: Value = serde_json::from_str(strip_code_fence(output))?;
+        if self.wrapped {
+            data = data.get_mut("value").map(Value::take).unwrap_or(data);
+        }
+        json_schema::validate(&self.schema, &data)?;
+        let data = serde_json::from_value(data)?;
+
+        if let Some(validator) = &self.validator {
+            validator(&data).map_err(ExtractionError::InvalidData)?;
+        }
+        Ok(data)
+    }
+
+    /// Message asking the model to fix the data it extracted
+    fn repair_prompt(&self, error: &ExtractionError) -> String {
+        let retry = match self.mode {
+            OutputMode::NativeSchema => "Answer again with the corrected JSON document.",
+            OutputMode::SubmitTool => "Call the `submit` function again with the corrected data.",
+        };
+        format!("The data you extracted is invalid: {error}\n{retry}")
+    }
+
+    /// Extract a `T` from a text too long for a single prompt: the text is split into
+    /// overlapping chunks (see [split_chunks]) which are extracted concurrently, and the
+    /// results are combined with the extractor's merge strategy. Chunks without data are
+    /// skipped.
+    ///
+    /// Items lying in the overlap of two chunks are extracted from both chunks. The default
+    /// merge keeps every item, as it cannot tell them apart from identical items found
+    /// elsewhere in the text: deduplicate them with [ExtractorBuilder::merge_with] (e.g. by
+    /// a key of the items) or set the overlap to 0 if the items have no such key.
+    pub async fn extract_chunked(&self, text: &str) -> Result<T, ExtractionError> {
+        let chunks = split_chunks(text, self.chunk_size, self.chunk_overlap);
+        tracing::debug!(target: "rig", "Extracting from {} chunks", chunks.len());
+
+        let results: Vec<_> = stream::iter(chunks)
+            .map(|chunk| self.extract(chunk))
+            .buffered(self.concurrency)
+            .collect()
+            .await;
+
+        let mut extracted = vec![];
+        for result in results {
+            match result {
+                Ok(data) => extracted.push(data),
+                Err(ExtractionError::NoData) => continue,
+                Err(error) => return Err(error),
+            }
+        }
+
+        if extracted.is_empty() {
+            return Err(ExtractionError::NoData);
+        }
+        Ok((self.merge)(extracted))
+    }
+
+    /// Extract a `T` from each of `texts` concurrently. Results are returned in the order of
+    /// `texts`.
+    pub async fn extract_batch(
+        &self,
+        texts: &[impl AsRef<str>],
+    ) -> Vec<Result<T, ExtractionError>> {
+        stream::iter(texts)
+            .map(|text| self.extract(text.as_ref()))
+            .buffered(self.concurrency)
+            .collect()
+            .await
+    }
+
+    pub fn output_mode(&self) -> OutputMode {
+        self.mode
+    }
+}
+
+/// Split `text` into chunks of at most `chunk_size` characters, each starting `overlap`
+/// characters before the end of the previous one so data spanning a boundary is seen whole
+/// at least once. Chunks end after a whitespace when one is found in their second half.
+pub fn split_chunks(text: &str, chunk_size: usize, overlap: usize) -> Vec<&str> {
+    assert!(
+        overlap < chunk_size,
+        "Chunk overlap must be smaller than the chunk size"
+    );
+    let bounds: Vec<usize> = text
+        .char_indices()
+        .map(|(index, _)| index)
+        .chain([text.len()])
+        .collect();
+    let length = bounds.len() - 1;
+
+    let mut chunks = vec![];
+    let mut start = 0;
+    loop {
+        let mut end = (start + chunk_size).min(length);
+        if end < length {
+            if let Some(space) = (start + chunk_size / 2..end)
+                .rev()
+                .find(|&i| text[bounds[i]..].starts_with(char::is_whitespace))
+            {
+                end = space + 1;
+            }
+        }
+        chunks.push(&text[bounds[start]..bounds[end]]);
+
+        if end == length {
+            return chunks;
+        }
+        start = end.saturating_sub(overlap).max(start + 1);
+    }
+}
+
+/// Default merge strategy: arrays are concatenated, objects are merged field by field and
+/// for scalars the first non-default value (not null, empty, zero or false) is kept.
+///
+/// Repeated items are all kept, including the items of the overlap between chunks which
+/// are extracted twice (see [Extractor::extract_chunked]).
+pub fn merge_values(a: Value, b: Value) -> Value {
+    match (a, b) {
+        (Value::Array(mut a), Value::Array(b)) => {
+            a.extend(b);
+            Value::Array(a)
+        }
+        (Value::Object(mut a), Value::Object(b)) => {
+            for (key, value) in b {
+                let merged = match a.remove(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => value,
+                };
+                a.insert(key, merged);
+            }
+            Value::Object(a)
+        }
+        (a, b) if is_default(&a) => b,
+        (a, _) => a,
+    }
+}
+
+fn is_default(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Bool(b) => !b,
+        Value::Number(n) => n.as_f64() == Some(0.0),
+        Value::String(s) => s.is_empty(),
+        Value::Array(a) => a.is_empty(),
+        Value::Object(o) => o.is_empty(),
+    }
+}
+
+fn default_merge<T: for<'a> Deserialize<'a> + Serialize>(extracted: Vec<T>) -> T {
+    let values: Vec<Value> = extracted
+        .iter()
+        .filter_map(|data| serde_json::to_value(data).ok())
+        .collect();
+    let merged = values.into_iter().reduce(merge_values).unwrap_or_default();
+
+    match serde_json::from_value(merged) {
+        Ok(data) => data,
+        Err(error) => {
+            tracing::warn!(target: "rig",
+                "Merged data does not deserialize ({error}), keeping the first chunk's data"
+            );
+            extracted
+                .into_iter()
+                .next()
+                .expect("At least one chunk was extracted")
+        }
     }
 }
 
+/// Remove the markdown code fence some models wrap JSON answers in
+fn strip_code_fence(output: &str) -> &str {
+    let output = output.trim();
+    output
+        .strip_prefix("```json")
+        .or_else(|| output.strip_prefix("```"))
+        .and_then(|output| output.strip_suffix("```"))
+        .map(str::trim)
+        .unwrap_or(output)
+}
+
 pub struct ExtractorBuilder<
     T: JsonSchema + for<'a> Deserialize<'a> + Send + Sync + 'static,
     M: CompletionModel,
 > {
     agent_builder: AgentBuilder<M>,
+    mode: OutputMode,
+    max_attempts: usize,
+    validator: Option<Validator<T>>,
+    chunk_size: usize,
+    chunk_overlap: usize,
+    concurrency: usize,
+    merge: Option<MergeStrategy<T>>,
     _t: PhantomData<T>,
 }
 
@@ -55,8 +364,8 @@ impl<T: JsonSchema + for<'a> Deserialize<'a> + Serialize + Send + Sync, M: Compl
     ExtractorBuilder<T, M>
 {
     pub fn new(model: M) -> Self {
-        Self {
-            agent_builder: AgentBuilder::new(model)
+        Self::with_agent_builder(
+            AgentBuilder::new(model)
                 .preamble("\
                     You are an AI assistant whose purpose is to extract structured data from the provided text.\n\
                     You will have access to a `submit` function that defines the structure of the data to extract from the provided text.\n\
@@ -64,6 +373,20 @@ impl<T: JsonSchema + for<'a> Deserialize<'a> + Serialize + Send + Sync, M: Compl
                     Be sure to fill out every field and ALWAYS CALL THE `submit` function, event with default values!!!.
                 ")
                 .tool(SubmitTool::<T> {_t: PhantomData}),
+            OutputMode::SubmitTool,
+        )
+    }
+
+    fn with_agent_builder(agent_builder: AgentBuilder<M>, mode: OutputMode) -> Self {
+        Self {
+            agent_builder,
+            mode,
+            max_attempts: 1,
+            validator: None,
+            chunk_size: 8000,
+            chunk_overlap: 500,
+            concurrency: 4,
+            merge: None,
             _t: PhantomData,
         }
     }
@@ -80,27 +403,316 @@ impl<T: JsonSchema + for<'a> Deserialize<'a> + Serialize + Send + Sync, M: Compl
         self
     }
 
+    /// Send invalid data back to the model with the error, for at most `max_attempts`
+    /// completions per extraction. Defaults to 1, i.e.: no repair.
+    pub fn max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Check the extracted data with `validator` (e.g.: "amount must be positive"). Data it
+    /// rejects is repaired like malformed data.
+    pub fn validate(
+        mut self,
+        validator: impl Fn(&T) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.validator = Some(Box::new(validator));
+        self
+    }
+
+    /// Set the size of the chunks of [Extractor::extract_chunked], in characters, and the
+    /// number of characters shared by consecutive chunks. Defaults to 8000 and 500.
+    ///
+    /// # Panics
+    /// If `overlap` is not smaller than `chunk_size`
+    pub fn chunking(mut self, chunk_size: usize, overlap: usize) -> Self {
+        assert!(
+            overlap < chunk_size,
+            "Chunk overlap must be smaller than the chunk size"
+        );
+        self.chunk_size = chunk_size;
+        self.chunk_overlap = overlap;
+        self
+    }
+
+    /// Set the maximum number of chunks or texts extracted concurrently. Defaults to 4.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Combine the data extracted from chunks with `merge` instead of [merge_values]
+    pub fn merge_with(mut self, merge: impl Fn(Vec<T>) -> T + Send + Sync + 'static) -> Self {
+        self.merge = Some(Box::new(merge));
+        self
+    }
+
     pub fn build(self) -> Extractor<M, T> {
+        let (_, wrapped) = model_schema::<T>();
         Extractor {
             agent: self.agent_builder.build(),
+            wrapped,
+            mode: self.mode,
+            schema: json!(schema_for!(T)),
+            max_attempts: self.max_attempts,
+            validator: self.validator,
+            chunk_size: self.chunk_size,
+            chunk_overlap: self.chunk_overlap,
+            concurrency: self.concurrency,
+            merge: self.merge.unwrap_or_else(|| Box::new(default_merge::<T>)),
             _t: PhantomData,
         }
     }
 }
 
+impl<T, M> ExtractorBuilder<T, M>
+where
+    T: JsonSchema + for<'a> Deserialize<'a> + Serialize + Send + Sync,
+    M: StructuredOutputModel,
+{
+    /// Create an extractor using the model's native structured outputs if it supports them,
+    /// or the `submit` tool otherwise
+    pub fn structured_output(model: M) -> Self {
+        let (schema, _) = model_schema::<T>();
+        let Some(params) = model.json_schema_params(&T::schema_name(), &schema) else {
+            return Self::new(model);
+        };
+
+        Self::with_agent_builder(
+            AgentBuilder::new(model)
+                .preamble("\
+                    You are an AI assistant whose purpose is to extract structured data from the provided text.\n\
+                    Answer with a JSON document holding the data extracted from the provided text.\n\
+                    Be sure to fill out every field, even with default values.
+                ")
+                .additional_params(params),
+            OutputMode::NativeSchema,
+        )
+    }
+}
+
+/// JSON schema of `T` as given to the model, and whether it wraps `T` in a `value` property.
+/// Providers require tool parameters and response formats to be objects, so targets which
+/// are not (e.g.: enums, whose schema is a `oneOf` of their variants) are wrapped.
+fn model_schema<T: JsonSchema>() -> (Value, bool) {
+    let schema = json!(schema_for!(T));
+    let Value::Object(mut inner) = schema else {
+        return (schema, false);
+    };
+    if inner.get("type") == Some(&json!("object")) {
+        return (Value::Object(inner), false);
+    }
+
+    // `$ref`s are resolved from the root of the schema, where the definitions must stay
+    inner.remove("$schema");
+    let definitions = inner.remove("definitions");
+    let title = inner.remove("title");
+
+    let mut wrapped = json!({
+        "type": "object",
+        "properties": { "value": inner },
+        "required": ["value"],
+    });
+    if let Some(title) = title {
+        wrapped["title"] = title;
+    }
+    if let Some(definitions) = definitions {
+        wrapped["definitions"] = definitions;
+    }
+    (wrapped, true)
+}
+
+/// Target type of an extractor classifying texts: the variant of `T` the text belongs to,
+/// with the rationale of the choice. `T` is usually an enum, whose variants may carry data.
+///
+/// # Example
+/// ```
+/// use Hydranta::extractor::{Classification, ExtractorBuilder};
+///
+/// #[derive(Debug, Deserialize, Serialize, JsonSchema)]
+/// #[serde(tag = "kind")]
+/// enum Signal {
+///     Launch { token: String },
+///     Rug { token: String },
+///     Noise,
+/// }
+///
+/// let classifier = ExtractorBuilder::<Classification<Signal>, _>::classifier(model).build();
+/// let Classification { rationale, label } = classifier.classify(&tweet).await?;
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+pub struct Classification<T> {
+    /// Short explanation of why the text belongs to the chosen category
+    pub rationale: String,
+    /// The category of the text
+    pub label: T,
+}
+
+impl<T, M> ExtractorBuilder<Classification<T>, M>
+where
+    T: JsonSchema + for<'a> Deserialize<'a> + Serialize + Send + Sync + 'static,
+    M: CompletionModel,
+{
+    /// Create an extractor classifying texts into one of the variants of `T`
+    pub fn classifier(model: M) -> Self {
+        Self::new(model).preamble(
+            "Classify the provided text into exactly one category of `label`, filling the \
+            data of the category from the text, and explain your choice in `rationale`.",
+        )
+    }
+}
+
+impl<T, M> Extractor<M, Classification<T>>
+where
+    T: JsonSchema + for<'a> Deserialize<'a> + Send + Sync,
+    M: CompletionModel + Sync,
+{
+    pub async fn classify(&self, text: &str) -> Result<Classification<T>, ExtractionError> {
+        self.extract(text).await
+    }
+}
+
+/// Target type of an extractor attributing the extracted data to its source: the model
+/// extracts `data` along with the passages of the text supporting each of its fields.
+/// Use [Extractor::extract_sourced] to get the attribution verified against the text.
+///
+/// # Example
+/// ```
+/// use Hydranta::extractor::{ExtractorBuilder, Sourced};
+///
+/// let extractor = ExtractorBuilder::<Sourced<Transfer>, _>::new(model).build();
+/// let extracted = extractor.extract_sourced(&statement).await?;
+///
+/// for (field, source) in &extracted.fields {
+///     println!("{field} ({:.0}%): {:?}", source.confidence * 100.0, source.spans);
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+pub struct Sourced<T> {
+    /// The data extracted from the text
+    pub data: T,
+    /// For each top-level field of `data`, the passages of the text supporting its value
+    pub sources: Vec<Evidence>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+pub struct Evidence {
+    /// Name of the top-level field of `data`
+    pub field: String,
+    /// Exact, verbatim quotes of the text supporting the value of the field
+    pub quotes: Vec<String>,
+    /// Your confidence in the value of the field, from 0 to 1
+    #[schemars(range(min = 0, max = 1))]
+    pub confidence: f64,
+}
+
+/// A passage of the source text, as byte offsets
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+/// Where the value of a field comes from
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldSource {
+    /// Quotes reported by the model which were found in the text
+    pub spans: Vec<Span>,
+    /// Quotes reported by the model which do not occur in the text
+    pub unverified: Vec<String>,
+    /// Confidence reported by the model, from 0 to 1
+    pub confidence: f64,
+}
+
+/// Data extracted from a text, with the source of each of its top-level fields
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Extracted<T> {
+    pub data: T,
+    /// Source of the fields the model reported evidence for, by field name
+    pub fields: BTreeMap<String, FieldSource>,
+}
+
+impl<T> Extracted<T> {
+    pub fn source(&self, field: &str) -> Option<&FieldSource> {
+        self.fields.get(field)
+    }
+
+    /// Whether every quote reported by the model occurs in the text
+    pub fn is_verified(&self) -> bool {
+        self.fields
+            .values()
+            .all(|source| source.unverified.is_empty())
+    }
+}
+
+impl<T, M> Extractor<M, Sourced<T>>
+where
+    T: JsonSchema + for<'a> Deserialize<'a> + Serialize + Send + Sync,
+    M: CompletionModel + Sync,
+{
+    /// Extract data from `text` with the source of each of its fields. Quotes are looked up
+    /// in `text`: those not found are reported as unverified, and evidence for fields which
+    /// are not top-level fields of `T` is dropped.
+    pub async fn extract_sourced(&self, text: &str) -> Result<Extracted<T>, ExtractionError> {
+        let Sourced { data, sources } = self.extract(text).await?;
+        let field_names = match serde_json::to_value(&data)? {
+            Value::Object(fields) => fields.into_iter().map(|(name, _)| name).collect(),
+            _ => vec![],
+        };
+
+        let mut fields = BTreeMap::new();
+        for evidence in sources {
+            if !field_names.contains(&evidence.field) {
+                tracing::warn!(target: "rig",
+                    "Dropping evidence for unknown field {}", evidence.field
+                );
+                continue;
+            }
+
+            let source = fields.entry(evidence.field).or_insert(FieldSource {
+                spans: vec![],
+                unverified: vec![],
+                confidence: evidence.confidence,
+            });
+            for quote in evidence.quotes {
+                match locate(text, &quote) {
+                    Some(span) => source.spans.push(span),
+                    None => source.unverified.push(quote),
+                }
+            }
+        }
+
+        Ok(Extracted { data, fields })
+    }
+}
+
+/// Find `quote` in `text`, ignoring surrounding whitespace
+fn locate(text: &str, quote: &str) -> Option<Span> {
+    let quote = quote.trim();
+    if quote.is_empty() {
+        return None;
+    }
+    text.find(quote).map(|start| Span {
+        start,
+        end: start + quote.len(),
+        text: quote.to_string(),
+    })
+}
+
 #[derive(Deserialize, Serialize)]
 struct SubmitTool<T: JsonSchema + for<'a> Deserialize<'a> + Send + Sync> {
     _t: PhantomData<T>,
 }
 
 #[derive(Debug, thiserror::Error)]
-#[error("SubmitError")]
-struct SubmitError;
+#[error("SubmitError: {0}")]
+struct SubmitError(serde_json::Error);
 
 impl<T: JsonSchema + for<'a> Deserialize<'a> + Serialize + Send + Sync> Tool for SubmitTool<T> {
     const NAME: &'static str = "submit";
     type Error = SubmitError;
-    type Args = T;
+    type Args = Value;
     type Output = T;
 
     async fn definition(&self, _prompt: String) -> ToolDefinition {
@@ -108,11 +720,366 @@ impl<T: JsonSchema + for<'a> Deserialize<'a> + Serialize + Send + Sync> Tool for
             name: Self::NAME.to_string(),
             description: "Submit the structured data you extracted from the provided text."
                 .to_string(),
-            parameters: json!(schema_for!(T)),
+            parameters: model_schema::<T>().0,
         }
     }
 
-    async fn call(&self, data: Self::Args) -> Result<Self::Output, Self::Error> {
-        Ok(data)
+    async fn call(&self, mut data: Self::Args) -> Result<Self::Output, Self::Error> {
+        if model_schema::<T>().1 {
+            data = data["value"].take();
+        }
+        serde_json::from_value(data).map_err(SubmitError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::tests::ScriptedModel;
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+    struct Transfer {
+        recipient: String,
+        amount: f64,
+    }
+
+    impl StructuredOutputModel for ScriptedModel {
+        fn json_schema_params(&self, name: &str, schema: &Value) -> Option<Value> {
+            Some(openai_response_format(name, schema))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_native_structured_output() {
+        let model = ScriptedModel::new([
+            ModelChoice::Message("```json\n{\"recipient\": \"ada\", \"amount\": 2.5}\n```".into()),
+            ModelChoice::Message(r#"{"recipient": "ada"}"#.into()),
+        ]);
+        let extractor = ExtractorBuilder::<Transfer, _>::structured_output(model.clone()).build();
+        assert_eq!(extractor.output_mode(), OutputMode::NativeSchema);
+
+        let transfer = extractor.extract("Send 2.5 SOL to ada").await.unwrap();
+        assert_eq!(transfer.amount, 2.5);
+        {
+            let requests = model.requests.lock().unwrap();
+            assert!(requests[0].tools.is_empty());
+            assert_eq!(
+                requests[0].additional_params.as_ref().unwrap()["response_format"]["json_schema"]
+                    ["name"],
+                "Transfer"
+            );
+        }
+
+        assert!(matches!(
+            extractor.extract("Send SOL to ada").await,
+            Err(ExtractionError::ValidationError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_submit_tool_fallback() {
+        let model = ScriptedModel::new([ModelChoice::ToolCall(
+            "submit".into(),
+            json!({"recipient": "ada", "amount": 1.0}),
+        )]);
+        let extractor = ExtractorBuilder::<Transfer, _>::new(model.clone()).build();
+        assert_eq!(extractor.output_mode(), OutputMode::SubmitTool);
+
+        assert_eq!(
+            extractor.extract("Send 1 SOL to ada").await.unwrap(),
+            Transfer {
+                recipient: "ada".into(),
+                amount: 1.0
+            }
+        );
+        assert_eq!(model.requests.lock().unwrap()[0].tools[0].name, "submit");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_data_is_repaired() {
+        let model = ScriptedModel::new([
+            ModelChoice::ToolCall("submit".into(), json!({"recipient": "ada"})),
+            ModelChoice::ToolCall("submit".into(), json!({"recipient": "ada", "amount": -1.0})),
+            ModelChoice::ToolCall("submit".into(), json!({"recipient": "ada", "amount": 1.0})),
+        ]);
+        let extractor = ExtractorBuilder::<Transfer, _>::new(model.clone())
+            .max_attempts(3)
+            .validate(|transfer| match transfer.amount > 0.0 {
+                true => Ok(()),
+                false => Err("amount must be positive".into()),
+            })
+            .build();
+
+        assert_eq!(
+            extractor.extract("Send 1 SOL to ada").await.unwrap().amount,
+            1.0
+        );
+        let requests = model.requests.lock().unwrap();
+        assert!(requests[1]
+            .prompt
+            .contains("$.amount: missing required property"));
+        assert!(requests[2]
+            .prompt
+            .contains("InvalidData: amount must be positive"));
+        assert_eq!(requests[2].chat_history.len(), 4);
+        assert_eq!(
+            requests[1].chat_history[1].content,
+            r#"Calling tool `submit` (call id `call_0`) with arguments: {"recipient":"ada"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_repair_attempts_are_limited() {
+        let model = ScriptedModel::new([
+            ModelChoice::Message("not json".into()),
+            ModelChoice::Message(r#"{"recipient": "ada", "amount": "one"}"#.into()),
+        ]);
+        let extractor = ExtractorBuilder::<Transfer, _>::structured_output(model)
+            .max_attempts(2)
+            .build();
+
+        assert!(matches!(
+            extractor.extract("Send 1 SOL to ada").await,
+            Err(ExtractionError::ValidationError(_))
+        ));
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+    struct History {
+        wallet: String,
+        transfers: Vec<Transfer>,
+    }
+
+    #[test]
+    fn test_split_chunks() {
+        let chunks = split_chunks("one two three four five", 10, 4);
+        assert_eq!(chunks, ["one two ", "two three ", "ree four ", "our five"]);
+        assert_eq!(split_chunks("short", 10, 4), ["short"]);
+        assert!(split_chunks(&"é".repeat(25), 10, 2)
+            .iter()
+            .all(|chunk| chunk.chars().count() <= 10));
+    }
+
+    #[tokio::test]
+    async fn test_chunked_extraction_merges_chunks() {
+        let transfer = |amount| json!({"recipient": "ada", "amount": amount});
+        let model = ScriptedModel::new([
+            ModelChoice::Message(json!({"wallet": "W1", "transfers": [transfer(1.0)]}).to_string()),
+            ModelChoice::Message(json!({"wallet": "", "transfers": [transfer(2.0)]}).to_string()),
+            ModelChoice::Message(json!({"wallet": "W2", "transfers": []}).to_string()),
+        ]);
+        let extractor = ExtractorBuilder::<History, _>::structured_output(model.clone())
+            .chunking(40, 10)
+            .build();
+
+        let text = "Wallet W1 sent 1 SOL to ada. Then it sent 2 SOL to ada again. Nothing else.";
+        let history = extractor.extract_chunked(text).await.unwrap();
+
+        assert_eq!(model.requests.lock().unwrap().len(), 3);
+        assert_eq!(history.wallet, "W1");
+        assert_eq!(
+            history
+                .transfers
+                .iter()
+                .map(|t| t.amount)
+                .collect::<Vec<_>>(),
+            [1.0, 2.0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chunked_extraction_keeps_repeated_items() {
+        let transfers = json!({"wallet": "W1", "transfers": [{"recipient": "ada", "amount": 1.0}]});
+        let model = ScriptedModel::new([
+            ModelChoice::Message(transfers.to_string()),
+            ModelChoice::Message(transfers.to_string()),
+        ]);
+        let extractor = ExtractorBuilder::<History, _>::structured_output(model.clone())
+            .chunking(40, 10)
+            .build();
+
+        // The same transfer is made on each side of the boundary between the two chunks
+        let text = "Wallet W1 sent 1 SOL to ada. Then it sent 1 SOL to ada again.";
+        let history = extractor.extract_chunked(text).await.unwrap();
+
+        assert_eq!(model.requests.lock().unwrap().len(), 2);
+        assert_eq!(history.transfers.len(), 2);
+    }
+
+    #[test]
+    fn test_merge_values_keeps_repeated_items() {
+        assert_eq!(
+            merge_values(json!([1, 1, 2, 3]), json!([2, 3, 4, 1])),
+            json!([1, 1, 2, 3, 2, 3, 4, 1])
+        );
+        assert_eq!(merge_values(json!([1]), json!([1])), json!([1, 1]));
+        assert_eq!(
+            merge_values(json!({"a": [1], "b": 0}), json!({"a": [2], "b": 3})),
+            json!({"a": [1, 2], "b": 3})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_custom_merge_and_batch() {
+        let total = |amount: f64| {
+            ModelChoice::Message(json!({"recipient": "ada", "amount": amount}).to_string())
+        };
+        let model = ScriptedModel::new([total(1.0), total(2.0), total(3.0), total(4.0)]);
+        let extractor = ExtractorBuilder::<Transfer, _>::structured_output(model)
+            .chunking(12, 2)
+            .merge_with(|transfers| Transfer {
+                recipient: transfers[0].recipient.clone(),
+                amount: transfers.iter().map(|t| t.amount).sum(),
+            })
+            .build();
+
+        let merged = extractor
+            .extract_chunked("1 SOL and 2 SOL to ada")
+            .await
+            .unwrap();
+        assert_eq!(merged.amount, 3.0);
+
+        let batch = extractor.extract_batch(&["3 SOL", "4 SOL"]).await;
+        assert_eq!(batch[1].as_ref().unwrap().amount, 4.0);
+    }
+
+    #[tokio::test]
+    async fn test_sourced_extraction_verifies_quotes() {
+        let model = ScriptedModel::new([ModelChoice::Message(
+            json!({
+                "data": {"recipient": "ada", "amount": 2.5},
+                "sources": [
+                    {"field": "recipient", "quotes": ["to ada"], "confidence": 0.9},
+                    {"field": "amount", "quotes": ["2.5 SOL", "two and a half"], "confidence": 1.0},
+                    {"field": "memo", "quotes": ["rent"], "confidence": 0.5}
+                ]
+            })
+            .to_string(),
+        )]);
+        let extractor = ExtractorBuilder::<Sourced<Transfer>, _>::structured_output(model).build();
+
+        let text = "Please send 2.5 SOL to ada for the rent.";
+        let extracted = extractor.extract_sourced(text).await.unwrap();
+
+        assert_eq!(extracted.data.amount, 2.5);
+        let recipient = extracted.source("recipient").unwrap();
+        assert_eq!(
+            &text[recipient.spans[0].start..recipient.spans[0].end],
+            "to ada"
+        );
+        let amount = extracted.source("amount").unwrap();
+        assert_eq!(amount.spans[0].start, 12);
+        assert_eq!(amount.unverified, ["two and a half"]);
+        assert_eq!(recipient.confidence, 0.9);
+        assert!(extracted.source("memo").is_none());
+        assert!(!extracted.is_verified());
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+    #[serde(tag = "kind")]
+    enum Signal {
+        Launch { token: String },
+        Rug { token: String, loss_usd: f64 },
+        Noise,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+    enum Sentiment {
+        Bullish,
+        Bearish,
+        Neutral,
+    }
+
+    #[tokio::test]
+    async fn test_enum_targets_are_wrapped() {
+        let (schema, wrapped) = model_schema::<Signal>();
+        assert!(wrapped);
+        assert_eq!(schema["type"], "object");
+        assert_eq!(
+            schema["properties"]["value"]["oneOf"]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+        assert!(!model_schema::<Transfer>().1);
+
+        let model = ScriptedModel::new([
+            ModelChoice::ToolCall(
+                "submit".into(),
+                json!({"value": {"kind": "Rug", "token": "XYZ"}}),
+            ),
+            ModelChoice::ToolCall(
+                "submit".into(),
+                json!({"value": {"kind": "Rug", "token": "XYZ", "loss_usd": 12000.0}}),
+            ),
+        ]);
+        let extractor = ExtractorBuilder::<Signal, _>::new(model.clone())
+            .max_attempts(2)
+            .build();
+
+        assert_eq!(
+            extractor
+                .extract("XYZ devs pulled the liquidity")
+                .await
+                .unwrap(),
+            Signal::Rug {
+                token: "XYZ".into(),
+                loss_usd: 12000.0
+            }
+        );
+        let requests = model.requests.lock().unwrap();
+        assert!(requests[1]
+            .prompt
+            .contains("$: does not match any of the allowed schemas"));
+        assert!(requests[1].chat_history[1]
+            .content
+            .contains(r#"{"value":{"kind":"Rug","token":"XYZ"}}"#));
+    }
+
+    #[tokio::test]
+    async fn test_native_enum_output() {
+        let model = ScriptedModel::new([ModelChoice::Message(
+            json!({"value": {"kind": "Noise"}}).to_string(),
+        )]);
+        let extractor = ExtractorBuilder::<Signal, _>::structured_output(model.clone()).build();
+
+        assert_eq!(extractor.extract("gm").await.unwrap(), Signal::Noise);
+        let requests = model.requests.lock().unwrap();
+        let schema = &requests[0].additional_params.as_ref().unwrap()["response_format"]
+            ["json_schema"]["schema"];
+        assert_eq!(schema["required"], json!(["value"]));
+    }
+
+    #[tokio::test]
+    async fn test_classifier_returns_variant_and_rationale() {
+        let model = ScriptedModel::new([
+            ModelChoice::ToolCall(
+                "submit".into(),
+                json!({"rationale": "Launch announcement", "label": {"kind": "Launch", "token": "ABC"}}),
+            ),
+            ModelChoice::ToolCall(
+                "submit".into(),
+                json!({"rationale": "Price up, happy tone", "label": "Bullish"}),
+            ),
+        ]);
+
+        let signals =
+            ExtractorBuilder::<Classification<Signal>, _>::classifier(model.clone()).build();
+        let classification = signals.classify("$ABC is live on Raydium!").await.unwrap();
+        assert_eq!(
+            classification.label,
+            Signal::Launch {
+                token: "ABC".into()
+            }
+        );
+        assert_eq!(classification.rationale, "Launch announcement");
+
+        let sentiments =
+            ExtractorBuilder::<Classification<Sentiment>, _>::classifier(model).build();
+        assert_eq!(
+            sentiments.classify("SOL up 20% today").await.unwrap().label,
+            Sentiment::Bullish
+        );
     }
 }
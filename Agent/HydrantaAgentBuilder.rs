@@ -1,3 +1,745 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use futures::{stream, StreamExt, TryStreamExt};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::{
+    approval::ApprovalHandler,
+    completion::{
+        Chat, Completion, CompletionError, CompletionModel, CompletionRequest,
+        CompletionRequestBuilder, CompletionResponse, Document, Message, ModelChoice, Prompt,
+        PromptError, ToolDefinition,
+    },
+    dynamic_tools::DynamicToolIndex,
+    guardrails::{Flag, Guardrail, GuardrailAction, Guardrails, Stage},
+    memory::{AgentMemory, ConversationError, ConversationMemory, MemoryStrategy},
+    streaming::{
+        collect_stream, StreamingChat, StreamingChoice, StreamingCompletionModel, StreamingPrompt,
+        StreamingResult, ToolCallDelta,
+    },
+    template::{TemplateError, TemplatedAgent, Templates},
+    token_budget::{ApproxTokenizer, BudgetReport, ContextBudget, TokenizedModel, Tokenizer},
+    tool::{
+        dispatch_concurrently, ResponseToolCalls, Tool, ToolCall, ToolError, ToolSet, ToolSetError,
+    },
+    tool_policy::ToolPolicy,
+    tool_registry::ToolView,
+    usage::{ResponseUsage, TokenUsage, UsageTracker},
+    vector_store::{VectorStoreError, VectorStoreIndexDyn},
+};
+
+/// Struct representing an LLM agent. An agent is an LLM model combined with a preamble
+/// (i.e.: system prompt) and a static set of context documents and tools.
+/// All context documents and tools are always provided to the agent when prompted.
+///
+/// # Example
+/// ```
+/// use Hydranta::{completion::Prompt, providers::Hydranta};
+///
+/// let Hydranta = Hydranta::Client::from_env();
+///
+/// let comedian_agent = Hydranta
+///     .agent("qbt-1.a")
+///     .preamble("You are a comedian here to entertain the user using humour and jokes.")
+///     .temperature(0.9)
+///     .build();
+///
+/// let response = comedian_agent.prompt("Entertain me!")
+///     .await
+///     .expect("Failed to prompt the agent");
+/// ```
+pub struct Agent<M: CompletionModel> {
+    /// Completion model (e.g.: OpenAI's gpt-3.5-turbo-1106, Cohere's command-r)
+    model: M,
+    /// System prompt
+    preamble: String,
+    /// Context documents always available to the agent
+    static_context: Vec<Document>,
+    /// Tools that are always available to the agent (identified by their name)
+    static_tools: Vec<String>,
+    /// Temperature of the model
+    temperature: Option<f64>,
+    /// Maximum number of tokens for the completion
+    max_tokens: Option<u64>,
+    /// Additional parameters to be passed to the model
+    additional_params: Option<serde_json::Value>,
+    /// List of vector store, with the sample number
+    dynamic_context: Vec<(usize, Box<dyn VectorStoreIndexDyn>)>,
+    /// Dynamic tools
+    dynamic_tools: Vec<(usize, Box<dyn VectorStoreIndexDyn>)>,
+    /// Indexes of embedding tools rehydrated on retrieval, with the sample number
+    dynamic_tool_indexes: Vec<(usize, DynamicToolIndex)>,
+    /// Actual tool implementations
+    pub tools: ToolSet,
+    /// Views of tool registries, whose enabled tools are always available to the agent
+    tool_views: Vec<ToolView>,
+    /// Maximum number of completion requests sent to the model for a single prompt
+    max_turns: usize,
+    /// Conditions under which a tool output is returned as the final answer
+    stop_conditions: Vec<StopCondition>,
+    /// Maximum number of tool calls executed concurrently
+    tool_concurrency: usize,
+    /// Timeout applied to each tool call
+    tool_timeout: Option<Duration>,
+    /// Conversation memory used by [Agent::converse]
+    memory: Option<AgentMemory>,
+    /// Context window the requests are trimmed to fit in
+    context_budget: Option<ContextBudget>,
+    /// Name of the agent, used to attribute its usage
+    name: String,
+    /// Tracker recording the usage of the agent, with the name of its model
+    usage_tracker: Option<(UsageTracker, String)>,
+    /// Token usage reported by the provider in a response, if any
+    response_usage: fn(&M::Response) -> Option<TokenUsage>,
+    /// Tool calls carried by a response, when the provider can return several at once
+    response_tool_calls: fn(&M::Response) -> Vec<ToolCall>,
+    /// Guardrails checking the prompts and answers of the agent
+    guardrails: Guardrails,
+}
+
+impl<M: CompletionModel> Agent<M> {
+    /// Prompt the agent as part of the conversation `conversation_id`: the chat history is
+    /// loaded from the agent's memory (according to its [MemoryStrategy]), and the prompt and
+    /// answer are recorded in it. Without memory, this is the same as [Prompt::prompt].
+    pub async fn converse(
+        &self,
+        conversation_id: &str,
+        prompt: &str,
+    ) -> Result<String, ConversationError> {
+        let Some(memory) = &self.memory else {
+            return Ok(self.prompt(prompt).await?);
+        };
+
+        let chat_history = memory.history(conversation_id).await?;
+        let (prompt, chat_history) = self.check_input(prompt, chat_history).await?;
+        let response = self.run_checked(&prompt, chat_history).await?;
+        // Record the prompt as rewritten by the input guardrails, e.g.: with redacted secrets
+        memory.record(conversation_id, &prompt, &response).await?;
+
+        Ok(response)
+    }
+
+    /// Token usage of the request the agent would send for `prompt` and `chat_history`,
+    /// after trimming it to the agent's context window. Returns `None` if the agent has no
+    /// context window (see [AgentBuilder::context_window]).
+    pub async fn budget_report(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<Option<BudgetReport>, CompletionError> {
+        Ok(self.request_context(prompt, chat_history).await?.report)
+    }
+
+    /// Execute the tool calls returned in a single completion (e.g.: collected from a
+    /// streaming response) concurrently, using the agent's concurrency limit and tool timeout.
+    /// Results are returned in the order of `calls`.
+    pub async fn call_tools(&self, calls: Vec<ToolCall>) -> Vec<Result<String, ToolSetError>> {
+        dispatch_concurrently(calls, self.tool_concurrency, |call| async move {
+            self.call_tool(&call.name, call.arguments).await
+        })
+        .await
+    }
+
+    /// Call a tool with the agent's tool timeout, approvals and tool policies, recording the
+    /// call in the usage tracker. Tools of the agent's toolset take precedence over tools of
+    /// its views, which take precedence over tools retrieved from its dynamic tool indexes.
+    async fn call_tool(&self, toolname: &str, args: String) -> Result<String, ToolSetError> {
+        let start = Instant::now();
+        let view = self.tool_views.iter().find(|view| view.contains(toolname));
+        let index = self
+            .dynamic_tool_indexes
+            .iter()
+            .find(|(_, index)| index.contains(toolname));
+        let result = match (view, index) {
+            (Some(view), _) if !self.tools.contains(toolname) => {
+                self.tools
+                    .call_guarded(toolname, args, self.tool_timeout, |args| {
+                        view.call(toolname, args)
+                    })
+                    .await
+            }
+            (None, Some((_, index))) if !self.tools.contains(toolname) => {
+                self.tools
+                    .call_guarded(toolname, args, self.tool_timeout, |args| {
+                        index.call(toolname, args)
+                    })
+                    .await
+            }
+            _ => {
+                self.tools
+                    .call_with_timeout(toolname, args, self.tool_timeout)
+                    .await
+            }
+        };
+
+        if let Some((tracker, _)) = &self.usage_tracker {
+            tracker.record_tool_call(toolname, start.elapsed(), result.is_ok());
+        }
+        result
+    }
+
+    /// Send a completion request, recording its usage in the usage tracker
+    pub(crate) async fn send_completion(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<CompletionResponse<M::Response>, CompletionError> {
+        let request = self.completion(prompt, chat_history).await?.build();
+        let Some((tracker, model)) = &self.usage_tracker else {
+            return self.model.completion(request).await;
+        };
+
+        let tokenizer: &dyn Tokenizer = match &self.context_budget {
+            Some(budget) => budget.tokenizer(),
+            None => &ApproxTokenizer::default(),
+        };
+        // Estimated before sending since the request is consumed by the model
+        let prompt_tokens = TokenUsage::estimate_prompt(tokenizer, &request);
+
+        let start = Instant::now();
+        let response = self.model.completion(request).await;
+        let latency = start.elapsed();
+
+        match &response {
+            Ok(response) => {
+                let usage = (self.response_usage)(&response.raw_response).unwrap_or(TokenUsage {
+                    prompt_tokens,
+                    completion_tokens: TokenUsage::estimate_completion(tokenizer, &response.choice),
+                });
+                tracker.record_completion(&self.name, model, usage, latency);
+            }
+            Err(_) => tracker.record_failed_completion(&self.name, model, latency),
+        }
+        response
+    }
+}
+
+/// Condition under which the agent loop ends on a tool call, returning the tool's output
+/// as the answer instead of sending it back to the model.
+pub enum StopCondition {
+    /// Stop after any tool call
+    AnyTool,
+    /// Stop after a call to the tool with the given name
+    Tool(String),
+    /// Stop when the predicate, given the tool name and its output, returns true
+    Custom(StopPredicate),
+}
+
+pub type StopPredicate = Box<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+impl StopCondition {
+    fn matches(&self, toolname: &str, output: &str) -> bool {
+        match self {
+            StopCondition::AnyTool => true,
+            StopCondition::Tool(name) => name == toolname,
+            StopCondition::Custom(predicate) => predicate(toolname, output),
+        }
+    }
+}
+
+/// Documents, tools and chat history of a completion request
+struct RequestContext {
+    documents: Vec<Document>,
+    tools: Vec<ToolDefinition>,
+    chat_history: Vec<Message>,
+    report: Option<BudgetReport>,
+}
+
+impl<M: CompletionModel> Agent<M> {
+    /// Retrieve the dynamic context and tools for `prompt`, and trim the request to the
+    /// context window if the agent has one
+    async fn request_context(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<RequestContext, CompletionError> {
+        let dynamic_context = stream::iter(self.dynamic_context.iter())
+            .then(|(num_sample, index)| async {
+                Ok::<_, VectorStoreError>(
+                    index
+                        .top_n(prompt, *num_sample)
+                        .await?
+                        .into_iter()
+                        .map(|(score, id, doc)| {
+                            // Pretty print the document if possible for better readability
+                            let text = serde_json::to_string_pretty(&doc)
+                                .unwrap_or_else(|_| doc.to_string());
+
+                            (
+                                score,
+                                Document {
+                                    id,
+                                    text,
+                                    additional_props: HashMap::new(),
+                                },
+                            )
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .try_fold(vec![], |mut acc, docs| async {
+                acc.extend(docs);
+                Ok(acc)
+            })
+            .await
+            .map_err(|e| CompletionError::RequestError(Box::new(e)))?;
+
+        let dynamic_tools = stream::iter(self.dynamic_tools.iter())
+            .then(|(num_sample, index)| async {
+                Ok::<_, VectorStoreError>(
+                    index
+                        .top_n_ids(prompt, *num_sample)
+                        .await?
+                        .into_iter()
+                        .map(|(_, id)| id)
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .try_fold(vec![], |mut acc, docs| async {
+                for doc in docs {
+                    if let Some(tool) = self.tools.get(&doc) {
+                        acc.push(tool.definition(prompt.into()).await)
+                    } else {
+                        tracing::warn!("Tool implementation not found in toolset: {}", doc);
+                    }
+                }
+                Ok(acc)
+            })
+            .await
+            .map_err(|e| CompletionError::RequestError(Box::new(e)))?;
+
+        let static_tools = stream::iter(self.static_tools.iter())
+            .filter_map(|toolname| async move {
+                if let Some(tool) = self.tools.get(toolname) {
+                    Some(tool.definition(prompt.into()).await)
+                } else {
+                    tracing::warn!("Tool implementation not found in toolset: {}", toolname);
+                    None
+                }
+            })
+            .collect::<Vec<_>>()
+            .await;
+
+        let mut view_tools = vec![];
+        for view in &self.tool_views {
+            view_tools.extend(view.definitions(prompt).await);
+        }
+
+        let mut index_tools = vec![];
+        for (num_sample, index) in &self.dynamic_tool_indexes {
+            index_tools.extend(
+                index
+                    .definitions(prompt, *num_sample)
+                    .await
+                    .map_err(|e| CompletionError::RequestError(Box::new(e)))?,
+            );
+        }
+
+        let tools = [static_tools, view_tools, dynamic_tools, index_tools].concat();
+
+        let Some(budget) = &self.context_budget else {
+            return Ok(RequestContext {
+                documents: [
+                    self.static_context.clone(),
+                    dynamic_context.into_iter().map(|(_, doc)| doc).collect(),
+                ]
+                .concat(),
+                tools,
+                chat_history,
+                report: None,
+            });
+        };
+
+        let trimmed = budget
+            .fit(
+                prompt,
+                &self.preamble,
+                &self.static_context,
+                &tools,
+                dynamic_context,
+                chat_history,
+                self.max_tokens,
+            )
+            .map_err(|e| CompletionError::RequestError(Box::new(e)))?;
+
+        tracing::debug!(target: "rig",
+            "Request budget: {} tokens out of {}",
+            trimmed.report.total(),
+            trimmed.report.limit
+        );
+
+        Ok(RequestContext {
+            documents: [self.static_context.clone(), trimmed.dynamic_context].concat(),
+            tools,
+            chat_history: trimmed.chat_history,
+            report: Some(trimmed.report),
+        })
+    }
+}
+
+impl<M: CompletionModel> Completion<M> for Agent<M> {
+    async fn completion(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<CompletionRequestBuilder<M>, CompletionError> {
+        let context = self.request_context(prompt, chat_history).await?;
+
+        Ok(self
+            .model
+            .completion_request(prompt)
+            .preamble(self.preamble.clone())
+            .messages(context.chat_history)
+            .documents(context.documents)
+            .tools(context.tools)
+            .temperature_opt(self.temperature)
+            .max_tokens_opt(self.max_tokens)
+            .additional_params_opt(self.additional_params.clone()))
+    }
+}
+
+impl<M: CompletionModel> Prompt for Agent<M> {
+    async fn prompt(&self, prompt: &str) -> Result<String, PromptError> {
+        self.chat(prompt, vec![]).await
+    }
+}
+
+impl<M: CompletionModel> Chat for Agent<M> {
+    /// Run the agent loop on the prompt, after checking it and the chat history with the
+    /// input guardrails, and check the final answer with the output guardrails
+    async fn chat(&self, prompt: &str, chat_history: Vec<Message>) -> Result<String, PromptError> {
+        let (prompt, chat_history) = self.check_input(prompt, chat_history).await?;
+        self.run_checked(&prompt, chat_history).await
+    }
+}
+
+impl<M: CompletionModel> Agent<M> {
+    /// Apply the input guardrails to the prompt and to the user messages of the chat history,
+    /// returning them as possibly rewritten by the guardrails
+    async fn check_input(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<(String, Vec<Message>), PromptError> {
+        let mut checked_history = Vec::with_capacity(chat_history.len());
+        for mut message in chat_history {
+            if message.role == "user" {
+                message.content = self.guardrails.apply(Stage::Input, message.content).await?;
+            }
+            checked_history.push(message);
+        }
+
+        let prompt = self
+            .guardrails
+            .apply(Stage::Input, prompt.to_string())
+            .await?;
+        Ok((prompt, checked_history))
+    }
+
+    /// Run the agent loop on an input already checked by [Agent::check_input], and check the
+    /// final answer with the output guardrails
+    async fn run_checked(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<String, PromptError> {
+        let answer = self.run_loop(prompt, chat_history).await?;
+        self.guardrails.apply(Stage::Output, answer).await
+    }
+}
+
+impl<M: CompletionModel> Agent<M> {
+    /// Run the agent loop: the model is prompted, and as long as it answers with tool calls,
+    /// the tools are executed (concurrently if the response carries several calls, see
+    /// [AgentBuilder::parallel_tool_calls]) and their outputs sent back to the model as tool
+    /// result messages, for at most `max_turns` completions. Calls with arguments not matching
+    /// the tool's schema are answered with a correction message listing the invalid fields.
+    ///
+    /// If a stop condition matches a call, the output of that call is returned. If the model
+    /// is still calling tools when the turn budget is exhausted, the loop fails with a
+    /// [MaxTurnsError].
+    async fn run_loop(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<String, PromptError> {
+        let mut chat_history = chat_history;
+        let mut prompt = prompt.to_string();
+
+        for turn in 1..=self.max_turns {
+            let response = self.send_completion(&prompt, chat_history.clone()).await?;
+            let calls = match response.choice {
+                ModelChoice::Message(msg) => return Ok(msg),
+                ModelChoice::ToolCall(toolname, args) => {
+                    match (self.response_tool_calls)(&response.raw_response) {
+                        calls if !calls.is_empty() => calls,
+                        _ => vec![ToolCall {
+                            id: "call_0".into(),
+                            name: toolname,
+                            arguments: args.to_string(),
+                        }],
+                    }
+                }
+            };
+
+            let results = self.call_tools(calls.clone()).await;
+            let mut tool_results = Vec::with_capacity(calls.len());
+            for (call, result) in calls.iter().zip(results) {
+                let toolname = &call.name;
+                match result {
+                    Ok(output) => {
+                        if self
+                            .stop_conditions
+                            .iter()
+                            .any(|condition| condition.matches(toolname, &output))
+                        {
+                            return Ok(output);
+                        }
+
+                        tracing::debug!(target: "rig",
+                            "Turn {turn}/{}: sending output of tool {toolname} back to the model",
+                            self.max_turns
+                        );
+                        tool_results.push(tool_result_message(call, &output));
+                    }
+                    // Give the model a chance to fix invalid arguments if it has turns left
+                    Err(ToolSetError::ToolCallError(ToolError::ValidationError(error)))
+                        if turn < self.max_turns =>
+                    {
+                        tracing::debug!(target: "rig",
+                            "Turn {turn}/{}: invalid arguments for tool {toolname}: {error}",
+                            self.max_turns
+                        );
+                        tool_results.push(tool_result_message(
+                            call,
+                            &error.correction_prompt(toolname),
+                        ));
+                    }
+                    Err(error) => return Err(error.into()),
+                }
+            }
+
+            if turn == self.max_turns {
+                return Err(MaxTurnsError {
+                    max_turns: self.max_turns,
+                }
+                .into());
+            }
+
+            chat_history.push(Message {
+                role: "user".into(),
+                content: prompt,
+            });
+            chat_history.extend(calls.iter().map(tool_call_message));
+            chat_history.extend(tool_results);
+            prompt = TOOL_RESULTS_PROMPT.to_string();
+        }
+
+        unreachable!("max_turns is at least 1")
+    }
+}
+
+/// The model was still calling tools when the agent's turn budget (see
+/// [AgentBuilder::max_turns]) was exhausted. Returned inside a [PromptError], as a request
+/// error.
+#[derive(Debug, thiserror::Error)]
+#[error("MaxTurnsError: the model was still calling tools after {max_turns} turns")]
+pub struct MaxTurnsError {
+    pub max_turns: usize,
+}
+
+impl From<MaxTurnsError> for PromptError {
+    fn from(error: MaxTurnsError) -> Self {
+        PromptError::CompletionError(CompletionError::RequestError(Box::new(error)))
+    }
+}
+
+/// Prompt of the turns following tool calls, whose results are in the chat history
+const TOOL_RESULTS_PROMPT: &str = "Continue with the results of the tool calls above.";
+
+/// Message recording a tool call made by the model, with the id its result refers to
+pub(crate) fn tool_call_message(call: &ToolCall) -> Message {
+    Message {
+        role: "assistant".into(),
+        content: format!(
+            "Calling tool `{}` (call id `{}`) with arguments: {}",
+            call.name, call.id, call.arguments
+        ),
+    }
+}
+
+/// Tool message carrying the result of `call` back to the model
+fn tool_result_message(call: &ToolCall, output: &str) -> Message {
+    Message {
+        role: "tool".into(),
+        content: format!(
+            "Result of tool `{}` (call id `{}`):\n{output}",
+            call.name, call.id
+        ),
+    }
+}
+
+impl<M: StreamingCompletionModel> StreamingPrompt for Agent<M> {
+    async fn stream_prompt(&self, prompt: &str) -> Result<StreamingResult, CompletionError> {
+        self.stream_chat(prompt, vec![]).await
+    }
+}
+
+impl<M: StreamingCompletionModel> StreamingChat for Agent<M> {
+    /// Stream the response of the model. Without output guardrails, chunks are yielded as
+    /// they arrive. With output guardrails, the response is buffered until it is complete and
+    /// checked, then yielded as a single text chunk followed by the tool calls.
+    async fn stream_chat(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<StreamingResult, CompletionError> {
+        let (prompt, chat_history) = self
+            .check_input(prompt, chat_history)
+            .await
+            .map_err(into_completion_error)?;
+        let request = self.completion(&prompt, chat_history).await?.build();
+        let stream = self.stream_completion(request).await?;
+        if !self.guardrails.has(Stage::Output) {
+            return Ok(stream);
+        }
+
+        let response = collect_stream(stream, |_| {}).await?;
+        let text = self
+            .guardrails
+            .apply(Stage::Output, response.text)
+            .await
+            .map_err(into_completion_error)?;
+
+        let chunks = Some(StreamingChoice::Message(text))
+            .into_iter()
+            .chain(
+                response
+                    .tool_calls
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, call)| {
+                        StreamingChoice::ToolCall(ToolCallDelta {
+                            index,
+                            id: Some(call.id),
+                            name: Some(call.name),
+                            arguments: call.arguments,
+                        })
+                    }),
+            )
+            .map(Ok)
+            .collect::<Vec<_>>();
+        Ok(Box::pin(stream::iter(chunks)))
+    }
+}
+
+impl<M: StreamingCompletionModel> Agent<M> {
+    /// Send a streaming completion request, recording its usage in the usage tracker once
+    /// the stream completes
+    async fn stream_completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<StreamingResult, CompletionError> {
+        let Some((tracker, model)) = &self.usage_tracker else {
+            return self.model.stream(request).await;
+        };
+
+        let budget = self.context_budget.clone();
+        let tokenizer = move |text: &str| match &budget {
+            Some(budget) => budget.tokenizer().count_tokens(text),
+            None => ApproxTokenizer::default().count_tokens(text),
+        };
+        let prompt_tokens = TokenUsage::estimate_prompt(&tokenizer, &request);
+
+        let start = Instant::now();
+        match self.model.stream(request).await {
+            Ok(stream) => Ok(track_stream_usage(
+                stream,
+                UsageRecord {
+                    tracker: tracker.clone(),
+                    agent: self.name.clone(),
+                    model: model.clone(),
+                    prompt_tokens,
+                    start,
+                },
+                tokenizer,
+            )),
+            Err(error) => {
+                tracker.record_failed_completion(&self.name, model, start.elapsed());
+                Err(error)
+            }
+        }
+    }
+}
+
+/// Errors of the guardrails surface as request errors in streaming completions
+fn into_completion_error(error: PromptError) -> CompletionError {
+    match error {
+        PromptError::CompletionError(error) => error,
+        error => CompletionError::RequestError(Box::new(error)),
+    }
+}
+
+/// Where and how to record the usage of a streamed completion
+struct UsageRecord {
+    tracker: UsageTracker,
+    agent: String,
+    model: String,
+    prompt_tokens: u64,
+    start: Instant,
+}
+
+/// Wrap `stream` to record its usage once it is fully consumed, estimating the completion
+/// tokens of the streamed text and tool calls with `tokenizer`. A stream dropped before its
+/// end is not recorded.
+fn track_stream_usage(
+    stream: StreamingResult,
+    record: UsageRecord,
+    tokenizer: impl Tokenizer + 'static,
+) -> StreamingResult {
+    let state = Some((stream, String::new(), record, tokenizer));
+    Box::pin(stream::unfold(state, |state| async move {
+        let (mut stream, mut completion, record, tokenizer) = state?;
+        match stream.next().await {
+            Some(Ok(chunk)) => {
+                match &chunk {
+                    StreamingChoice::Message(delta) => completion.push_str(delta),
+                    StreamingChoice::ToolCall(delta) => {
+                        completion.extend(delta.name.as_deref());
+                        completion.push_str(&delta.arguments);
+                    }
+                }
+                Some((Ok(chunk), Some((stream, completion, record, tokenizer))))
+            }
+            Some(Err(error)) => {
+                record.tracker.record_failed_completion(
+                    &record.agent,
+                    &record.model,
+                    record.start.elapsed(),
+                );
+                Some((Err(error), None))
+            }
+            None => {
+                let usage = TokenUsage {
+                    prompt_tokens: record.prompt_tokens,
+                    completion_tokens: tokenizer.count_tokens(&completion) as u64,
+                };
+                record.tracker.record_completion(
+                    &record.agent,
+                    &record.model,
+                    usage,
+                    record.start.elapsed(),
+                );
+                None
+            }
+        }
+    }))
+}
+
 /// A builder for creating an agent
 ///
 /// # Example
@@ -36,10 +778,38 @@ pub struct AgentBuilder<M: CompletionModel> {
     dynamic_context: Vec<(usize, Box<dyn VectorStoreIndexDyn>)>,
     /// Dynamic tools
     dynamic_tools: Vec<(usize, Box<dyn VectorStoreIndexDyn>)>,
+    /// Indexes of embedding tools rehydrated on retrieval
+    dynamic_tool_indexes: Vec<(usize, DynamicToolIndex)>,
     /// Temperature of the model
     temperature: Option<f64>,
     /// Actual tool implementations
     tools: ToolSet,
+    /// Views of tool registries
+    tool_views: Vec<ToolView>,
+    /// Maximum number of completion requests per prompt
+    max_turns: usize,
+    /// Conditions ending the agent loop on a tool call
+    stop_conditions: Vec<StopCondition>,
+    /// Maximum number of concurrent tool calls
+    tool_concurrency: usize,
+    /// Timeout applied to each tool call
+    tool_timeout: Option<Duration>,
+    /// Conversation memory
+    memory: Option<AgentMemory>,
+    /// Context window of the model
+    context_budget: Option<ContextBudget>,
+    /// Name of the agent
+    name: Option<String>,
+    /// Usage tracker, with the name of the model
+    usage_tracker: Option<(UsageTracker, String)>,
+    /// Token usage reported by the provider
+    response_usage: fn(&M::Response) -> Option<TokenUsage>,
+    /// Tool calls carried by a response
+    response_tool_calls: fn(&M::Response) -> Vec<ToolCall>,
+    /// Input and output guardrails
+    guardrails: Guardrails,
+    /// Partials available to the templates of the agent
+    templates: Templates,
 }
 
 impl<M: CompletionModel> AgentBuilder<M> {
@@ -55,9 +825,30 @@ impl<M: CompletionModel> AgentBuilder<M> {
             dynamic_context: vec![],
             dynamic_tools: vec![],
             tools: ToolSet::default(),
+            tool_views: vec![],
+            dynamic_tool_indexes: vec![],
+            max_turns: 1,
+            stop_conditions: vec![],
+            tool_concurrency: 8,
+            tool_timeout: None,
+            memory: None,
+            context_budget: None,
+            name: None,
+            usage_tracker: None,
+            response_usage: |_| None,
+            response_tool_calls: |_| vec![],
+            guardrails: Guardrails::default(),
+            templates: Templates::default(),
         }
     }
 
+    /// Set the name of the agent, used to attribute its usage in a [UsageTracker].
+    /// Defaults to "agent".
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
     /// Set the system prompt
     pub fn preamble(mut self, preamble: &str) -> Self {
         self.preamble = Some(preamble.into());
@@ -74,6 +865,23 @@ impl<M: CompletionModel> AgentBuilder<M> {
         self
     }
 
+    /// Set the system prompt by rendering the template `source` with `vars`, see
+    /// [template](crate::template) for the syntax
+    pub fn preamble_template(
+        self,
+        source: &str,
+        vars: &impl Serialize,
+    ) -> Result<Self, TemplateError> {
+        let preamble = self.templates.parse(source)?.render(vars)?;
+        Ok(self.preamble(&preamble))
+    }
+
+    /// Register a partial template that can be included by name in the agent's templates
+    pub fn template_partial(mut self, name: &str, source: &str) -> Self {
+        self.templates.add_partial(name, source);
+        self
+    }
+
     /// Add a static context document to the agent
     pub fn context(mut self, doc: &str) -> Self {
         self.static_context.push(Document {
@@ -92,6 +900,50 @@ impl<M: CompletionModel> AgentBuilder<M> {
         self
     }
 
+    /// Add all the tools of `toolset` as static tools, e.g.: tools selected from a
+    /// [ToolRegistry](crate::tool_registry::ToolRegistry)
+    pub fn tools(mut self, toolset: ToolSet) -> Self {
+        self.static_tools.extend(toolset.tools.keys().cloned());
+        self.tools.add_tools(toolset);
+        self
+    }
+
+    /// Make the enabled tools of `view` available to the agent. Unlike [AgentBuilder::tools],
+    /// tools registered, enabled, disabled or removed after the agent is built are taken into
+    /// account. Policies and approvals apply to them under their qualified names (see
+    /// [AgentBuilder::tool_policy] and [AgentBuilder::require_approval]).
+    pub fn tool_view(mut self, view: ToolView) -> Self {
+        self.tool_views.push(view);
+        self
+    }
+
+    /// Add a static tool to the agent whose calls must be approved by the approval handler
+    /// before being executed. A rejection is sent back to the model as the tool's result, so
+    /// allow it more than one turn (see [AgentBuilder::max_turns]) to answer after one.
+    pub fn tool_requiring_approval(mut self, tool: impl Tool + 'static) -> Self {
+        self.tools.require_approval(&tool.name());
+        self.tool(tool)
+    }
+
+    /// Require the approval of the approval handler for calls to the tool `toolname`, e.g.: a
+    /// tool of a [ToolView] or of a [DynamicToolIndex]
+    pub fn require_approval(mut self, toolname: &str) -> Self {
+        self.tools.require_approval(toolname);
+        self
+    }
+
+    /// Set the handler deciding on calls to tools requiring approval
+    pub fn approval_handler(mut self, handler: impl ApprovalHandler + 'static) -> Self {
+        self.tools.set_approval_handler(handler);
+        self
+    }
+
+    /// Attach an execution policy (timeout, retries, circuit breaker) to the tool `toolname`
+    pub fn tool_policy(mut self, toolname: &str, policy: ToolPolicy) -> Self {
+        self.tools.set_policy(toolname, policy);
+        self
+    }
+
     pub fn dynamic_context(
         mut self,
         sample: usize,
@@ -113,6 +965,13 @@ impl<M: CompletionModel> AgentBuilder<M> {
         self
     }
 
+    /// Add an index of embedding tools, from which the `sample` tools most relevant to each
+    /// prompt are retrieved and initialized from their saved context. See [DynamicToolIndex].
+    pub fn dynamic_tool_index(mut self, sample: usize, index: DynamicToolIndex) -> Self {
+        self.dynamic_tool_indexes.push((sample, index));
+        self
+    }
+
     pub fn temperature(mut self, temperature: f64) -> Self {
         self.temperature = Some(temperature);
         self
@@ -128,6 +987,90 @@ impl<M: CompletionModel> AgentBuilder<M> {
         self
     }
 
+    /// Set the maximum number of completion requests the agent may send for a single
+    /// prompt. Each tool call answered by the model uses one turn. Defaults to 1.
+    ///
+    /// If the model is still calling tools after the last turn, the prompt fails with a
+    /// [MaxTurnsError]. To return the output of a tool call as the answer instead (e.g.: the
+    /// first call of a single-turn agent), add a stop condition such as
+    /// [StopCondition::AnyTool].
+    pub fn max_turns(mut self, max_turns: usize) -> Self {
+        self.max_turns = max_turns.max(1);
+        self
+    }
+
+    /// Add a condition under which the agent loop ends on a tool call
+    pub fn stop_when(mut self, condition: StopCondition) -> Self {
+        self.stop_conditions.push(condition);
+        self
+    }
+
+    /// Set the maximum number of tool calls executed concurrently when the model
+    /// requests several tools at once. Defaults to 8.
+    pub fn tool_concurrency(mut self, concurrency: usize) -> Self {
+        self.tool_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Set a timeout applied to each tool call
+    pub fn tool_timeout(mut self, timeout: Duration) -> Self {
+        self.tool_timeout = Some(timeout);
+        self
+    }
+
+    /// Store the agent's conversations in `memory`, see [Agent::converse]
+    pub fn memory(
+        mut self,
+        memory: impl ConversationMemory + 'static,
+        strategy: MemoryStrategy,
+    ) -> Self {
+        self.memory = Some(AgentMemory::new(memory, strategy));
+        self
+    }
+
+    /// Trim every request to fit in a context window of `window` tokens, as counted by
+    /// `tokenizer`, keeping `max_tokens` free for the completion. The lowest-scoring dynamic
+    /// context documents are dropped first, then the oldest chat history messages.
+    pub fn context_window(mut self, window: usize, tokenizer: impl Tokenizer + 'static) -> Self {
+        self.context_budget = Some(ContextBudget::new(window, tokenizer));
+        self
+    }
+
+    /// Record the token usage, latency and cost of the agent's completions and tool calls in
+    /// `tracker`. `model` is the name of the model in the tracker's price table.
+    /// Token usage is estimated unless [AgentBuilder::provider_usage] is set.
+    pub fn usage_tracker(mut self, tracker: UsageTracker, model: &str) -> Self {
+        self.usage_tracker = Some((tracker, model.into()));
+        self
+    }
+
+    /// Check every prompt with `guardrail` before it is sent to the model. On a finding, the
+    /// prompt is blocked, rewritten or flagged according to `action`.
+    pub fn input_guardrail(
+        mut self,
+        guardrail: impl Guardrail + 'static,
+        action: GuardrailAction,
+    ) -> Self {
+        self.guardrails.add(Stage::Input, guardrail, action);
+        self
+    }
+
+    /// Check every final answer of the agent with `guardrail` before it is returned
+    pub fn output_guardrail(
+        mut self,
+        guardrail: impl Guardrail + 'static,
+        action: GuardrailAction,
+    ) -> Self {
+        self.guardrails.add(Stage::Output, guardrail, action);
+        self
+    }
+
+    /// Call `handler` with the findings of guardrails attached with [GuardrailAction::Flag]
+    pub fn on_guardrail_flag(mut self, handler: impl Fn(&Flag) + Send + Sync + 'static) -> Self {
+        self.guardrails.set_flag_handler(handler);
+        self
+    }
+
     pub fn build(self) -> Agent<M> {
         Agent {
             model: self.model,
@@ -140,6 +1083,381 @@ impl<M: CompletionModel> AgentBuilder<M> {
             dynamic_context: self.dynamic_context,
             dynamic_tools: self.dynamic_tools,
             tools: self.tools,
+            tool_views: self.tool_views,
+            dynamic_tool_indexes: self.dynamic_tool_indexes,
+            max_turns: self.max_turns,
+            stop_conditions: self.stop_conditions,
+            tool_concurrency: self.tool_concurrency,
+            tool_timeout: self.tool_timeout,
+            memory: self.memory,
+            context_budget: self.context_budget,
+            name: self.name.unwrap_or_else(|| "agent".into()),
+            usage_tracker: self.usage_tracker,
+            response_usage: self.response_usage,
+            response_tool_calls: self.response_tool_calls,
+            guardrails: self.guardrails,
+        }
+    }
+}
+
+impl<M: CompletionModel> AgentBuilder<M> {
+    /// Build an agent prompted with a `V` struct rendered through the template `source`.
+    /// Fails if the template uses variables which are not fields of `V`.
+    pub fn build_templated<V: Serialize + JsonSchema>(
+        self,
+        source: &str,
+    ) -> Result<TemplatedAgent<M, V>, TemplateError> {
+        let template = self.templates.compile::<V>(source)?;
+        Ok(TemplatedAgent::new(self.build(), template))
+    }
+}
+
+impl<M> AgentBuilder<M>
+where
+    M: CompletionModel,
+    M::Response: ResponseUsage,
+{
+    /// Record the token usage reported by the provider instead of estimating it
+    pub fn provider_usage(mut self) -> Self {
+        self.response_usage = ResponseUsage::token_usage;
+        self
+    }
+}
+
+impl<M> AgentBuilder<M>
+where
+    M: CompletionModel,
+    M::Response: ResponseToolCalls,
+{
+    /// Execute all the tool calls of a response concurrently (see [Agent::call_tools]) instead
+    /// of only the first one
+    pub fn parallel_tool_calls(mut self) -> Self {
+        self.response_tool_calls = ResponseToolCalls::tool_calls;
+        self
+    }
+}
+
+impl<M: TokenizedModel> AgentBuilder<M> {
+    /// Same as [AgentBuilder::context_window], using the model's own context window and tokenizer
+    pub fn model_context_window(mut self) -> Self {
+        self.context_budget = Some(ContextBudget::for_model(&self.model));
+        self
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    /// Completion model answering with a scripted list of choices, recording every request
+    #[derive(Clone, Default)]
+    pub struct ScriptedModel {
+        pub choices: Arc<Mutex<VecDeque<ModelChoice>>>,
+        pub requests: Arc<Mutex<Vec<CompletionRequest>>>,
+    }
+
+    impl ScriptedModel {
+        pub fn new(choices: impl IntoIterator<Item = ModelChoice>) -> Self {
+            Self {
+                choices: Arc::new(Mutex::new(choices.into_iter().collect())),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl CompletionModel for ScriptedModel {
+        type Response = ();
+
+        async fn completion(
+            &self,
+            request: CompletionRequest,
+        ) -> Result<CompletionResponse<()>, CompletionError> {
+            self.requests.lock().unwrap().push(request);
+            let choice = self
+                .choices
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or_else(|| CompletionError::ResponseError("Script exhausted".into()))?;
+
+            Ok(CompletionResponse {
+                choice,
+                raw_response: (),
+            })
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct OperationArgs {
+        x: i32,
+        y: i32,
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("Math error")]
+    pub struct MathError;
+
+    pub struct Adder;
+
+    impl Tool for Adder {
+        const NAME: &'static str = "add";
+        type Error = MathError;
+        type Args = OperationArgs;
+        type Output = i32;
+
+        async fn definition(&self, _prompt: String) -> ToolDefinition {
+            ToolDefinition {
+                name: Self::NAME.to_string(),
+                description: "Add x and y together".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "x": { "type": "number" },
+                        "y": { "type": "number" }
+                    },
+                    "required": ["x", "y"]
+                }),
+            }
+        }
+
+        async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+            Ok(args.x + args.y)
+        }
+    }
+
+    fn add_call(x: i32, y: i32) -> ModelChoice {
+        ModelChoice::ToolCall("add".into(), json!({ "x": x, "y": y }))
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_turns_fail_unless_stopped() {
+        let model = ScriptedModel::new([add_call(1, 2), add_call(1, 2)]);
+        let agent = AgentBuilder::new(model.clone()).tool(Adder).build();
+        let error = agent.prompt("1 + 2?").await.unwrap_err();
+        assert!(error.to_string().contains("MaxTurnsError"));
+
+        let agent = AgentBuilder::new(model)
+            .tool(Adder)
+            .stop_when(StopCondition::AnyTool)
+            .build();
+        assert_eq!(agent.prompt("1 + 2?").await.unwrap(), "3");
+    }
+
+    #[tokio::test]
+    async fn test_multi_turn_feeds_tool_results_back() {
+        let model = ScriptedModel::new([
+            add_call(1, 2),
+            add_call(3, 4),
+            ModelChoice::Message("The answer is 10".into()),
+        ]);
+        let agent = AgentBuilder::new(model.clone())
+            .tool(Adder)
+            .max_turns(5)
+            .build();
+
+        let answer = agent.prompt("1 + 2 + 3 + 4?").await.unwrap();
+        assert_eq!(answer, "The answer is 10");
+
+        let requests = model.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1].prompt, TOOL_RESULTS_PROMPT);
+        let history = &requests[2].chat_history;
+        assert_eq!(history.len(), 6);
+        assert_eq!(history[0].content, "1 + 2 + 3 + 4?");
+        assert_eq!(history[1].role, "assistant");
+        assert_eq!(history[2].role, "tool");
+        assert_eq!(
+            history[2].content,
+            "Result of tool `add` (call id `call_0`):\n3"
+        );
+        assert_eq!(
+            history[5].content,
+            "Result of tool `add` (call id `call_0`):\n7"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_arguments_are_corrected() {
+        let model = ScriptedModel::new([
+            ModelChoice::ToolCall("add".into(), json!({ "x": 1 })),
+            add_call(1, 2),
+            ModelChoice::Message("3".into()),
+        ]);
+        let agent = AgentBuilder::new(model.clone())
+            .tool(Adder)
+            .max_turns(3)
+            .build();
+
+        assert_eq!(agent.prompt("1 + 2?").await.unwrap(), "3");
+
+        let requests = model.requests.lock().unwrap();
+        let correction = requests[1].chat_history.last().unwrap();
+        assert_eq!(correction.role, "tool");
+        assert!(correction
+            .content
+            .contains("- $.y: missing required property"));
+    }
+
+    #[tokio::test]
+    async fn test_stop_condition_ends_loop() {
+        let model = ScriptedModel::new([add_call(1, 2), add_call(3, 4)]);
+        let agent = AgentBuilder::new(model.clone())
+            .tool(Adder)
+            .max_turns(5)
+            .stop_when(StopCondition::Custom(Box::new(|_, output| output == "7")))
+            .build();
+
+        assert_eq!(agent.prompt("1 + 2 + 3 + 4?").await.unwrap(), "7");
+        assert_eq!(model.requests.lock().unwrap().len(), 2);
+    }
+
+    /// Model answering with several tool calls per response, or with a message once the
+    /// script is exhausted
+    #[derive(Clone, Default)]
+    struct ParallelModel {
+        calls: Arc<Mutex<VecDeque<Vec<ToolCall>>>>,
+        requests: Arc<Mutex<Vec<CompletionRequest>>>,
+    }
+
+    struct ParallelResponse(Vec<ToolCall>);
+
+    impl ResponseToolCalls for ParallelResponse {
+        fn tool_calls(&self) -> Vec<ToolCall> {
+            self.0.clone()
         }
     }
+
+    impl CompletionModel for ParallelModel {
+        type Response = ParallelResponse;
+
+        async fn completion(
+            &self,
+            request: CompletionRequest,
+        ) -> Result<CompletionResponse<ParallelResponse>, CompletionError> {
+            self.requests.lock().unwrap().push(request);
+            let calls = self.calls.lock().unwrap().pop_front().unwrap_or_default();
+            let choice = match calls.first() {
+                Some(call) => ModelChoice::ToolCall(
+                    call.name.clone(),
+                    serde_json::from_str(&call.arguments).unwrap(),
+                ),
+                None => ModelChoice::Message("Done".into()),
+            };
+
+            Ok(CompletionResponse {
+                choice,
+                raw_response: ParallelResponse(calls),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parallel_tool_calls_are_all_dispatched() {
+        let call = |id: &str, arguments: Value| ToolCall {
+            id: id.into(),
+            name: "add".into(),
+            arguments: arguments.to_string(),
+        };
+        let model = ParallelModel {
+            calls: Arc::new(Mutex::new(VecDeque::from([vec![
+                call("call_a", json!({ "x": 1, "y": 2 })),
+                call("call_b", json!({ "x": "3", "y": 4 })),
+                call("call_c", json!({ "x": 5, "y": 6 })),
+            ]]))),
+            ..Default::default()
+        };
+        let agent = AgentBuilder::new(model.clone())
+            .tool(Adder)
+            .max_turns(3)
+            .parallel_tool_calls()
+            .build();
+
+        assert_eq!(agent.prompt("Add these").await.unwrap(), "Done");
+
+        let requests = model.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        // The original prompt, one assistant message per call, then one tool message per call
+        let history = &requests[1].chat_history;
+        assert_eq!(history.len(), 7);
+        assert!(history[1].content.contains("call id `call_a`"));
+        assert_eq!(
+            history[4].content,
+            "Result of tool `add` (call id `call_a`):\n3"
+        );
+        assert!(history[5].content.contains("(call id `call_b`)"));
+        assert!(history[5].content.contains("$.x"));
+        assert_eq!(
+            history[6].content,
+            "Result of tool `add` (call id `call_c`):\n11"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_context_window_trims_oldest_history() {
+        let model = ScriptedModel::new([ModelChoice::Message("Done".into())]);
+        // One token per word
+        let agent = AgentBuilder::new(model.clone())
+            .preamble("Be brief")
+            .max_tokens(10)
+            .context_window(40, |text: &str| text.split_whitespace().count())
+            .build();
+
+        let history = ["one two three", "four five six", "seven", "eight"]
+            .into_iter()
+            .zip(["user", "assistant"].into_iter().cycle())
+            .map(|(content, role)| Message {
+                role: role.into(),
+                content: content.into(),
+            })
+            .collect::<Vec<_>>();
+
+        let report = agent
+            .budget_report("Summarize", history.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.limit, 30);
+        assert_eq!(report.dropped_messages, 2);
+        assert!(report.total() <= report.limit);
+
+        agent.chat("Summarize", history).await.unwrap();
+        let requests = model.requests.lock().unwrap();
+        assert_eq!(
+            requests[0]
+                .chat_history
+                .iter()
+                .map(|message| message.content.as_str())
+                .collect::<Vec<_>>(),
+            vec!["seven", "eight"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_usage_tracker_records_completions_and_tools() {
+        let tracker = UsageTracker::default();
+        let model = ScriptedModel::new([add_call(1, 2), ModelChoice::Message("3".into())]);
+        let agent = AgentBuilder::new(model)
+            .name("calculator")
+            .tool(Adder)
+            .max_turns(2)
+            .usage_tracker(tracker.clone(), "scripted")
+            .build();
+
+        agent.prompt("1 + 2?").await.unwrap();
+
+        let report = tracker.report();
+        assert_eq!(report.agents["calculator"].requests, 2);
+        assert_eq!(report.models["scripted"].requests, 2);
+        assert!(report.total.prompt_tokens > 0);
+        assert_eq!(report.tools["add"].calls, 1);
+        assert_eq!(report.tools["add"].failed_calls, 0);
+    }
 }
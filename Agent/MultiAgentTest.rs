@@ -2,7 +2,7 @@ use std::env;
 
 use rig::{
     agent::{Agent, AgentBuilder},
-    cli_chatbot::cli_chatbot,
+    cli_chatbot::{cli_chatbot, WholeResponse},
     completion::{Chat, CompletionModel, Message, PromptError},
     providers::openai::Client as OpenAIClient,
 };
@@ -62,7 +62,7 @@ async fn main() -> Result<(), anyhow::Error> {
     let translator = EnglishTranslator::new(model);
 
     // Spin up a chatbot using the agent
-    cli_chatbot(translator).await?;
+    cli_chatbot(WholeResponse(translator)).await?;
 
     Ok(())
 }
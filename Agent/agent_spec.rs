@@ -0,0 +1,296 @@
+//! Declarative agent definitions, loaded from TOML or YAML files.
+//!
+//! An [AgentSpec] describes everything [AgentBuilder] is usually given in code: the provider
+//! and model, the preamble, static context documents (read from files), the tools (by name,
+//! from a [ToolRegistry]), the temperature, `max_tokens` and additional parameters. Prompts can
+//! then be changed by editing the spec file, without recompiling.
+//!
+//! # Example
+//! ```toml
+//! name = "analyst"
+//! provider = "hydranta"
+//! model = "qbt-1.a"
+//! preamble = "You are a market analyst. Answer with facts only."
+//! context_files = ["docs/glossary.md"]
+//! tools = ["get_price", "get_balance"]
+//! temperature = 0.2
+//! max_tokens = 1024
+//!
+//! [additional_params]
+//! top_p = 0.9
+//! ```
+//! ```
+//! use Hydranta::agent_spec::AgentSpec;
+//!
+//! let spec = AgentSpec::from_file("agents/analyst.toml")?;
+//! let agent = spec.build(&hydranta_client, &registry)?;
+//! ```
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    agent::{Agent, AgentBuilder},
+    completion::CompletionModel,
+    tool_registry::{ToolRegistry, ToolRegistryError},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum AgentSpecError {
+    #[error("IoError: {0}: {1}")]
+    IoError(PathBuf, std::io::Error),
+
+    #[error("TomlError: {0}")]
+    TomlError(#[from] toml::de::Error),
+
+    #[error("YamlError: {0}")]
+    YamlError(#[from] serde_yaml::Error),
+
+    /// The spec file extension is neither `.toml`, `.yaml` nor `.yml`
+    #[error("UnsupportedFormat: {0}")]
+    UnsupportedFormat(PathBuf),
+
+    /// The spec names a different provider than the one given to build it
+    #[error("ProviderMismatch: spec requires provider {spec}, got {provider}")]
+    ProviderMismatch { spec: String, provider: String },
+
+    #[error("ToolError: {0}")]
+    ToolError(#[from] ToolRegistryError),
+}
+
+/// A provider of completion models, referred to by name in agent specs
+pub trait CompletionProvider {
+    type Model: CompletionModel;
+
+    /// Name of the provider in agent specs (e.g.: "openai")
+    fn name(&self) -> &str;
+
+    /// The completion model named `model`
+    fn completion_model(&self, model: &str) -> Self::Model;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentSpec {
+    /// Name of the agent
+    pub name: Option<String>,
+    /// Provider of the model, see [CompletionProvider::name]
+    pub provider: String,
+    /// Model name
+    pub model: String,
+    /// System prompt
+    pub preamble: Option<String>,
+    /// Files whose content is added as static context documents. Relative paths are resolved
+    /// from the directory of the spec file.
+    #[serde(default)]
+    pub context_files: Vec<PathBuf>,
+    /// Names of the tools of the agent in the tool registry
+    #[serde(default)]
+    pub tools: Vec<String>,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<u64>,
+    pub additional_params: Option<serde_json::Value>,
+    /// Directory relative context file paths are resolved from
+    #[serde(skip)]
+    pub base_dir: Option<PathBuf>,
+}
+
+impl AgentSpec {
+    pub fn from_toml(spec: &str) -> Result<Self, AgentSpecError> {
+        Ok(toml::from_str(spec)?)
+    }
+
+    pub fn from_yaml(spec: &str) -> Result<Self, AgentSpecError> {
+        Ok(serde_yaml::from_str(spec)?)
+    }
+
+    /// Load a spec from a `.toml`, `.yaml` or `.yml` file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, AgentSpecError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| AgentSpecError::IoError(path.to_path_buf(), e))?;
+
+        let mut spec = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&content)?,
+            Some("yaml" | "yml") => Self::from_yaml(&content)?,
+            _ => return Err(AgentSpecError::UnsupportedFormat(path.to_path_buf())),
+        };
+        spec.base_dir = path.parent().map(Path::to_path_buf);
+        Ok(spec)
+    }
+
+    /// Build the agent with the model of `provider` named in the spec
+    pub fn build<P: CompletionProvider>(
+        &self,
+        provider: &P,
+        registry: &ToolRegistry,
+    ) -> Result<Agent<P::Model>, AgentSpecError> {
+        if provider.name() != self.provider {
+            return Err(AgentSpecError::ProviderMismatch {
+                spec: self.provider.clone(),
+                provider: provider.name().to_string(),
+            });
+        }
+
+        Ok(self
+            .builder(provider.completion_model(&self.model), registry)?
+            .build())
+    }
+
+    /// Configure an agent builder for `model` from the spec, e.g.: to add settings that cannot
+    /// be expressed in the spec before building the agent. The spec's provider and model are
+    /// not checked against `model`.
+    pub fn builder<M: CompletionModel>(
+        &self,
+        model: M,
+        registry: &ToolRegistry,
+    ) -> Result<AgentBuilder<M>, AgentSpecError> {
+        let mut builder = AgentBuilder::new(model).tools(registry.toolset(&self.tools)?);
+
+        if let Some(name) = &self.name {
+            builder = builder.name(name);
+        }
+        if let Some(preamble) = &self.preamble {
+            builder = builder.preamble(preamble);
+        }
+        for path in &self.context_files {
+            let path = match &self.base_dir {
+                Some(base_dir) => base_dir.join(path),
+                None => path.clone(),
+            };
+            let doc =
+                std::fs::read_to_string(&path).map_err(|e| AgentSpecError::IoError(path, e))?;
+            builder = builder.context(&doc);
+        }
+        if let Some(temperature) = self.temperature {
+            builder = builder.temperature(temperature);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            builder = builder.max_tokens(max_tokens);
+        }
+        if let Some(params) = &self.additional_params {
+            builder = builder.additional_params(params.clone());
+        }
+
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        agent::tests::{Adder, ScriptedModel},
+        completion::{Completion, ModelChoice},
+    };
+
+    struct ScriptedProvider;
+
+    impl CompletionProvider for ScriptedProvider {
+        type Model = ScriptedModel;
+
+        fn name(&self) -> &str {
+            "scripted"
+        }
+
+        fn completion_model(&self, _model: &str) -> ScriptedModel {
+            ScriptedModel::new([ModelChoice::Message("Hi".into())])
+        }
+    }
+
+    fn registry() -> ToolRegistry {
+        let registry = ToolRegistry::default();
+        registry.register(Adder).unwrap();
+        registry
+    }
+
+    #[test]
+    fn test_toml_and_yaml_are_equivalent() {
+        let toml = AgentSpec::from_toml(
+            r#"
+            provider = "scripted"
+            model = "small"
+            preamble = "Be nice"
+            tools = ["add"]
+            temperature = 0.5
+
+            [additional_params]
+            top_p = 0.9
+            "#,
+        )
+        .unwrap();
+        let yaml = AgentSpec::from_yaml(
+            "
+            provider: scripted
+            model: small
+            preamble: Be nice
+            tools: [add]
+            temperature: 0.5
+            additional_params:
+              top_p: 0.9
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(toml, yaml);
+        assert_eq!(toml.additional_params, Some(json!({"top_p": 0.9})));
+    }
+
+    #[tokio::test]
+    async fn test_build_agent_from_file() {
+        let directory = std::env::temp_dir().join(format!("agent_spec_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("glossary.md"), "SOL: Solana").unwrap();
+        std::fs::write(
+            directory.join("agent.yaml"),
+            "
+            provider: scripted
+            model: small
+            preamble: Be nice
+            context_files: [glossary.md]
+            tools: [add]
+            max_tokens: 100
+            ",
+        )
+        .unwrap();
+
+        let spec = AgentSpec::from_file(directory.join("agent.yaml")).unwrap();
+        let agent = spec.build(&ScriptedProvider, &registry()).unwrap();
+        let request = agent.completion("Hello", vec![]).await.unwrap().build();
+
+        assert_eq!(request.preamble.as_deref(), Some("Be nice"));
+        assert_eq!(request.documents[0].text, "SOL: Solana");
+        assert_eq!(request.tools[0].name, "add");
+        assert_eq!(request.max_tokens, Some(100));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_specs() {
+        let spec = AgentSpec {
+            provider: "other".into(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            spec.build(&ScriptedProvider, &registry()),
+            Err(AgentSpecError::ProviderMismatch { .. })
+        ));
+
+        let spec = AgentSpec {
+            provider: "scripted".into(),
+            tools: vec!["subtract".into()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            spec.build(&ScriptedProvider, &registry()),
+            Err(AgentSpecError::ToolError(ToolRegistryError::ToolNotFound(
+                _
+            )))
+        ));
+
+        assert!(AgentSpec::from_toml("provider = \"x\"\nmodel = \"y\"\ntemprature = 1").is_err());
+    }
+}
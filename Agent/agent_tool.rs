@@ -0,0 +1,259 @@
+//! Agents as tools: a supervisor agent delegates to specialist agents by calling them
+//! through its [ToolSet](crate::tool::ToolSet) like any other tool.
+//!
+//! Delegation depth is tracked across nested calls: an [AgentTool] refuses to run when it
+//! is already [AgentTool::max_depth] delegations deep, so agents delegating to each other
+//! cannot loop forever.
+//!
+//! # Example
+//! ```
+//! use Hydranta::{agent::AgentBuilder, agent_tool::AgentTool, completion::Prompt};
+//!
+//! let translator = AgentBuilder::new(model.clone())
+//!     .preamble("You translate any text into English.")
+//!     .build();
+//!
+//! let supervisor = AgentBuilder::new(model)
+//!     .preamble("Translate the user's text to English if needed, then answer it.")
+//!     .tool(AgentTool::new(translator, "translator", "Translate a text into English"))
+//!     .max_turns(3)
+//!     .build();
+//!
+//! let answer = supervisor.prompt("Quel est le prix du SOL ?").await?;
+//! ```
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use serde::Deserialize;
+use serde_json::json;
+use tokio::task::JoinHandle;
+
+use crate::{
+    agent::Agent,
+    completion::{CompletionModel, Prompt, PromptError, ToolDefinition},
+    tool::Tool,
+    usage,
+};
+
+tokio::task_local! {
+    /// Number of agent delegations the current task is nested in
+    static DELEGATION_DEPTH: usize;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AgentToolError {
+    #[error("PromptError: {0}")]
+    PromptError(#[from] PromptError),
+
+    /// The agent was called at a delegation depth above the tool's limit
+    #[error("DelegationDepthExceeded: agent {0} cannot be called more than {1} delegations deep")]
+    DelegationDepthExceeded(String, usize),
+
+    /// The task running the agent panicked or was cancelled
+    #[error("JoinError: {0}")]
+    JoinError(#[from] tokio::task::JoinError),
+}
+
+#[derive(Deserialize)]
+pub struct AgentToolArgs {
+    /// Task or question for the agent
+    pub prompt: String,
+}
+
+/// A [Tool] prompting an agent with the `prompt` argument and returning its answer
+pub struct AgentTool<M: CompletionModel> {
+    agent: Arc<Agent<M>>,
+    name: String,
+    description: String,
+    max_depth: usize,
+}
+
+impl<M: CompletionModel + 'static> AgentTool<M> {
+    /// Expose `agent` as the tool `name`. The description tells the calling agent when to
+    /// delegate to it.
+    pub fn new(agent: Agent<M>, name: &str, description: &str) -> Self {
+        Self {
+            agent: Arc::new(agent),
+            name: name.to_string(),
+            description: description.to_string(),
+            max_depth: 3,
+        }
+    }
+
+    /// Set the maximum delegation depth at which the agent can be called. A depth of 1 means
+    /// only a top-level agent may call it. Defaults to 3.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+}
+
+impl<M: CompletionModel + 'static> Tool for AgentTool<M> {
+    const NAME: &'static str = "agent";
+
+    type Error = AgentToolError;
+    type Args = AgentToolArgs;
+    type Output = String;
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        ToolDefinition {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "prompt": {
+                        "type": "string",
+                        "description": "Task or question for the agent, with all the context it needs"
+                    }
+                },
+                "required": ["prompt"]
+            }),
+        }
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<String, AgentToolError> {
+        let depth = DELEGATION_DEPTH.try_with(|depth| *depth).unwrap_or(0) + 1;
+        if depth > self.max_depth {
+            return Err(AgentToolError::DelegationDepthExceeded(
+                self.name.clone(),
+                self.max_depth,
+            ));
+        }
+
+        tracing::debug!(target: "rig",
+            "Delegating to agent {} (depth {depth}): {}",
+            self.name,
+            args.prompt
+        );
+
+        // Tool futures must be Sync, which the agent's future is not: run it in its own task,
+        // carrying the delegation depth and pipeline run over. The task is aborted if the call
+        // is dropped (e.g. on timeout), so the agent does not keep running detached.
+        let agent = self.agent.clone();
+        let task = AbortOnDrop(tokio::spawn(DELEGATION_DEPTH.scope(
+            depth,
+            usage::in_current_pipeline_run(async move { agent.prompt(&args.prompt).await }),
+        )));
+        Ok(task.await??)
+    }
+}
+
+/// Handle of a spawned task, aborting the task when dropped
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Future for AbortOnDrop<T> {
+    type Output = Result<T, tokio::task::JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::{tests::ScriptedModel, AgentBuilder},
+        completion::ModelChoice,
+        tool::{ToolError, ToolSetError},
+        usage::UsageTracker,
+    };
+
+    fn delegate(toolname: &str, prompt: &str) -> ModelChoice {
+        ModelChoice::ToolCall(toolname.into(), json!({ "prompt": prompt }))
+    }
+
+    #[tokio::test]
+    async fn test_supervisor_delegates_to_specialist() {
+        let specialist = ScriptedModel::new([ModelChoice::Message("Hello".into())]);
+        let supervisor = ScriptedModel::new([
+            delegate("translator", "Translate 'Bonjour'"),
+            ModelChoice::Message("It means Hello".into()),
+        ]);
+
+        let agent = AgentBuilder::new(supervisor.clone())
+            .tool(AgentTool::new(
+                AgentBuilder::new(specialist.clone()).build(),
+                "translator",
+                "Translate a text into English",
+            ))
+            .max_turns(2)
+            .build();
+
+        assert_eq!(agent.prompt("Bonjour?").await.unwrap(), "It means Hello");
+        assert_eq!(
+            specialist.requests.lock().unwrap()[0].prompt,
+            "Translate 'Bonjour'"
+        );
+        assert!(supervisor.requests.lock().unwrap()[1]
+            .chat_history
+            .last()
+            .unwrap()
+            .content
+            .contains("\"Hello\""));
+    }
+
+    #[tokio::test]
+    async fn test_delegated_usage_is_attributed_to_pipeline_run() {
+        let tracker = UsageTracker::default();
+        let specialist =
+            AgentBuilder::new(ScriptedModel::new([ModelChoice::Message("Hello".into())]))
+                .usage_tracker(tracker.clone(), "small")
+                .build();
+        let agent = AgentBuilder::new(ScriptedModel::new([
+            delegate("translator", "Translate 'Bonjour'"),
+            ModelChoice::Message("It means Hello".into()),
+        ]))
+        .tool(AgentTool::new(specialist, "translator", "Translate a text"))
+        .usage_tracker(tracker.clone(), "small")
+        .max_turns(2)
+        .build();
+
+        tracker
+            .pipeline_run("run-1", agent.prompt("Bonjour?"))
+            .await
+            .unwrap();
+
+        assert_eq!(tracker.report().pipeline_runs["run-1"].requests, 3);
+    }
+
+    #[tokio::test]
+    async fn test_delegation_depth_is_limited() {
+        // Each level delegates to the next one, the innermost agent is never reached
+        let level = |inner: Option<AgentTool<ScriptedModel>>| {
+            let mut builder = AgentBuilder::new(ScriptedModel::new([
+                delegate("next", "Go deeper"),
+                ModelChoice::Message("Done".into()),
+            ]));
+            if let Some(inner) = inner {
+                builder = builder.tool(inner);
+            }
+            AgentTool::new(builder.max_turns(2).build(), "next", "Next level").max_depth(2)
+        };
+        let agent = AgentBuilder::new(ScriptedModel::new([delegate("next", "Go")]))
+            .tool(level(Some(level(Some(level(None))))))
+            .build();
+
+        let error = agent.prompt("Start").await.unwrap_err();
+        assert!(matches!(
+            error,
+            PromptError::ToolError(ToolSetError::ToolCallError(ToolError::ToolCallError(_)))
+        ));
+        assert!(error.to_string().contains("DelegationDepthExceeded"));
+    }
+}
@@ -0,0 +1,32 @@
+//! Human-in-the-loop approval of sensitive tool calls.
+//!
+//! Tools marked as requiring approval in a [ToolSet](crate::tool::ToolSet) (see
+//! [ToolSet::require_approval](crate::tool::ToolSet::require_approval)) are only executed once
+//! the toolset's [ApprovalHandler] has approved the call. The handler can also reject the call,
+//! in which case the rejection is returned to the model as the tool result, or edit its arguments.
+use std::{future::Future, pin::Pin};
+
+/// Decision of an [ApprovalHandler] on a tool call
+#[derive(Debug, Clone, PartialEq)]
+pub enum Approval {
+    /// Execute the call as requested by the model
+    Approve,
+    /// Do not execute the call. The reason is sent back to the model.
+    Reject(String),
+    /// Execute the call with the given JSON encoded arguments instead
+    Edit(String),
+}
+
+pub trait ApprovalHandler: Send + Sync {
+    /// Decide whether the call of `toolname` with the JSON encoded `args` may proceed
+    fn approve<'a>(
+        &'a self,
+        toolname: &'a str,
+        args: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Approval> + Send + 'a>>;
+}
+
+/// Message returned to the model as the result of a rejected tool call
+pub(crate) fn rejection_message(toolname: &str, reason: &str) -> String {
+    format!("The call to tool `{toolname}` was rejected by the user: {reason}")
+}
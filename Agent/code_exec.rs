@@ -0,0 +1,338 @@
+//! Tool running code written by the model in a sandboxed [Rhai](https://rhai.rs) engine.
+//! Requires the `rhai` feature.
+//!
+//! The engine has no access to the filesystem, the network or the environment: `import`
+//! statements are rejected and `print`/`debug` write to a buffer returned to the model
+//! instead of the process' stdout. Each run is limited in time, in number of operations, in
+//! memory and in output size. Errors of the code (including exceeded limits) are returned to
+//! the model in the tool output so it can fix its code.
+//!
+//! Memory is bounded per value, by the maximum size of strings and of arrays and maps
+//! (nested values included), and in total by the maximum number of variables alive at once
+//! and the depth of calls: a run uses at most about `max_variables` values of the maximum
+//! size.
+//!
+//! # Example
+//! ```rust
+//! use std::time::Duration;
+//!
+//! use Hydranta::{code_exec::CodeExecTool, providers::openai};
+//!
+//! let openai = openai::Client::from_env();
+//!
+//! let analyst = openai
+//!     .agent("gpt-4o")
+//!     .preamble("You are a data analyst. Use the code tool for any computation.")
+//!     .tool(CodeExecTool::default().timeout(Duration::from_secs(2)))
+//!     .max_turns(5)
+//!     .build();
+//! ```
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rhai::{module_resolvers::DummyModuleResolver, Dynamic, Engine, EvalAltResult};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{completion::ToolDefinition, tool::Tool};
+
+#[derive(Debug, thiserror::Error)]
+pub enum CodeExecError {
+    /// The thread running the code panicked or was cancelled
+    #[error("TaskError: {0}")]
+    TaskError(#[from] tokio::task::JoinError),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CodeExecArgs {
+    pub code: String,
+}
+
+/// Output of a run, returned to the model
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CodeOutput {
+    /// Text printed by the code with `print` and `debug`
+    pub stdout: String,
+    /// Whether `stdout` was cut at the output limit
+    pub truncated: bool,
+    /// Value of the last expression of the code, as JSON (`null` if the code failed)
+    pub result: Value,
+    /// Why the code failed to compile or run, if it did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Limits of a single run
+#[derive(Debug, Clone)]
+struct Limits {
+    timeout: Duration,
+    max_operations: u64,
+    max_string_size: usize,
+    max_collection_size: usize,
+    max_call_levels: usize,
+    max_variables: usize,
+    max_output: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            max_operations: 50_000_000,
+            max_string_size: 1 << 20,
+            max_collection_size: 100_000,
+            max_call_levels: 64,
+            max_variables: 256,
+            max_output: 16 * 1024,
+        }
+    }
+}
+
+/// Tool running Rhai code in a sandbox (see the [module documentation](self))
+#[derive(Debug, Clone, Default)]
+pub struct CodeExecTool {
+    limits: Limits,
+}
+
+impl CodeExecTool {
+    /// Maximum wall-clock time of a run, spent computing on a blocking thread (default: 5s)
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.limits.timeout = timeout;
+        self
+    }
+
+    /// Maximum number of operations executed by a run (default: 50 000 000)
+    pub fn max_operations(mut self, operations: u64) -> Self {
+        self.limits.max_operations = operations;
+        self
+    }
+
+    /// Maximum size in bytes of a single string built by the code (default: 1 MiB). This is
+    /// a per-value limit, see [CodeExecTool::max_variables] for the total.
+    pub fn max_string_size(mut self, bytes: usize) -> Self {
+        self.limits.max_string_size = bytes;
+        self
+    }
+
+    /// Maximum number of elements of a single array or map built by the code, nested values
+    /// included (default: 100 000). This is a per-value limit, see
+    /// [CodeExecTool::max_variables] for the total.
+    pub fn max_collection_size(mut self, size: usize) -> Self {
+        self.limits.max_collection_size = size;
+        self
+    }
+
+    /// Maximum depth of nested function calls (default: 64)
+    pub fn max_call_levels(mut self, levels: usize) -> Self {
+        self.limits.max_call_levels = levels;
+        self
+    }
+
+    /// Maximum number of variables alive at once, which bounds the total memory of a run
+    /// together with the per-value limits (default: 256)
+    pub fn max_variables(mut self, variables: usize) -> Self {
+        self.limits.max_variables = variables.max(1);
+        self
+    }
+
+    /// Maximum size in bytes of the printed output, beyond which it is truncated
+    /// (default: 16 KiB)
+    pub fn max_output(mut self, bytes: usize) -> Self {
+        self.limits.max_output = bytes;
+        self
+    }
+
+    /// Run `code` on the current thread
+    fn run(limits: &Limits, code: &str) -> CodeOutput {
+        let output = Arc::new(Mutex::new(CodeOutput::default()));
+        let engine = sandbox(limits, output.clone());
+
+        let result = engine.eval::<Dynamic>(code);
+        let mut output = std::mem::take(&mut *output.lock().unwrap());
+        match result {
+            Ok(value) => {
+                output.result = rhai::serde::from_dynamic(&value)
+                    .unwrap_or_else(|_| Value::String(value.to_string()));
+            }
+            Err(error) => output.error = Some(error_message(limits, &error)),
+        }
+        output
+    }
+}
+
+impl Tool for CodeExecTool {
+    const NAME: &'static str = "run_code";
+    type Error = CodeExecError;
+    type Args = CodeExecArgs;
+    type Output = CodeOutput;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        ToolDefinition {
+            name: Self::NAME.to_string(),
+            description: format!(
+                "Run a script written in the Rhai language and return its printed output \
+                and the value of its last expression. Scripts cannot access files or the \
+                network and are stopped after {:?}.",
+                self.limits.timeout
+            ),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "code": {
+                        "type": "string",
+                        "description": "Rhai source code. Use `print` to output text; the \
+                            value of the last expression is returned as the result."
+                    }
+                },
+                "required": ["code"]
+            }),
+        }
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+        let limits = self.limits.clone();
+        Ok(tokio::task::spawn_blocking(move || Self::run(&limits, &args.code)).await?)
+    }
+}
+
+/// Engine with the limits of `limits`, writing printed text to `output`
+fn sandbox(limits: &Limits, output: Arc<Mutex<CodeOutput>>) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .set_max_operations(limits.max_operations)
+        .set_max_string_size(limits.max_string_size)
+        .set_max_array_size(limits.max_collection_size)
+        .set_max_map_size(limits.max_collection_size)
+        .set_max_call_levels(limits.max_call_levels)
+        .set_max_variables(limits.max_variables);
+
+    let start = Instant::now();
+    let timeout = limits.timeout;
+    engine.on_progress(move |_| (start.elapsed() > timeout).then_some(Dynamic::UNIT));
+
+    let max_output = limits.max_output;
+    let print_output = output.clone();
+    engine.on_print(move |text| write_output(&print_output, max_output, text));
+    engine.on_debug(move |text, _, _| write_output(&output, max_output, text));
+    engine
+}
+
+/// Append a line to the output, truncating it at `max_output` bytes
+fn write_output(output: &Mutex<CodeOutput>, max_output: usize, text: &str) {
+    let mut output = output.lock().unwrap();
+    if output.truncated {
+        return;
+    }
+
+    let line = format!("{text}\n");
+    let remaining = max_output.saturating_sub(output.stdout.len());
+    if line.len() <= remaining {
+        output.stdout.push_str(&line);
+    } else {
+        let mut end = remaining;
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        output.stdout.push_str(&line[..end]);
+        output.truncated = true;
+    }
+}
+
+fn error_message(limits: &Limits, error: &EvalAltResult) -> String {
+    match error {
+        EvalAltResult::ErrorTerminated(..) => {
+            format!("Script exceeded the time limit of {:?}", limits.timeout)
+        }
+        EvalAltResult::ErrorTooManyOperations(..) => format!(
+            "Script exceeded the limit of {} operations",
+            limits.max_operations
+        ),
+        error => error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tool::ToolSet;
+
+    async fn run(tool: &CodeExecTool, code: &str) -> CodeOutput {
+        tool.call(CodeExecArgs {
+            code: code.to_string(),
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_stdout_and_result() {
+        let output = run(
+            &CodeExecTool::default(),
+            r#"
+                let prices = [1.5, 2.5, 3.0];
+                let total = 0.0;
+                for price in prices { total += price; }
+                print(`total: ${total}`);
+                #{ total: total, count: prices.len() }
+            "#,
+        )
+        .await;
+
+        assert_eq!(output.stdout, "total: 7.0\n");
+        assert_eq!(output.result, json!({ "total": 7.0, "count": 3 }));
+        assert_eq!(output.error, None);
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let tool = CodeExecTool::default()
+            .timeout(Duration::from_millis(100))
+            .max_collection_size(10)
+            .max_output(8);
+
+        let output = run(&tool, "loop {}").await;
+        assert!(output.error.unwrap().contains("time limit"));
+
+        let output = run(&tool, "let a = []; for i in 0..100 { a.push(i); }").await;
+        assert!(output.error.unwrap().contains("too large"));
+
+        let output = run(
+            &CodeExecTool::default().max_variables(2),
+            "let a = 1; let b = 2; let c = 3; a + b + c",
+        )
+        .await;
+        assert!(output.error.unwrap().contains("variables"));
+
+        let output = run(&tool, r#"print("hello"); print("world"); 1"#).await;
+        assert_eq!(output.stdout, "hello\nwo");
+        assert!(output.truncated);
+        assert_eq!(output.result, json!(1));
+    }
+
+    #[tokio::test]
+    async fn test_no_imports() {
+        let output = run(&CodeExecTool::default(), r#"import "/etc/passwd" as p; 1"#).await;
+        assert!(output.error.is_some());
+        assert_eq!(output.result, Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_toolset_call() {
+        let toolset = ToolSet::from_tools(vec![CodeExecTool::default()]);
+        let output = toolset
+            .call("run_code", json!({ "code": "40 + 2" }).to_string())
+            .await
+            .unwrap();
+        let output: CodeOutput = serde_json::from_str(&output).unwrap();
+        assert_eq!(output.result, json!(42));
+
+        let output = toolset
+            .call("run_code", json!({ "code": "let x = ;" }).to_string())
+            .await
+            .unwrap();
+        assert!(output.contains("\"error\""));
+    }
+}
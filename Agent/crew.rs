@@ -0,0 +1,649 @@
+//! Multi-agent orchestration: a [Crew] of named agents working on a task over several hops.
+//!
+//! At each hop, the crew's [Router] picks the next agent (or ends the run), the agent is
+//! prompted with a prompt built from the shared [Blackboard], and its answer is recorded in
+//! the blackboard and folded into the typed crew state. The run ends when the router returns
+//! [Route::Finish], a [Termination] condition holds, or the maximum number of hops is reached.
+//!
+//! Every hop is recorded as a [Hop] in the blackboard and traced in a `crew_hop` span.
+//!
+//! # Example
+//! ```
+//! use Hydranta::crew::{Blackboard, Crew, CrewMember, Route, RuleRouter, Termination};
+//!
+//! #[derive(Default, Clone, serde::Serialize)]
+//! struct Report {
+//!     facts: Vec<String>,
+//!     draft: Option<String>,
+//! }
+//!
+//! let crew = Crew::builder()
+//!     .member(
+//!         CrewMember::new("researcher", "Collects facts about the task", researcher)
+//!             .on_response(|state: &mut Report, response| state.facts.push(response.into())),
+//!     )
+//!     .member(
+//!         CrewMember::new("writer", "Writes the final report from the facts", writer)
+//!             .prompt(|board: &Blackboard<Report>| format!("Write a report from: {:?}", board.state.facts))
+//!             .on_response(|state: &mut Report, response| state.draft = Some(response.into())),
+//!     )
+//!     .router(
+//!         RuleRouter::default()
+//!             .when(|board: &Blackboard<Report>| board.state.facts.len() < 2, "researcher")
+//!             .otherwise(Route::agent("writer")),
+//!     )
+//!     .terminate_when(Termination::AfterAgent("writer".into()))
+//!     .build()?;
+//!
+//! let outcome = crew.run("Weekly SOL market report", Report::default()).await?;
+//! for hop in &outcome.board.hops {
+//!     println!("{} -> {}", hop.agent, hop.response);
+//! }
+//! ```
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+use serde::Serialize;
+use tracing::Instrument;
+
+use crate::{
+    completion::{Chat, Message, Prompt, PromptError},
+    usage::serialize_millis,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum CrewError {
+    #[error("PromptError: {0}")]
+    PromptError(#[from] PromptError),
+
+    /// The router picked an agent which is not a member of the crew
+    #[error("UnknownAgent: {0}")]
+    UnknownAgent(String),
+
+    #[error("RouterError: {0}")]
+    RouterError(String),
+
+    /// Several members of the crew were given the same name
+    #[error("DuplicateMember: {0}")]
+    DuplicateMember(String),
+}
+
+/// Object-safe chat interface of the crew members
+pub trait CrewAgent: Send + Sync {
+    fn chat<'a>(
+        &'a self,
+        prompt: &'a str,
+        chat_history: Vec<Message>,
+    ) -> BoxFuture<'a, Result<String, PromptError>>;
+}
+
+impl<T: Chat> CrewAgent for T {
+    fn chat<'a>(
+        &'a self,
+        prompt: &'a str,
+        chat_history: Vec<Message>,
+    ) -> BoxFuture<'a, Result<String, PromptError>> {
+        Box::pin(Chat::chat(self, prompt, chat_history))
+    }
+}
+
+/// One step of a crew run
+#[derive(Debug, Clone, Serialize)]
+pub struct Hop {
+    /// Position of the hop in the run, starting at 1
+    pub index: usize,
+    pub agent: String,
+    pub prompt: String,
+    pub response: String,
+    #[serde(rename = "duration_ms", serialize_with = "serialize_millis")]
+    pub duration: Duration,
+}
+
+/// State shared by the members of a crew during a run
+#[derive(Debug, Clone, Serialize)]
+pub struct Blackboard<S> {
+    pub task: String,
+    /// Typed state updated by the members' [CrewMember::on_response] hooks
+    pub state: S,
+    pub hops: Vec<Hop>,
+}
+
+impl<S> Blackboard<S> {
+    pub fn last_hop(&self) -> Option<&Hop> {
+        self.hops.last()
+    }
+
+    /// The task followed by the responses of all previous hops
+    pub fn transcript(&self) -> String {
+        let mut transcript = format!("Task: {}", self.task);
+        if !self.hops.is_empty() {
+            transcript.push_str("\n\nWork so far:");
+            for hop in &self.hops {
+                transcript.push_str(&format!("\n[{}]: {}", hop.agent, hop.response));
+            }
+        }
+        transcript
+    }
+}
+
+type PromptFn<S> = Box<dyn Fn(&Blackboard<S>) -> String + Send + Sync>;
+type UpdateFn<S> = Box<dyn Fn(&mut S, &str) + Send + Sync>;
+type Predicate<S> = Box<dyn Fn(&Blackboard<S>) -> bool + Send + Sync>;
+
+/// A named agent of a crew
+pub struct CrewMember<S> {
+    name: String,
+    description: String,
+    agent: Box<dyn CrewAgent>,
+    prompt: PromptFn<S>,
+    on_response: Option<UpdateFn<S>>,
+}
+
+impl<S: 'static> CrewMember<S> {
+    /// The description is given to LLM routers to pick the agent
+    pub fn new(name: &str, description: &str, agent: impl CrewAgent + 'static) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            agent: Box::new(agent),
+            prompt: Box::new(Blackboard::transcript),
+            on_response: None,
+        }
+    }
+
+    /// Build the agent's prompt from the blackboard. Defaults to [Blackboard::transcript].
+    pub fn prompt(
+        mut self,
+        prompt: impl Fn(&Blackboard<S>) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.prompt = Box::new(prompt);
+        self
+    }
+
+    /// Update the crew state with the agent's response
+    pub fn on_response(mut self, update: impl Fn(&mut S, &str) + Send + Sync + 'static) -> Self {
+        self.on_response = Some(Box::new(update));
+        self
+    }
+}
+
+/// Name and description of a crew member, as seen by routers
+#[derive(Debug, Clone)]
+pub struct MemberInfo {
+    pub name: String,
+    pub description: String,
+}
+
+/// Decision of a [Router]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Route {
+    Agent(String),
+    Finish,
+}
+
+impl Route {
+    pub fn agent(name: &str) -> Self {
+        Route::Agent(name.to_string())
+    }
+}
+
+/// Picks the next agent of a crew run
+pub trait Router<S>: Send + Sync {
+    fn route<'a>(
+        &'a self,
+        board: &'a Blackboard<S>,
+        members: &'a [MemberInfo],
+    ) -> BoxFuture<'a, Result<Route, CrewError>>;
+}
+
+/// Router applying the first rule whose condition holds
+pub struct RuleRouter<S> {
+    rules: Vec<(Predicate<S>, Route)>,
+    otherwise: Route,
+}
+
+impl<S> Default for RuleRouter<S> {
+    fn default() -> Self {
+        Self {
+            rules: vec![],
+            otherwise: Route::Finish,
+        }
+    }
+}
+
+impl<S> RuleRouter<S> {
+    /// Route to `agent` when `condition` holds
+    pub fn when(
+        mut self,
+        condition: impl Fn(&Blackboard<S>) -> bool + Send + Sync + 'static,
+        agent: &str,
+    ) -> Self {
+        self.rules.push((Box::new(condition), Route::agent(agent)));
+        self
+    }
+
+    /// Route to use when no rule applies. Defaults to [Route::Finish].
+    pub fn otherwise(mut self, route: Route) -> Self {
+        self.otherwise = route;
+        self
+    }
+
+    /// Router calling the agents once each, in order, then finishing
+    pub fn sequence<I, T>(agents: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        agents
+            .into_iter()
+            .enumerate()
+            .fold(Self::default(), |router, (index, agent)| {
+                router.when(move |board| board.hops.len() == index, agent.as_ref())
+            })
+    }
+}
+
+impl<S: Send + Sync> Router<S> for RuleRouter<S> {
+    fn route<'a>(
+        &'a self,
+        board: &'a Blackboard<S>,
+        _members: &'a [MemberInfo],
+    ) -> BoxFuture<'a, Result<Route, CrewError>> {
+        let route = self
+            .rules
+            .iter()
+            .find(|(condition, _)| condition(board))
+            .map_or(&self.otherwise, |(_, route)| route);
+        Box::pin(futures::future::ready(Ok(route.clone())))
+    }
+}
+
+/// Router asking a model which agent should act next, given the members' descriptions,
+/// the task, the crew state and the work done so far. The model must answer with the name
+/// of an agent, or `FINISH`.
+pub struct LlmRouter<P: Prompt> {
+    model: P,
+}
+
+impl<P: Prompt> LlmRouter<P> {
+    pub fn new(model: P) -> Self {
+        Self { model }
+    }
+}
+
+impl<S: Serialize + Send + Sync, P: Prompt> Router<S> for LlmRouter<P> {
+    fn route<'a>(
+        &'a self,
+        board: &'a Blackboard<S>,
+        members: &'a [MemberInfo],
+    ) -> BoxFuture<'a, Result<Route, CrewError>> {
+        Box::pin(async move {
+            let agents = members
+                .iter()
+                .map(|member| format!("- {}: {}", member.name, member.description))
+                .collect::<Vec<_>>()
+                .join("\n");
+            let state = serde_json::to_string_pretty(&board.state)
+                .map_err(|e| CrewError::RouterError(e.to_string()))?;
+            let prompt = format!(
+                "You coordinate a team of agents. Pick the agent that should act next.\n\n\
+                 Agents:\n{agents}\n\n{}\n\nCurrent state:\n{state}\n\n\
+                 Answer with the name of the next agent only, or FINISH if the task is complete.",
+                board.transcript()
+            );
+
+            let answer = self.model.prompt(&prompt).await?;
+            let answer = answer
+                .trim()
+                .trim_matches(|c: char| !c.is_alphanumeric() && c != '_' && c != '-');
+
+            if answer.eq_ignore_ascii_case("finish") {
+                return Ok(Route::Finish);
+            }
+            members
+                .iter()
+                .find(|member| member.name.eq_ignore_ascii_case(answer))
+                .map(|member| Route::Agent(member.name.clone()))
+                .ok_or_else(|| CrewError::UnknownAgent(answer.to_string()))
+        })
+    }
+}
+
+/// Condition ending a crew run after a hop
+pub enum Termination<S> {
+    /// End the run once the given agent has responded
+    AfterAgent(String),
+    /// End the run when the predicate holds
+    When(Predicate<S>),
+}
+
+impl<S> Termination<S> {
+    fn holds(&self, board: &Blackboard<S>) -> bool {
+        match self {
+            Termination::AfterAgent(name) => board.last_hop().is_some_and(|hop| hop.agent == *name),
+            Termination::When(predicate) => predicate(board),
+        }
+    }
+}
+
+/// Why a crew run ended
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    /// The router returned [Route::Finish]
+    Finished,
+    /// A termination condition held
+    Terminated,
+    /// The maximum number of hops was reached
+    MaxHops,
+}
+
+/// Result of a crew run
+#[derive(Debug)]
+pub struct CrewOutcome<S> {
+    pub board: Blackboard<S>,
+    pub reason: StopReason,
+}
+
+impl<S> CrewOutcome<S> {
+    /// Response of the last hop, if any
+    pub fn answer(&self) -> Option<&str> {
+        self.board.last_hop().map(|hop| hop.response.as_str())
+    }
+}
+
+pub struct Crew<S> {
+    members: HashMap<String, CrewMember<S>>,
+    infos: Vec<MemberInfo>,
+    router: Box<dyn Router<S>>,
+    terminations: Vec<Termination<S>>,
+    max_hops: usize,
+}
+
+impl<S: Send + Sync> Crew<S> {
+    pub fn builder() -> CrewBuilder<S> {
+        CrewBuilder::default()
+    }
+
+    /// Run the crew on `task`, starting from `state`
+    pub async fn run(&self, task: &str, state: S) -> Result<CrewOutcome<S>, CrewError> {
+        let mut board = Blackboard {
+            task: task.to_string(),
+            state,
+            hops: vec![],
+        };
+
+        while board.hops.len() < self.max_hops {
+            let name = match self.router.route(&board, &self.infos).await? {
+                Route::Finish => {
+                    return Ok(CrewOutcome {
+                        board,
+                        reason: StopReason::Finished,
+                    })
+                }
+                Route::Agent(name) => name,
+            };
+            let member = self
+                .members
+                .get(&name)
+                .ok_or_else(|| CrewError::UnknownAgent(name.clone()))?;
+
+            let index = board.hops.len() + 1;
+            let prompt = (member.prompt)(&board);
+            let start = Instant::now();
+            let response = member
+                .agent
+                .chat(&prompt, vec![])
+                .instrument(
+                    tracing::info_span!(target: "rig", "crew_hop", hop = index, agent = %name),
+                )
+                .await?;
+            let duration = start.elapsed();
+
+            tracing::info!(target: "rig",
+                "Crew hop {index}: agent {name} responded in {duration:?}"
+            );
+            tracing::debug!(target: "rig", "Crew hop {index} prompt:\n{prompt}\nresponse:\n{response}");
+
+            if let Some(update) = &member.on_response {
+                update(&mut board.state, &response);
+            }
+            board.hops.push(Hop {
+                index,
+                agent: name,
+                prompt,
+                response,
+                duration,
+            });
+
+            if self
+                .terminations
+                .iter()
+                .any(|termination| termination.holds(&board))
+            {
+                return Ok(CrewOutcome {
+                    board,
+                    reason: StopReason::Terminated,
+                });
+            }
+        }
+
+        tracing::warn!(target: "rig", "Crew stopped after {} hops", self.max_hops);
+        Ok(CrewOutcome {
+            board,
+            reason: StopReason::MaxHops,
+        })
+    }
+}
+
+pub struct CrewBuilder<S> {
+    members: Vec<CrewMember<S>>,
+    router: Option<Box<dyn Router<S>>>,
+    terminations: Vec<Termination<S>>,
+    max_hops: usize,
+}
+
+impl<S> Default for CrewBuilder<S> {
+    fn default() -> Self {
+        Self {
+            members: vec![],
+            router: None,
+            terminations: vec![],
+            max_hops: 10,
+        }
+    }
+}
+
+impl<S: Send + Sync + 'static> CrewBuilder<S> {
+    pub fn member(mut self, member: CrewMember<S>) -> Self {
+        self.members.push(member);
+        self
+    }
+
+    /// Set the router picking the next agent. Defaults to calling the members once each,
+    /// in the order they were added.
+    pub fn router(mut self, router: impl Router<S> + 'static) -> Self {
+        self.router = Some(Box::new(router));
+        self
+    }
+
+    pub fn terminate_when(mut self, termination: Termination<S>) -> Self {
+        self.terminations.push(termination);
+        self
+    }
+
+    /// Set the maximum number of hops of a run. Defaults to 10.
+    pub fn max_hops(mut self, max_hops: usize) -> Self {
+        self.max_hops = max_hops;
+        self
+    }
+
+    /// Build the crew, failing with [CrewError::DuplicateMember] if two members share a name
+    pub fn build(self) -> Result<Crew<S>, CrewError> {
+        let mut names = HashSet::new();
+        if let Some(member) = self
+            .members
+            .iter()
+            .find(|member| !names.insert(member.name.as_str()))
+        {
+            return Err(CrewError::DuplicateMember(member.name.clone()));
+        }
+
+        let infos = self
+            .members
+            .iter()
+            .map(|member| MemberInfo {
+                name: member.name.clone(),
+                description: member.description.clone(),
+            })
+            .collect::<Vec<_>>();
+        let router = self
+            .router
+            .unwrap_or_else(|| Box::new(RuleRouter::sequence(infos.iter().map(|info| &info.name))));
+
+        Ok(Crew {
+            members: self
+                .members
+                .into_iter()
+                .map(|member| (member.name.clone(), member))
+                .collect(),
+            infos,
+            router,
+            terminations: self.terminations,
+            max_hops: self.max_hops,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::{tests::ScriptedModel, Agent, AgentBuilder},
+        completion::ModelChoice,
+    };
+
+    fn agent(responses: &[&str]) -> (ScriptedModel, Agent<ScriptedModel>) {
+        let model = ScriptedModel::new(
+            responses
+                .iter()
+                .map(|response| ModelChoice::Message(response.to_string())),
+        );
+        (model.clone(), AgentBuilder::new(model).build())
+    }
+
+    #[derive(Debug, Default, Serialize)]
+    struct Notes {
+        facts: Vec<String>,
+        report: Option<String>,
+    }
+
+    #[tokio::test]
+    async fn test_rule_router_and_blackboard() {
+        let (_, researcher) = agent(&["SOL is up", "Volume is high"]);
+        let (writer_model, writer) = agent(&["Report: SOL is up on high volume"]);
+
+        let crew = Crew::builder()
+            .member(
+                CrewMember::new("researcher", "Finds facts", researcher)
+                    .on_response(|notes: &mut Notes, response| notes.facts.push(response.into())),
+            )
+            .member(
+                CrewMember::new("writer", "Writes reports", writer)
+                    .prompt(|board: &Blackboard<Notes>| board.state.facts.join("; "))
+                    .on_response(|notes: &mut Notes, response| {
+                        notes.report = Some(response.into())
+                    }),
+            )
+            .router(
+                RuleRouter::default()
+                    .when(
+                        |board: &Blackboard<Notes>| board.state.facts.len() < 2,
+                        "researcher",
+                    )
+                    .otherwise(Route::agent("writer")),
+            )
+            .terminate_when(Termination::AfterAgent("writer".into()))
+            .build()
+            .unwrap();
+
+        let outcome = crew.run("SOL report", Notes::default()).await.unwrap();
+
+        assert_eq!(outcome.reason, StopReason::Terminated);
+        assert_eq!(
+            outcome
+                .board
+                .hops
+                .iter()
+                .map(|hop| hop.agent.as_str())
+                .collect::<Vec<_>>(),
+            vec!["researcher", "researcher", "writer"]
+        );
+        assert_eq!(
+            outcome.board.hops[1].prompt,
+            "Task: SOL report\n\nWork so far:\n[researcher]: SOL is up"
+        );
+        assert_eq!(
+            writer_model.requests.lock().unwrap()[0].prompt,
+            "SOL is up; Volume is high"
+        );
+        assert_eq!(outcome.answer(), Some("Report: SOL is up on high volume"));
+        assert!(outcome.board.state.report.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_llm_router() {
+        let (_, researcher) = agent(&["SOL is up"]);
+        let (router_model, router) = agent(&["researcher", " Finish."]);
+
+        let crew = Crew::builder()
+            .member(CrewMember::new("researcher", "Finds facts", researcher))
+            .router(LlmRouter::new(router))
+            .build()
+            .unwrap();
+
+        let outcome = crew.run("SOL report", Notes::default()).await.unwrap();
+
+        assert_eq!(outcome.reason, StopReason::Finished);
+        assert_eq!(outcome.board.hops.len(), 1);
+        assert!(router_model.requests.lock().unwrap()[0]
+            .prompt
+            .contains("- researcher: Finds facts"));
+    }
+
+    #[tokio::test]
+    async fn test_max_hops_and_unknown_agent() {
+        let (_, echo) = agent(&["1", "2", "3"]);
+        let crew = Crew::builder()
+            .member(CrewMember::new("echo", "Echoes", echo))
+            .router(RuleRouter::default().otherwise(Route::agent("echo")))
+            .max_hops(2)
+            .build()
+            .unwrap();
+        let outcome = crew.run("Count", ()).await.unwrap();
+        assert_eq!(outcome.reason, StopReason::MaxHops);
+        assert_eq!(outcome.answer(), Some("2"));
+
+        let crew = Crew::<()>::builder()
+            .router(RuleRouter::default().otherwise(Route::agent("ghost")))
+            .build()
+            .unwrap();
+        assert!(matches!(
+            crew.run("Count", ()).await,
+            Err(CrewError::UnknownAgent(name)) if name == "ghost"
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_member_names_are_rejected() {
+        let (_, first) = agent(&[]);
+        let (_, second) = agent(&[]);
+        let result = Crew::<()>::builder()
+            .member(CrewMember::new("echo", "Echoes", first))
+            .member(CrewMember::new("echo", "Echoes again", second))
+            .build();
+        assert!(matches!(result, Err(CrewError::DuplicateMember(name)) if name == "echo"));
+    }
+}
@@ -0,0 +1,530 @@
+//! Vector indexes of embedding tools ([ToolEmbedding]), retrieved by the agent for each
+//! prompt and rehydrated from the context saved in the index.
+//!
+//! [DynamicToolIndexBuilder::build] embeds the [ToolEmbedding::embedding_docs] of the tools,
+//! stores them with their [ToolEmbedding::context] in a [ToolStore] and returns the resulting
+//! [DynamicToolIndex]. When the agent retrieves a tool from the index, the tool is
+//! initialized with [ToolEmbedding::init] from its saved context and the state given to the
+//! builder, so the index can be persisted (e.g.: in MongoDB) and reopened with
+//! [DynamicToolIndexBuilder::open] without the original tool instances.
+//!
+//! # Example
+//! ```rust
+//! use Hydranta::{
+//!     dynamic_tools::DynamicToolIndex,
+//!     providers::openai,
+//!     vector_store::in_memory_store::InMemoryVectorStore,
+//! };
+//!
+//! let openai = openai::Client::from_env();
+//! let embedding_model = openai.embedding_model(openai::TEXT_EMBEDDING_ADA_002);
+//!
+//! // `Add` and `Subtract` implement `ToolEmbedding` with `State = ()`
+//! let index = DynamicToolIndex::builder(embedding_model)
+//!     .tool(Add, ())
+//!     .tool(Subtract, ())
+//!     .build(InMemoryVectorStore::default())
+//!     .await?;
+//!
+//! let calculator = openai
+//!     .agent("gpt-4o")
+//!     .preamble("You are a calculator.")
+//!     .dynamic_tool_index(1, index)
+//!     .build();
+//! ```
+//!
+//! Any vector store can hold the tools by implementing [ToolStore]: it stores the
+//! [ToolSchema]s (whose `name` is the id of the document) with their embeddings and returns
+//! a [VectorStoreIndex](crate::vector_store::VectorStoreIndex) over them. For instance, with
+//! a MongoDB collection and the `rig-mongodb` index (mongodb 3.x):
+//! ```rust
+//! use mongodb::bson::{self, doc};
+//! use rig_mongodb::{MongoDbVectorIndex, SearchParams};
+//!
+//! struct MongoToolStore {
+//!     collection: mongodb::Collection<bson::Document>,
+//!     index_name: String,
+//! }
+//!
+//! impl<M: EmbeddingModel + 'static> ToolStore<M> for MongoToolStore {
+//!     type Index = MongoDbVectorIndex<M, bson::Document>;
+//!
+//!     async fn insert_tools(
+//!         self,
+//!         model: M,
+//!         tools: Vec<(ToolSchema, OneOrMany<Embedding>)>,
+//!     ) -> Result<Self::Index, VectorStoreError> {
+//!         let documents = tools
+//!             .into_iter()
+//!             .map(|(tool, embeddings)| {
+//!                 Ok(doc! {
+//!                     "_id": tool.name.clone(),
+//!                     "name": tool.name.clone(),
+//!                     "context": bson::to_bson(&tool.context)?,
+//!                     "embedding": embeddings.first().vec,
+//!                 })
+//!             })
+//!             .collect::<Result<Vec<_>, bson::ser::Error>>()
+//!             .map_err(|e| VectorStoreError::DatastoreError(Box::new(e)))?;
+//!         self.collection
+//!             .insert_many(documents)
+//!             .await
+//!             .map_err(|e| VectorStoreError::DatastoreError(Box::new(e)))?;
+//!
+//!         MongoDbVectorIndex::new(self.collection, model, &self.index_name, SearchParams::new())
+//!             .await
+//!     }
+//! }
+//! ```
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, RwLock},
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    completion::ToolDefinition,
+    embeddings::{
+        embed::EmbedError, tool::ToolSchema, Embedding, EmbeddingError, EmbeddingModel,
+        EmbeddingsBuilder,
+    },
+    tool::{ToolDyn, ToolEmbedding, ToolEmbeddingDyn, ToolSetError, ValidatedTool},
+    vector_store::{
+        in_memory_store::{InMemoryVectorIndex, InMemoryVectorStore},
+        VectorStoreError, VectorStoreIndexDyn,
+    },
+    OneOrMany,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum DynamicToolError {
+    #[error("EmbedError: {0}")]
+    EmbedError(#[from] EmbedError),
+
+    #[error("EmbeddingError: {0}")]
+    EmbeddingError(#[from] EmbeddingError),
+
+    #[error("VectorStoreError: {0}")]
+    VectorStoreError(#[from] VectorStoreError),
+
+    #[error("JsonError: {0}")]
+    JsonError(#[from] serde_json::Error),
+
+    /// No tool of this name was given to the builder of the index
+    #[error("UnknownToolError: tool {0} has no loader")]
+    UnknownToolError(String),
+
+    /// [ToolEmbedding::init] failed for the saved context of the tool
+    #[error("InitError: failed to initialize tool {0}: {1}")]
+    InitError(String, Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Vector store able to hold the tools of a [DynamicToolIndex]
+pub trait ToolStore<M: EmbeddingModel> {
+    type Index: VectorStoreIndexDyn + 'static;
+
+    /// Store the tools with their embeddings, using the tool names as document ids, and
+    /// return an index over them
+    fn insert_tools(
+        self,
+        model: M,
+        tools: Vec<(ToolSchema, OneOrMany<Embedding>)>,
+    ) -> impl Future<Output = Result<Self::Index, VectorStoreError>> + Send;
+}
+
+impl<M: EmbeddingModel + 'static> ToolStore<M> for InMemoryVectorStore<ToolSchema> {
+    type Index = InMemoryVectorIndex<M, ToolSchema>;
+
+    async fn insert_tools(
+        mut self,
+        model: M,
+        tools: Vec<(ToolSchema, OneOrMany<Embedding>)>,
+    ) -> Result<Self::Index, VectorStoreError> {
+        self.add_documents_with_id_f(tools, |tool| tool.name.clone());
+        Ok(self.index(model))
+    }
+}
+
+/// Tool as stored in the index. Other fields of the document (e.g.: the embedding docs, or
+/// the embeddings themselves for some stores) are ignored.
+#[derive(Deserialize)]
+struct StoredTool {
+    name: String,
+    context: Value,
+}
+
+type Loader = Box<dyn Fn(Value) -> Result<Arc<dyn ToolDyn>, DynamicToolError> + Send + Sync>;
+
+/// Builder for [DynamicToolIndex]
+pub struct DynamicToolIndexBuilder<M: EmbeddingModel> {
+    model: M,
+    tools: Vec<Box<dyn ToolEmbeddingDyn>>,
+    loaders: HashMap<String, Loader>,
+}
+
+impl<M: EmbeddingModel> DynamicToolIndexBuilder<M> {
+    pub fn new(model: M) -> Self {
+        Self {
+            model,
+            tools: vec![],
+            loaders: HashMap::new(),
+        }
+    }
+
+    /// Add a tool to embed in the index. Retrieved instances of the tool are initialized
+    /// with `state` and the context saved from `tool`.
+    pub fn tool<T>(mut self, tool: T, state: T::State) -> Self
+    where
+        T: ToolEmbedding + 'static,
+        T::State: Clone + Sync + 'static,
+    {
+        self.tools.push(Box::new(tool));
+        self.loader::<T>(state)
+    }
+
+    /// Register how to initialize the tool `T` when it is retrieved, without embedding an
+    /// instance of it. Used with [DynamicToolIndexBuilder::open] for tools already stored.
+    pub fn loader<T>(mut self, state: T::State) -> Self
+    where
+        T: ToolEmbedding + 'static,
+        T::State: Clone + Sync + 'static,
+    {
+        let loader: Loader = Box::new(move |context| {
+            let tool = T::init(state.clone(), serde_json::from_value(context)?)
+                .map_err(|e| DynamicToolError::InitError(T::NAME.to_string(), Box::new(e)))?;
+            Ok(Arc::new(ValidatedTool::new(tool)))
+        });
+        self.loaders.insert(T::NAME.to_string(), loader);
+        self
+    }
+
+    /// Embed the tools and store them in `store`
+    pub async fn build(
+        self,
+        store: impl ToolStore<M>,
+    ) -> Result<DynamicToolIndex, DynamicToolError> {
+        let schemas = self
+            .tools
+            .iter()
+            .map(|tool| ToolSchema::try_from(&**tool))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let embeddings = EmbeddingsBuilder::new(self.model.clone())
+            .documents(schemas)?
+            .build()
+            .await?;
+
+        let index = store.insert_tools(self.model, embeddings).await?;
+        Ok(DynamicToolIndex::new(index, self.loaders))
+    }
+
+    /// Open an index whose tools were already stored (see [DynamicToolIndexBuilder::build])
+    pub fn open(self, index: impl VectorStoreIndexDyn + 'static) -> DynamicToolIndex {
+        DynamicToolIndex::new(index, self.loaders)
+    }
+}
+
+/// Vector index of embedding tools. Tools are initialized from their saved context the
+/// first time they are retrieved, and kept to serve the calls of the model.
+pub struct DynamicToolIndex {
+    index: Box<dyn VectorStoreIndexDyn>,
+    loaders: HashMap<String, Loader>,
+    loaded: RwLock<HashMap<String, Arc<dyn ToolDyn>>>,
+}
+
+impl DynamicToolIndex {
+    pub fn builder<M: EmbeddingModel>(model: M) -> DynamicToolIndexBuilder<M> {
+        DynamicToolIndexBuilder::new(model)
+    }
+
+    fn new(index: impl VectorStoreIndexDyn + 'static, loaders: HashMap<String, Loader>) -> Self {
+        Self {
+            index: Box::new(index),
+            loaders,
+            loaded: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `toolname` was retrieved from the index and can be called
+    pub fn contains(&self, toolname: &str) -> bool {
+        self.loaded.read().unwrap().contains_key(toolname)
+    }
+
+    /// Definitions of the `n` tools most relevant to `prompt`. Tools which cannot be
+    /// initialized from their saved context are skipped.
+    pub async fn definitions(
+        &self,
+        prompt: &str,
+        n: usize,
+    ) -> Result<Vec<ToolDefinition>, DynamicToolError> {
+        let mut definitions = vec![];
+        for (_, id, document) in self.index.top_n(prompt, n).await? {
+            match self.load(document) {
+                Ok(tool) => definitions.push(tool.definition(prompt.to_string()).await),
+                Err(e) => tracing::warn!(target: "rig", "Skipping dynamic tool {id}: {e}"),
+            }
+        }
+        Ok(definitions)
+    }
+
+    /// Call a tool retrieved from the index
+    pub async fn call(&self, toolname: &str, args: String) -> Result<String, ToolSetError> {
+        let tool = self
+            .loaded
+            .read()
+            .unwrap()
+            .get(toolname)
+            .cloned()
+            .ok_or_else(|| ToolSetError::ToolNotFoundError(toolname.to_string()))?;
+        Ok(tool.call(args).await?)
+    }
+
+    /// The tool stored as `document`, initialized on its first retrieval
+    fn load(&self, document: Value) -> Result<Arc<dyn ToolDyn>, DynamicToolError> {
+        let stored: StoredTool = serde_json::from_value(document)?;
+        if let Some(tool) = self.loaded.read().unwrap().get(&stored.name) {
+            return Ok(tool.clone());
+        }
+
+        let loader = self
+            .loaders
+            .get(&stored.name)
+            .ok_or_else(|| DynamicToolError::UnknownToolError(stored.name.clone()))?;
+        let tool = loader(stored.context)?;
+        self.loaded
+            .write()
+            .unwrap()
+            .insert(stored.name, tool.clone());
+        Ok(tool)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        agent::{tests::ScriptedModel, AgentBuilder, StopCondition},
+        completion::{Completion, ModelChoice, Prompt},
+        tool::Tool,
+    };
+
+    /// Embedding model placing texts on two axes: arithmetic and text manipulation
+    #[derive(Clone)]
+    struct KeywordModel;
+
+    impl EmbeddingModel for KeywordModel {
+        const MAX_DOCUMENTS: usize = 16;
+
+        fn ndims(&self) -> usize {
+            2
+        }
+
+        async fn embed_texts(
+            &self,
+            texts: impl IntoIterator<Item = String> + Send,
+        ) -> Result<Vec<Embedding>, EmbeddingError> {
+            Ok(texts
+                .into_iter()
+                .map(|text| {
+                    let math = ["add", "sum", "plus"].iter().any(|w| text.contains(w));
+                    Embedding {
+                        vec: if math { vec![1.0, 0.1] } else { vec![0.1, 1.0] },
+                        document: text,
+                    }
+                })
+                .collect())
+        }
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("Init error")]
+    struct InitError;
+
+    #[derive(Deserialize, Serialize)]
+    struct Offset {
+        offset: i32,
+    }
+
+    /// Adds its saved offset to `x`, scaled by the state given to the index
+    struct AddOffset {
+        offset: i32,
+        scale: i32,
+    }
+
+    impl Tool for AddOffset {
+        const NAME: &'static str = "add_offset";
+        type Error = InitError;
+        type Args = Value;
+        type Output = i32;
+
+        async fn definition(&self, _prompt: String) -> ToolDefinition {
+            ToolDefinition {
+                name: Self::NAME.to_string(),
+                description: format!("Add {} to x", self.offset),
+                parameters: json!({
+                    "type": "object",
+                    "properties": { "x": { "type": "integer" } },
+                }),
+            }
+        }
+
+        async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+            Ok((args["x"].as_i64().unwrap() as i32 + self.offset) * self.scale)
+        }
+    }
+
+    impl ToolEmbedding for AddOffset {
+        type InitError = InitError;
+        type Context = Offset;
+        type State = i32;
+
+        fn embedding_docs(&self) -> Vec<String> {
+            vec!["add a number".to_string(), "sum of numbers".to_string()]
+        }
+
+        fn context(&self) -> Self::Context {
+            Offset {
+                offset: self.offset,
+            }
+        }
+
+        fn init(scale: Self::State, context: Self::Context) -> Result<Self, Self::InitError> {
+            Ok(AddOffset {
+                offset: context.offset,
+                scale,
+            })
+        }
+    }
+
+    struct Shout;
+
+    impl Tool for Shout {
+        const NAME: &'static str = "shout";
+        type Error = InitError;
+        type Args = String;
+        type Output = String;
+
+        async fn definition(&self, _prompt: String) -> ToolDefinition {
+            ToolDefinition {
+                name: Self::NAME.to_string(),
+                description: "Uppercase a text".to_string(),
+                parameters: json!({ "type": "string" }),
+            }
+        }
+
+        async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+            Ok(args.to_uppercase())
+        }
+    }
+
+    impl ToolEmbedding for Shout {
+        type InitError = InitError;
+        type Context = ();
+        type State = ();
+
+        fn embedding_docs(&self) -> Vec<String> {
+            vec!["uppercase a text".to_string()]
+        }
+
+        fn context(&self) -> Self::Context {}
+
+        fn init(_state: Self::State, _context: Self::Context) -> Result<Self, Self::InitError> {
+            Err(InitError)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tools_rehydrated_on_retrieval() {
+        let original = AddOffset {
+            offset: 2,
+            scale: 1,
+        };
+        let index = DynamicToolIndex::builder(KeywordModel)
+            .tool(original, 10)
+            .tool(Shout, ())
+            .build(InMemoryVectorStore::default())
+            .await
+            .unwrap();
+        assert!(!index.contains("add_offset"));
+
+        let definitions = index.definitions("what is 1 plus 2?", 1).await.unwrap();
+        assert_eq!(definitions.len(), 1);
+        assert_eq!(definitions[0].name, "add_offset");
+        assert_eq!(definitions[0].description, "Add 2 to x");
+
+        // Initialized with the saved offset and the state given to the builder
+        let result = index
+            .call("add_offset", json!({ "x": 1 }).to_string())
+            .await
+            .unwrap();
+        assert_eq!(result, "30");
+
+        // Tools failing to initialize are skipped
+        let definitions = index.definitions("uppercase this", 1).await.unwrap();
+        assert!(definitions.is_empty());
+        assert!(matches!(
+            index.call("shout", "\"hi\"".to_string()).await,
+            Err(ToolSetError::ToolNotFoundError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_open_requires_loaders() {
+        let embeddings = EmbeddingsBuilder::new(KeywordModel)
+            .documents(vec![ToolSchema::try_from(&AddOffset {
+                offset: 5,
+                scale: 1,
+            }
+                as &dyn ToolEmbeddingDyn)
+            .unwrap()])
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let store =
+            InMemoryVectorStore::from_documents_with_id_f(embeddings, |tool| tool.name.clone());
+
+        let index = DynamicToolIndex::builder(KeywordModel).open(store.clone().index(KeywordModel));
+        assert!(index.definitions("add", 1).await.unwrap().is_empty());
+
+        let index = DynamicToolIndex::builder(KeywordModel)
+            .loader::<AddOffset>(1)
+            .open(store.index(KeywordModel));
+        let definitions = index.definitions("add", 1).await.unwrap();
+        assert_eq!(definitions[0].description, "Add 5 to x");
+    }
+
+    #[tokio::test]
+    async fn test_agent_calls_retrieved_tool() {
+        let index = DynamicToolIndex::builder(KeywordModel)
+            .tool(
+                AddOffset {
+                    offset: 2,
+                    scale: 1,
+                },
+                1,
+            )
+            .build(InMemoryVectorStore::default())
+            .await
+            .unwrap();
+
+        let model = ScriptedModel::new([ModelChoice::ToolCall(
+            "add_offset".into(),
+            json!({ "x": 40 }),
+        )]);
+        let agent = AgentBuilder::new(model)
+            .dynamic_tool_index(1, index)
+            .stop_when(StopCondition::AnyTool)
+            .build();
+
+        let request = agent.completion("add", vec![]).await.unwrap().build();
+        assert_eq!(request.tools[0].name, "add_offset");
+        assert_eq!(agent.prompt("What is 40 plus 2?").await.unwrap(), "42");
+    }
+}
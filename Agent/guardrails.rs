@@ -0,0 +1,530 @@
+//! Input and output guardrails of agents.
+//!
+//! A [Guardrail] inspects a text (the user's prompt, or the agent's answer) and reports a
+//! [Finding] when the text breaks its rule, optionally with a compliant rewrite of the text.
+//! Each guardrail is attached to an agent with a [GuardrailAction] deciding what happens on a
+//! finding:
+//! - [GuardrailAction::Block]: the prompt fails with a [GuardrailViolation]
+//! - [GuardrailAction::Rewrite]: the text is replaced by the guardrail's rewrite (e.g.: with
+//!   redacted secrets), or blocked if the guardrail has no rewrite
+//! - [GuardrailAction::Flag]: the text goes through, and the finding is reported to the
+//!   agent's flag handler and logged
+//!
+//! Built-in guardrails: [RegexGuardrail] (with [RegexGuardrail::pii] and
+//! [RegexGuardrail::secrets] presets), [MaxLength] and [LlmJudge].
+//!
+//! A [GuardrailViolation] is returned inside a [PromptError]; use
+//! [PromptErrorExt::guardrail_violation] to tell it apart from transport and tool errors.
+//!
+//! # Example
+//! ```
+//! use Hydranta::guardrails::{GuardrailAction, MaxLength, PromptErrorExt, RegexGuardrail};
+//!
+//! let agent = AgentBuilder::new(model)
+//!     .input_guardrail(RegexGuardrail::secrets(), GuardrailAction::Block)
+//!     .output_guardrail(RegexGuardrail::pii(), GuardrailAction::Rewrite)
+//!     .output_guardrail(MaxLength(2000), GuardrailAction::Flag)
+//!     .build();
+//!
+//! match agent.prompt(&user_input).await {
+//!     Ok(answer) => println!("{answer}"),
+//!     Err(error) => match error.guardrail_violation() {
+//!         Some(violation) => println!("Refused: {}", violation.reason),
+//!         None => return Err(error.into()),
+//!     },
+//! }
+//! ```
+use std::{fmt, sync::Arc};
+
+use futures::future::BoxFuture;
+use regex::Regex;
+
+use crate::completion::{CompletionError, Prompt, PromptError};
+
+/// Whether a guardrail checks the prompt or the answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Input,
+    Output,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Input => write!(f, "input"),
+            Stage::Output => write!(f, "output"),
+        }
+    }
+}
+
+/// What happens when a guardrail reports a finding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardrailAction {
+    Block,
+    Rewrite,
+    Flag,
+}
+
+/// A rule broken by a text
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub reason: String,
+    /// The text rewritten to comply with the rule, if the guardrail can rewrite it
+    pub rewritten: Option<String>,
+}
+
+/// A prompt or answer blocked by a guardrail
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("GuardrailViolation: {guardrail} blocked the {stage}: {reason}")]
+pub struct GuardrailViolation {
+    pub guardrail: String,
+    pub stage: Stage,
+    pub reason: String,
+}
+
+/// A finding of a guardrail attached with [GuardrailAction::Flag]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Flag {
+    pub guardrail: String,
+    pub stage: Stage,
+    pub reason: String,
+}
+
+pub trait Guardrail: Send + Sync {
+    fn name(&self) -> String;
+
+    /// Check `text`, returning a finding if it breaks the guardrail's rule
+    fn check<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Option<Finding>, PromptError>>;
+}
+
+/// Access to the guardrail violation carried by a [PromptError]
+pub trait PromptErrorExt {
+    fn guardrail_violation(&self) -> Option<&GuardrailViolation>;
+}
+
+impl PromptErrorExt for PromptError {
+    fn guardrail_violation(&self) -> Option<&GuardrailViolation> {
+        match self {
+            PromptError::CompletionError(CompletionError::RequestError(error)) => {
+                error.downcast_ref::<GuardrailViolation>()
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<GuardrailViolation> for PromptError {
+    fn from(violation: GuardrailViolation) -> Self {
+        PromptError::CompletionError(CompletionError::RequestError(Box::new(violation)))
+    }
+}
+
+pub type FlagHandler = Arc<dyn Fn(&Flag) + Send + Sync>;
+
+/// The guardrails of an agent, with their actions
+#[derive(Default)]
+pub struct Guardrails {
+    input: Vec<(Box<dyn Guardrail>, GuardrailAction)>,
+    output: Vec<(Box<dyn Guardrail>, GuardrailAction)>,
+    on_flag: Option<FlagHandler>,
+}
+
+impl Guardrails {
+    pub fn add(
+        &mut self,
+        stage: Stage,
+        guardrail: impl Guardrail + 'static,
+        action: GuardrailAction,
+    ) {
+        let guardrails = match stage {
+            Stage::Input => &mut self.input,
+            Stage::Output => &mut self.output,
+        };
+        guardrails.push((Box::new(guardrail), action));
+    }
+
+    pub fn set_flag_handler(&mut self, handler: impl Fn(&Flag) + Send + Sync + 'static) {
+        self.on_flag = Some(Arc::new(handler));
+    }
+
+    /// Whether any guardrail checks the texts of `stage`
+    pub fn has(&self, stage: Stage) -> bool {
+        match stage {
+            Stage::Input => !self.input.is_empty(),
+            Stage::Output => !self.output.is_empty(),
+        }
+    }
+
+    /// Run the guardrails of `stage` on `text` in order, returning the possibly rewritten text
+    pub async fn apply(&self, stage: Stage, text: String) -> Result<String, PromptError> {
+        let guardrails = match stage {
+            Stage::Input => &self.input,
+            Stage::Output => &self.output,
+        };
+
+        let mut text = text;
+        for (guardrail, action) in guardrails {
+            let Some(finding) = guardrail.check(&text).await? else {
+                continue;
+            };
+            let name = guardrail.name();
+
+            match (action, finding.rewritten) {
+                (GuardrailAction::Rewrite, Some(rewritten)) => {
+                    tracing::info!(target: "rig",
+                        "Guardrail {name} rewrote the {stage}: {}", finding.reason
+                    );
+                    text = rewritten;
+                }
+                (GuardrailAction::Flag, _) => {
+                    tracing::warn!(target: "rig",
+                        "Guardrail {name} flagged the {stage}: {}", finding.reason
+                    );
+                    if let Some(on_flag) = &self.on_flag {
+                        on_flag(&Flag {
+                            guardrail: name,
+                            stage,
+                            reason: finding.reason,
+                        });
+                    }
+                }
+                _ => {
+                    tracing::warn!(target: "rig",
+                        "Guardrail {name} blocked the {stage}: {}", finding.reason
+                    );
+                    return Err(GuardrailViolation {
+                        guardrail: name,
+                        stage,
+                        reason: finding.reason,
+                    }
+                    .into());
+                }
+            }
+        }
+        Ok(text)
+    }
+}
+
+/// Guardrail matching a set of regular expressions. Rewrites replace each match with
+/// `[REDACTED <label>]`.
+pub struct RegexGuardrail {
+    name: String,
+    patterns: Vec<(String, Regex)>,
+}
+
+impl RegexGuardrail {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            patterns: vec![],
+        }
+    }
+
+    /// Add the regular expression `pattern`, reported as `label` when it matches
+    pub fn pattern(mut self, label: &str, pattern: &str) -> Result<Self, regex::Error> {
+        self.patterns
+            .push((label.to_string(), Regex::new(pattern)?));
+        Ok(self)
+    }
+
+    /// Personally identifiable information: email addresses, phone numbers and card numbers
+    pub fn pii() -> Self {
+        Self::preset(
+            "pii",
+            &[
+                ("email", r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}"),
+                ("card number", r"\b(?:\d[ -]?){12,18}\d\b"),
+                (
+                    "phone number",
+                    r"(?:\+\d{1,3}[ .-]?)?\(?\d{3}\)?[ .-]\d{3}[ .-]\d{3,4}\b",
+                ),
+            ],
+        )
+    }
+
+    /// Credentials: private keys (PEM, byte arrays, hex and base58 encoded), wallet seed
+    /// phrases and common API key formats. Seed phrases are detected as lines of 12 to 24
+    /// short lowercase words, and hex keys as 64 hex digits, so transaction hashes and some
+    /// plain sentences may be reported too.
+    pub fn secrets() -> Self {
+        Self::preset(
+            "secrets",
+            &[
+                (
+                    "private key",
+                    r"-----BEGIN [A-Z ]*PRIVATE KEY-----[\s\S]*?(?:-----END [A-Z ]*PRIVATE KEY-----|$)",
+                ),
+                (
+                    "private key bytes",
+                    r"\[\s*(?:\d{1,3}\s*,\s*){31,}\d{1,3}\s*\]",
+                ),
+                ("hex private key", r"\b(?:0x)?[0-9a-fA-F]{64}\b"),
+                ("base58 private key", r"\b[1-9A-HJ-NP-Za-km-z]{86,88}\b"),
+                (
+                    "seed phrase",
+                    r"(?m)^[ \t]*(?:[a-z]{3,8}[ \t]+){11,23}[a-z]{3,8}[ \t]*$",
+                ),
+                (
+                    "api key",
+                    r"\b(?:sk-[A-Za-z0-9_-]{20,}|AKIA[0-9A-Z]{16}|gh[pousr]_[A-Za-z0-9]{36})\b",
+                ),
+            ],
+        )
+    }
+
+    fn preset(name: &str, patterns: &[(&str, &str)]) -> Self {
+        patterns
+            .iter()
+            .fold(Self::new(name), |guardrail, (label, pattern)| {
+                guardrail
+                    .pattern(label, pattern)
+                    .expect("Preset patterns should be valid")
+            })
+    }
+}
+
+impl Guardrail for RegexGuardrail {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn check<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Option<Finding>, PromptError>> {
+        let mut labels = vec![];
+        let mut rewritten = text.to_string();
+        for (label, regex) in &self.patterns {
+            if regex.is_match(&rewritten) {
+                labels.push(label.as_str());
+                rewritten = regex
+                    .replace_all(&rewritten, format!("[REDACTED {label}]"))
+                    .into_owned();
+            }
+        }
+
+        let finding = (!labels.is_empty()).then(|| Finding {
+            reason: format!("contains {}", labels.join(", ")),
+            rewritten: Some(rewritten),
+        });
+        Box::pin(futures::future::ready(Ok(finding)))
+    }
+}
+
+/// Guardrail limiting the number of characters of a text. Rewrites truncate the text.
+pub struct MaxLength(pub usize);
+
+impl Guardrail for MaxLength {
+    fn name(&self) -> String {
+        "max_length".into()
+    }
+
+    fn check<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Option<Finding>, PromptError>> {
+        let length = text.chars().count();
+        let finding = (length > self.0).then(|| Finding {
+            reason: format!("{length} characters, above the limit of {}", self.0),
+            rewritten: Some(text.chars().take(self.0).collect()),
+        });
+        Box::pin(futures::future::ready(Ok(finding)))
+    }
+}
+
+/// Guardrail asking a model whether a text complies with a policy. The judge must answer
+/// `ALLOW`, or `BLOCK: <reason>`. It cannot rewrite texts.
+pub struct LlmJudge<P: Prompt> {
+    name: String,
+    judge: P,
+    policy: String,
+}
+
+impl<P: Prompt> LlmJudge<P> {
+    pub fn new(name: &str, judge: P, policy: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            judge,
+            policy: policy.to_string(),
+        }
+    }
+}
+
+impl<P: Prompt> Guardrail for LlmJudge<P> {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn check<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Option<Finding>, PromptError>> {
+        Box::pin(async move {
+            let prompt = format!(
+                "You review texts for compliance with this policy:\n{}\n\n\
+                 Text to review:\n<text>\n{text}\n</text>\n\n\
+                 Answer ALLOW if the text complies with the policy, \
+                 otherwise BLOCK: followed by the reason.",
+                self.policy
+            );
+            let verdict = self.judge.prompt(&prompt).await?;
+            let verdict = verdict.trim();
+
+            if verdict.to_uppercase().starts_with("ALLOW") {
+                return Ok(None);
+            }
+            let reason = verdict
+                .strip_prefix("BLOCK")
+                .map(|reason| reason.trim_start_matches(':').trim())
+                .filter(|reason| !reason.is_empty())
+                .unwrap_or(verdict);
+            Ok(Some(Finding {
+                reason: reason.to_string(),
+                rewritten: None,
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{
+        agent::{tests::ScriptedModel, AgentBuilder},
+        completion::{Chat, Message, ModelChoice},
+        memory::{ConversationMemory, InMemoryMemory, MemoryStrategy},
+    };
+
+    async fn check(guardrail: &impl Guardrail, text: &str) -> Option<Finding> {
+        guardrail.check(text).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_secrets_are_detected() {
+        let secrets = RegexGuardrail::secrets();
+        let key_bytes = format!("PRIVATE_KEY = [{}]", vec!["174"; 64].join(", "));
+
+        for text in [
+            key_bytes.as_str(),
+            "abandon ability able about above absent absorb abstract absurd abuse access accident",
+            "key: 0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318",
+            "OPENAI_API_KEY=sk-proj-abcdefghijklmnopqrstuvwxyz",
+        ] {
+            assert!(check(&secrets, text).await.is_some(), "{text}");
+        }
+        assert!(check(
+            &secrets,
+            "Send 1 SOL to 7xKXtg2CW87d97TXJSDpbD5jBkheTqA83TZRuJosgAsU"
+        )
+        .await
+        .is_none());
+
+        let finding = check(&secrets, &key_bytes).await.unwrap();
+        assert_eq!(
+            finding.rewritten.as_deref(),
+            Some("PRIVATE_KEY = [REDACTED private key bytes]")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pii_and_max_length() {
+        let finding = check(
+            &RegexGuardrail::pii(),
+            "Mail ada@example.com or call 555-123-4567",
+        )
+        .await
+        .unwrap();
+        assert_eq!(finding.reason, "contains email, phone number");
+        assert_eq!(
+            finding.rewritten.as_deref(),
+            Some("Mail [REDACTED email] or call [REDACTED phone number]")
+        );
+
+        assert!(check(&MaxLength(5), "short").await.is_none());
+        assert_eq!(
+            check(&MaxLength(5), "too long")
+                .await
+                .unwrap()
+                .rewritten
+                .as_deref(),
+            Some("too l")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_agent_guardrails() {
+        let flags = Arc::new(Mutex::new(vec![]));
+        let recorded = flags.clone();
+        let model =
+            ScriptedModel::new([ModelChoice::Message("Reach me at ada@example.com".into())]);
+        let agent = AgentBuilder::new(model.clone())
+            .input_guardrail(RegexGuardrail::secrets(), GuardrailAction::Block)
+            .input_guardrail(MaxLength(10), GuardrailAction::Flag)
+            .output_guardrail(RegexGuardrail::pii(), GuardrailAction::Rewrite)
+            .on_guardrail_flag(move |flag| recorded.lock().unwrap().push(flag.clone()))
+            .build();
+
+        let error = agent
+            .prompt("My key is sk-abcdefghijklmnopqrstuvwxyz")
+            .await
+            .unwrap_err();
+        let violation = error.guardrail_violation().unwrap();
+        assert_eq!(violation.stage, Stage::Input);
+        assert_eq!(violation.guardrail, "secrets");
+        assert!(model.requests.lock().unwrap().is_empty());
+
+        assert_eq!(
+            agent.prompt("How can I reach you?").await.unwrap(),
+            "Reach me at [REDACTED email]"
+        );
+        assert_eq!(flags.lock().unwrap()[0].guardrail, "max_length");
+    }
+
+    #[tokio::test]
+    async fn test_input_guardrails_check_history_and_memory() {
+        let model = ScriptedModel::new([
+            ModelChoice::Message("Noted".into()),
+            ModelChoice::Message("Noted again".into()),
+        ]);
+        let backend = Arc::new(InMemoryMemory::default());
+        let agent = AgentBuilder::new(model.clone())
+            .input_guardrail(RegexGuardrail::pii(), GuardrailAction::Rewrite)
+            .memory(backend.clone(), MemoryStrategy::Full)
+            .build();
+
+        agent
+            .converse("c", "Mail me at ada@example.com")
+            .await
+            .unwrap();
+        assert_eq!(
+            backend.load("c").await.unwrap()[0].content,
+            "Mail me at [REDACTED email]"
+        );
+
+        let history = vec![Message {
+            role: "user".into(),
+            content: "I am bob@example.com".into(),
+        }];
+        agent.chat("Remember me?", history).await.unwrap();
+        assert_eq!(
+            model.requests.lock().unwrap()[1].chat_history[0].content,
+            "I am [REDACTED email]"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_llm_judge() {
+        let judge = |verdict: &str| {
+            LlmJudge::new(
+                "tone",
+                AgentBuilder::new(ScriptedModel::new([ModelChoice::Message(verdict.into())]))
+                    .build(),
+                "No financial advice",
+            )
+        };
+
+        assert!(check(&judge("ALLOW"), "SOL is a blockchain")
+            .await
+            .is_none());
+        assert_eq!(
+            check(&judge("BLOCK: gives investment advice"), "Buy SOL now")
+                .await
+                .unwrap()
+                .reason,
+            "gives investment advice"
+        );
+    }
+}
@@ -0,0 +1,517 @@
+//! Validation of tool arguments against the JSON schema of their [ToolDefinition](crate::completion::ToolDefinition).
+//!
+//! Supports the subset of JSON schema (draft 7) produced by `schemars` and used in hand-written
+//! tool definitions: `type`, `enum`, `const`, `properties`, `required`, `additionalProperties`,
+//! `items`, `allOf`/`anyOf`/`oneOf`, local `$ref`s and the usual numeric, string and array bounds.
+//! Unknown keywords are ignored.
+use std::cell::Cell;
+
+use serde_json::{Map, Value};
+
+/// Maximum number of `$ref`s followed while validating a single value. Recursive schemas
+/// only go one level deeper per level of the instance, so this is only reached by a cycle of
+/// references (e.g.: `{ "$ref": "#" }`) or an unreasonably nested instance.
+const MAX_REF_DEPTH: usize = 256;
+
+/// Maximum number of (sub)schemas checked while validating a single value. `anyOf`/`oneOf`
+/// check every branch, so a schema like `{ "anyOf": [{ "$ref": "#" }, { "$ref": "#" }] }`
+/// would otherwise take time exponential in [MAX_REF_DEPTH].
+const MAX_STEPS: usize = 1_000_000;
+
+/// A single mismatch between a value and its schema
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaViolation {
+    /// Location of the offending value, e.g.: `$.transfers[1].amount`
+    pub path: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("Arguments do not match the schema: {}", display_violations(.violations, "; "))]
+pub struct ValidationError {
+    pub violations: Vec<SchemaViolation>,
+}
+
+impl ValidationError {
+    /// Message sent back to the model so it can fix the arguments of its call to `toolname`
+    pub fn correction_prompt(&self, toolname: &str) -> String {
+        format!(
+            "The arguments of your call to tool `{toolname}` are invalid:\n- {}\n\
+            Call the tool again with corrected arguments.",
+            display_violations(&self.violations, "\n- ")
+        )
+    }
+}
+
+fn display_violations(violations: &[SchemaViolation], separator: &str) -> String {
+    violations
+        .iter()
+        .map(|violation| violation.to_string())
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// Validate `instance` against `schema`, returning every violation found
+pub fn validate(schema: &Value, instance: &Value) -> Result<(), ValidationError> {
+    let mut violations = vec![];
+    let validator = Validator {
+        root: schema,
+        ref_depth: Cell::new(0),
+        steps: Cell::new(0),
+    };
+    validator.validate(schema, instance, "$", &mut violations);
+
+    if validator.steps.get() > MAX_STEPS {
+        // Violations found before the budget ran out may be spurious
+        violations = vec![SchemaViolation {
+            path: "$".into(),
+            message: "the schema is too complex to validate".into(),
+        }];
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationError { violations })
+    }
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+    /// Number of `$ref`s currently being followed
+    ref_depth: Cell<usize>,
+    /// Number of (sub)schemas checked so far, see [MAX_STEPS]
+    steps: Cell<usize>,
+}
+
+impl<'a> Validator<'a> {
+    fn validate(
+        &self,
+        schema: &'a Value,
+        instance: &Value,
+        path: &str,
+        violations: &mut Vec<SchemaViolation>,
+    ) {
+        self.steps.set(self.steps.get() + 1);
+        if self.steps.get() > MAX_STEPS {
+            return push(
+                violations,
+                path,
+                "the schema is too complex to validate".into(),
+            );
+        }
+
+        let schema = match schema {
+            Value::Object(schema) => schema,
+            Value::Bool(false) => {
+                return push(violations, path, "no value is allowed here".into());
+            }
+            _ => return,
+        };
+
+        // In draft 7, keywords next to a `$ref` are ignored
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            if self.ref_depth.get() >= MAX_REF_DEPTH {
+                return push(
+                    violations,
+                    path,
+                    format!("schema reference `{reference}` is nested too deeply"),
+                );
+            }
+            match self.resolve(reference) {
+                Some(schema) => {
+                    self.ref_depth.set(self.ref_depth.get() + 1);
+                    self.validate(schema, instance, path, violations);
+                    self.ref_depth.set(self.ref_depth.get() - 1);
+                }
+                None => push(
+                    violations,
+                    path,
+                    format!("unresolvable schema reference `{reference}`"),
+                ),
+            }
+            return;
+        }
+
+        if let Some(expected) = schema.get("type") {
+            let types = match expected {
+                Value::String(ty) => vec![ty.as_str()],
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                _ => vec![],
+            };
+            if !types.is_empty() && !types.iter().any(|ty| has_type(instance, ty)) {
+                let message = format!(
+                    "expected {}, found {}",
+                    types.join(" or "),
+                    type_name(instance)
+                );
+                return push(violations, path, message);
+            }
+        }
+
+        if let Some(Value::Array(allowed)) = schema.get("enum") {
+            if !allowed.contains(instance) {
+                let allowed = Value::Array(allowed.clone());
+                push(
+                    violations,
+                    path,
+                    format!("expected one of {allowed}, found {instance}"),
+                );
+            }
+        }
+
+        if let Some(expected) = schema.get("const") {
+            if expected != instance {
+                push(
+                    violations,
+                    path,
+                    format!("expected {expected}, found {instance}"),
+                );
+            }
+        }
+
+        self.validate_combinators(schema, instance, path, violations);
+
+        match instance {
+            Value::Object(object) => self.validate_object(schema, object, path, violations),
+            Value::Array(array) => self.validate_array(schema, array, path, violations),
+            Value::String(string) => {
+                let length = string.chars().count() as u64;
+                if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+                    if length < min {
+                        push(
+                            violations,
+                            path,
+                            format!("expected at least {min} characters"),
+                        );
+                    }
+                }
+                if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+                    if length > max {
+                        push(
+                            violations,
+                            path,
+                            format!("expected at most {max} characters"),
+                        );
+                    }
+                }
+            }
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or_default();
+                let bound = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+                if let Some(min) = bound("minimum").filter(|min| number < *min) {
+                    push(violations, path, format!("expected a value >= {min}"));
+                }
+                if let Some(max) = bound("maximum").filter(|max| number > *max) {
+                    push(violations, path, format!("expected a value <= {max}"));
+                }
+                if let Some(min) = bound("exclusiveMinimum").filter(|min| number <= *min) {
+                    push(violations, path, format!("expected a value > {min}"));
+                }
+                if let Some(max) = bound("exclusiveMaximum").filter(|max| number >= *max) {
+                    push(violations, path, format!("expected a value < {max}"));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn validate_combinators(
+        &self,
+        schema: &'a Map<String, Value>,
+        instance: &Value,
+        path: &str,
+        violations: &mut Vec<SchemaViolation>,
+    ) {
+        if let Some(Value::Array(schemas)) = schema.get("allOf") {
+            for schema in schemas {
+                self.validate(schema, instance, path, violations);
+            }
+        }
+
+        let matching = |schemas: &'a Vec<Value>| {
+            schemas
+                .iter()
+                .filter(|schema| {
+                    let mut violations = vec![];
+                    self.validate(schema, instance, path, &mut violations);
+                    violations.is_empty()
+                })
+                .count()
+        };
+
+        if let Some(Value::Array(schemas)) = schema.get("anyOf") {
+            if matching(schemas) == 0 {
+                push(
+                    violations,
+                    path,
+                    "does not match any of the allowed schemas".into(),
+                );
+            }
+        }
+
+        if let Some(Value::Array(schemas)) = schema.get("oneOf") {
+            match matching(schemas) {
+                1 => {}
+                0 => push(
+                    violations,
+                    path,
+                    "does not match any of the allowed schemas".into(),
+                ),
+                n => push(
+                    violations,
+                    path,
+                    format!("matches {n} schemas, expected exactly one"),
+                ),
+            }
+        }
+    }
+
+    fn validate_object(
+        &self,
+        schema: &'a Map<String, Value>,
+        object: &Map<String, Value>,
+        path: &str,
+        violations: &mut Vec<SchemaViolation>,
+    ) {
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(name) {
+                    push(
+                        violations,
+                        &format!("{path}.{name}"),
+                        "missing required property".into(),
+                    );
+                }
+            }
+        }
+
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (name, value) in object {
+            let property_path = format!("{path}.{name}");
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(property) => self.validate(property, value, &property_path, violations),
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        push(violations, &property_path, "unexpected property".into())
+                    }
+                    Some(additional) => {
+                        self.validate(additional, value, &property_path, violations)
+                    }
+                    None => {}
+                },
+            }
+        }
+    }
+
+    fn validate_array(
+        &self,
+        schema: &'a Map<String, Value>,
+        array: &[Value],
+        path: &str,
+        violations: &mut Vec<SchemaViolation>,
+    ) {
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+            if (array.len() as u64) < min {
+                push(violations, path, format!("expected at least {min} items"));
+            }
+        }
+        if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+            if (array.len() as u64) > max {
+                push(violations, path, format!("expected at most {max} items"));
+            }
+        }
+
+        match schema.get("items") {
+            // Tuple validation
+            Some(Value::Array(items)) => {
+                for (i, (item, value)) in items.iter().zip(array).enumerate() {
+                    self.validate(item, value, &format!("{path}[{i}]"), violations);
+                }
+            }
+            Some(items) => {
+                for (i, value) in array.iter().enumerate() {
+                    self.validate(items, value, &format!("{path}[{i}]"), violations);
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Resolve a local reference such as `#/definitions/Transfer`
+    fn resolve(&self, reference: &str) -> Option<&'a Value> {
+        match reference {
+            "#" => Some(self.root),
+            reference => self.root.pointer(reference.strip_prefix('#')?),
+        }
+    }
+}
+
+fn push(violations: &mut Vec<SchemaViolation>, path: &str, message: String) {
+    violations.push(SchemaViolation {
+        path: path.to_string(),
+        message,
+    });
+}
+
+fn has_type(instance: &Value, ty: &str) -> bool {
+    match ty {
+        "null" => instance.is_null(),
+        "boolean" => instance.is_boolean(),
+        "string" => instance.is_string(),
+        "array" => instance.is_array(),
+        "object" => instance.is_object(),
+        "number" => instance.is_number(),
+        "integer" => {
+            instance.is_i64()
+                || instance.is_u64()
+                || instance
+                    .as_f64()
+                    .is_some_and(|number| number.fract() == 0.0)
+        }
+        _ => true,
+    }
+}
+
+fn type_name(instance: &Value) -> &'static str {
+    match instance {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn transfer_schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "to": { "type": "string", "minLength": 32 },
+                "amount": { "type": "number", "exclusiveMinimum": 0 },
+                "memo": { "type": ["string", "null"] },
+                "legs": {
+                    "type": "array",
+                    "items": { "$ref": "#/definitions/Leg" }
+                }
+            },
+            "required": ["to", "amount"],
+            "additionalProperties": false,
+            "definitions": {
+                "Leg": {
+                    "type": "object",
+                    "properties": {
+                        "token": { "type": "string", "enum": ["SOL", "USDC"] }
+                    },
+                    "required": ["token"]
+                }
+            }
+        })
+    }
+
+    fn paths(error: ValidationError) -> Vec<String> {
+        error
+            .violations
+            .into_iter()
+            .map(|violation| violation.path)
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_arguments() {
+        let args = json!({
+            "to": "9WzDXwBbmkg8ZTbNMqUxvQRAyrZzDsGYdLVL9zYtAWWM",
+            "amount": 1.5,
+            "memo": null,
+            "legs": [{ "token": "SOL" }]
+        });
+
+        assert_eq!(validate(&transfer_schema(), &args), Ok(()));
+    }
+
+    #[test]
+    fn test_violation_paths() {
+        let args = json!({
+            "to": "short",
+            "amount": "1.5",
+            "legs": [{ "token": "SOL" }, { "token": "BONK" }, {}],
+            "fee": 1
+        });
+
+        let mut paths = paths(validate(&transfer_schema(), &args).unwrap_err());
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                "$.amount",
+                "$.fee",
+                "$.legs[1].token",
+                "$.legs[2].token",
+                "$.to"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_required_and_type_mismatch() {
+        let error = validate(&transfer_schema(), &json!({ "amount": -1 })).unwrap_err();
+        assert_eq!(paths(error.clone()), vec!["$.to", "$.amount"]);
+        assert!(error
+            .correction_prompt("transfer")
+            .contains("- $.to: missing required property"));
+
+        let error = validate(&transfer_schema(), &json!([1, 2])).unwrap_err();
+        assert_eq!(error.violations[0].message, "expected object, found array");
+    }
+
+    #[test]
+    fn test_one_of() {
+        let schema = json!({
+            "oneOf": [
+                { "type": "object", "required": ["Launch"] },
+                { "type": "object", "required": ["Rug"] }
+            ]
+        });
+
+        assert!(validate(&schema, &json!({ "Rug": {} })).is_ok());
+        assert!(validate(&schema, &json!({ "Noise": {} })).is_err());
+        assert!(validate(&schema, &json!({ "Rug": {}, "Launch": {} })).is_err());
+    }
+
+    #[test]
+    fn test_self_reference_cycle() {
+        let error = validate(&json!({ "$ref": "#" }), &json!(1)).unwrap_err();
+        assert!(error.violations[0].message.contains("nested too deeply"));
+
+        // Recursive schemas still validate nested instances
+        let tree = json!({
+            "type": "object",
+            "properties": { "children": { "type": "array", "items": { "$ref": "#" } } }
+        });
+        assert!(validate(&tree, &json!({ "children": [{ "children": [{}] }] })).is_ok());
+        assert!(validate(&tree, &json!({ "children": [{ "children": [1] }] })).is_err());
+    }
+
+    #[test]
+    fn test_exponential_schema_is_bounded() {
+        let schema = json!({ "anyOf": [{ "$ref": "#" }, { "$ref": "#" }] });
+        let error = validate(&schema, &json!(1)).unwrap_err();
+        assert_eq!(
+            error.violations[0].message,
+            "the schema is too complex to validate"
+        );
+    }
+}
@@ -1,14 +1,32 @@
 pub mod agent;
+pub mod agent_spec;
+pub mod agent_tool;
+pub mod approval;
 pub mod cli_chatbot;
+#[cfg(feature = "rhai")]
+pub mod code_exec;
 pub mod completion;
+pub mod crew;
+pub mod dynamic_tools;
 pub mod embeddings;
 pub mod extractor;
+pub mod guardrails;
+pub mod json_schema;
 pub(crate) mod json_utils;
 pub mod loaders;
+pub mod mcp;
+pub mod memory;
 pub mod one_or_many;
 pub mod pipeline;
 pub mod providers;
+pub mod replay;
+pub mod streaming;
+pub mod template;
+pub mod token_budget;
 pub mod tool;
+pub mod tool_policy;
+pub mod tool_registry;
+pub mod usage;
 pub mod vector_store;
 
 // Re-export commonly used types and traits
@@ -17,3 +35,19 @@ pub use one_or_many::{EmptyListError, OneOrMany};
 
 #[cfg(feature = "derive")]
 pub use rig_derive::Embed;
+
+#[cfg(feature = "derive")]
+pub use rig_macros::tool;
+
+// Lets the code generated by `#[tool]` refer to `::rig` from within this crate's tests
+#[cfg(all(test, feature = "derive"))]
+extern crate self as rig;
+
+// Dependencies used by the code generated by `#[tool]`, so that crates using the macro do not
+// need to depend on them directly
+#[doc(hidden)]
+pub mod __private {
+    pub use schemars;
+    pub use serde;
+    pub use serde_json;
+}
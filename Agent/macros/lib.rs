@@ -0,0 +1,48 @@
+//! Procedural macros for rig, re-exported from the main crate under the `derive` feature.
+//!
+//! # Example
+//! ```rust
+//! use rig::tool;
+//!
+//! #[derive(Debug, thiserror::Error)]
+//! #[error("Math error")]
+//! struct MathError;
+//!
+//! /// Add x and y together
+//! #[tool]
+//! async fn add(
+//!     /// The first number to add
+//!     x: i32,
+//!     /// The second number to add
+//!     y: i32,
+//! ) -> Result<i32, MathError> {
+//!     Ok(x + y)
+//! }
+//!
+//! // `Add` implements `rig::tool::Tool` and can be registered like any other tool
+//! let agent = openai.agent("gpt-4o").tool(Add).build();
+//! ```
+use proc_macro::TokenStream;
+
+mod tool;
+
+/// Turn an async function into a [`Tool`](../rig/tool/trait.Tool.html).
+///
+/// The function must be `async`, take only named arguments and return a `Result<T, E>` where
+/// `T: Serialize` and `E: std::error::Error + Send + Sync + 'static`. The macro keeps the
+/// function as is and generates next to it:
+/// - a unit struct named after the function in `PascalCase` (e.g.: `get_price` -> `GetPrice`)
+///   implementing `Tool`, with `NAME` set to the function name,
+/// - an argument struct (e.g.: `GetPriceArgs`) with one field per function argument, deriving
+///   `Deserialize` and `JsonSchema`. Its schema becomes the tool definition's `parameters`.
+///
+/// The function's doc comment is used as the tool description and the doc comments of its
+/// arguments as the descriptions of the corresponding parameters.
+///
+/// Both can be overridden with `#[tool(name = "...", description = "...")]`.
+#[proc_macro_attribute]
+pub fn tool(attr: TokenStream, item: TokenStream) -> TokenStream {
+    tool::expand(attr.into(), item.into())
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
@@ -0,0 +1,241 @@
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{
+    parse::Parser, spanned::Spanned, Attribute, Error, Expr, ExprLit, FnArg, GenericArgument,
+    ItemFn, Lit, LitStr, Meta, Pat, PathArguments, ReturnType, Type,
+};
+
+/// Options of the `#[tool(...)]` attribute
+#[derive(Default)]
+struct ToolAttr {
+    name: Option<LitStr>,
+    description: Option<LitStr>,
+}
+
+impl ToolAttr {
+    fn parse(attr: TokenStream) -> syn::Result<Self> {
+        let mut options = Self::default();
+        let parser = syn::meta::parser(|meta| {
+            if meta.path.is_ident("name") {
+                options.name = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("description") {
+                options.description = Some(meta.value()?.parse()?);
+            } else {
+                return Err(meta.error("expected `name` or `description`"));
+            }
+            Ok(())
+        });
+        parser.parse2(attr)?;
+        Ok(options)
+    }
+}
+
+pub(crate) fn expand(attr: TokenStream, item: TokenStream) -> syn::Result<TokenStream> {
+    let options = ToolAttr::parse(attr)?;
+    let mut item_fn: ItemFn = syn::parse2(item)?;
+    let sig = &item_fn.sig;
+
+    if sig.asyncness.is_none() {
+        return Err(Error::new(
+            sig.fn_token.span(),
+            "#[tool] requires an async function",
+        ));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(Error::new(
+            sig.generics.span(),
+            "#[tool] does not support generic functions",
+        ));
+    }
+    let (output, error) = result_types(&sig.output)?;
+
+    let fn_ident = sig.ident.clone();
+    let vis = item_fn.vis.clone();
+    let struct_ident = format_ident!("{}", pascal_case(&fn_ident.to_string()));
+    let args_ident = format_ident!("{}Args", struct_ident);
+
+    let name = options
+        .name
+        .unwrap_or_else(|| LitStr::new(&fn_ident.to_string(), fn_ident.span()));
+    let description = match options.description {
+        Some(description) => description,
+        None => match doc_string(&item_fn.attrs) {
+            Some(doc) => LitStr::new(&doc, Span::call_site()),
+            None => {
+                return Err(Error::new(
+                    fn_ident.span(),
+                    "#[tool] requires a doc comment or a `description` describing the tool",
+                ))
+            }
+        },
+    };
+
+    // Move the doc comments of the arguments to the fields of the argument struct, where
+    // schemars picks them up as parameter descriptions
+    let mut fields = Vec::new();
+    let mut idents = Vec::new();
+    for input in item_fn.sig.inputs.iter_mut() {
+        let FnArg::Typed(arg) = input else {
+            return Err(Error::new(input.span(), "#[tool] does not support methods"));
+        };
+        let Pat::Ident(pat) = arg.pat.as_ref() else {
+            return Err(Error::new(
+                arg.pat.span(),
+                "#[tool] arguments must be plain identifiers",
+            ));
+        };
+        let ident = &pat.ident;
+        let ty = &arg.ty;
+        let docs = arg
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("doc"))
+            .collect::<Vec<_>>();
+        fields.push(quote! {
+            #(#docs)*
+            #vis #ident: #ty
+        });
+        idents.push(ident.clone());
+        arg.attrs.retain(|attr| !attr.path().is_ident("doc"));
+    }
+
+    let args_doc = format!("Arguments of the [`{struct_ident}`] tool");
+    let struct_doc = format!("Tool calling [`{fn_ident}`]");
+
+    Ok(quote! {
+        #item_fn
+
+        #[doc = #args_doc]
+        #[derive(::rig::__private::serde::Deserialize, ::rig::__private::schemars::JsonSchema)]
+        #[serde(crate = "::rig::__private::serde")]
+        #[schemars(crate = "::rig::__private::schemars")]
+        #vis struct #args_ident {
+            #(#fields,)*
+        }
+
+        #[doc = #struct_doc]
+        #[derive(Debug, Default, Clone, Copy)]
+        #vis struct #struct_ident;
+
+        impl ::rig::tool::Tool for #struct_ident {
+            const NAME: &'static str = #name;
+            type Error = #error;
+            type Args = #args_ident;
+            type Output = #output;
+
+            async fn definition(&self, _prompt: String) -> ::rig::completion::ToolDefinition {
+                ::rig::completion::ToolDefinition {
+                    name: Self::NAME.to_string(),
+                    description: #description.to_string(),
+                    parameters: ::rig::__private::serde_json::json!(
+                        ::rig::__private::schemars::schema_for!(#args_ident)
+                    ),
+                }
+            }
+
+            async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+                let #args_ident { #(#idents),* } = args;
+                #fn_ident(#(#idents),*).await
+            }
+        }
+    })
+}
+
+/// `T` and `E` of a function returning `Result<T, E>`
+fn result_types(output: &ReturnType) -> syn::Result<(Type, Type)> {
+    let error = || {
+        Error::new(
+            output.span(),
+            "#[tool] functions must return a `Result<T, E>`",
+        )
+    };
+    let ReturnType::Type(_, ty) = output else {
+        return Err(error());
+    };
+    let Type::Path(path) = ty.as_ref() else {
+        return Err(error());
+    };
+    let segment = path.path.segments.last().ok_or_else(error)?;
+    if segment.ident != "Result" {
+        return Err(error());
+    }
+    let PathArguments::AngleBracketed(generics) = &segment.arguments else {
+        return Err(error());
+    };
+    match generics.args.iter().collect::<Vec<_>>()[..] {
+        [GenericArgument::Type(output), GenericArgument::Type(error)] => {
+            Ok((output.clone(), error.clone()))
+        }
+        _ => Err(error()),
+    }
+}
+
+/// Doc comment lines of an item joined with newlines, if any
+fn doc_string(attrs: &[Attribute]) -> Option<String> {
+    let lines = attrs
+        .iter()
+        .filter_map(|attr| match &attr.meta {
+            Meta::NameValue(meta) if meta.path.is_ident("doc") => match &meta.value {
+                Expr::Lit(ExprLit {
+                    lit: Lit::Str(doc), ..
+                }) => Some(doc.value().trim().to_string()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let doc = lines.join("\n").trim().to_string();
+    (!doc.is_empty()).then_some(doc)
+}
+
+fn pascal_case(name: &str) -> String {
+    name.split('_')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pascal_case() {
+        assert_eq!(pascal_case("get_price"), "GetPrice");
+        assert_eq!(pascal_case("add"), "Add");
+        assert_eq!(pascal_case("_private_tool"), "PrivateTool");
+    }
+
+    #[test]
+    fn test_requires_result() {
+        let err = expand(
+            TokenStream::new(),
+            quote! {
+                /// Add x and y together
+                async fn add(x: i32, y: i32) -> i32 { x + y }
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Result<T, E>"));
+    }
+
+    #[test]
+    fn test_requires_async() {
+        let err = expand(
+            TokenStream::new(),
+            quote! {
+                /// Add x and y together
+                fn add(x: i32, y: i32) -> Result<i32, MathError> { Ok(x + y) }
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("async"));
+    }
+}
@@ -0,0 +1,665 @@
+//! Interoperability with the [Model Context Protocol](https://modelcontextprotocol.io) (MCP).
+//!
+//! - [McpClient] connects to an MCP tool server (usually a child process talking over stdio)
+//!   and exposes its tools as [ToolDyn] implementations, which can be added to a [ToolSet]
+//! - [McpServer] exposes the tools of a [ToolSet] (static and embedding tools alike) to MCP
+//!   hosts, see the `mcp_server` example
+//!
+//! Messages are JSON-RPC 2.0, one per line. Only the tools capability is supported.
+//!
+//! # Example
+//! ```
+//! use Hydranta::{agent::AgentBuilder, mcp::McpClient, tool::ToolSet};
+//!
+//! let client = McpClient::spawn("npx", ["-y", "@modelcontextprotocol/server-everything"]).await?;
+//!
+//! let mut toolset = ToolSet::default();
+//! client.register_tools(&mut toolset).await?;
+//!
+//! let agent = AgentBuilder::new(model).tools(toolset).build();
+//! ```
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    future::Future,
+    pin::Pin,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    process::{Child, Command},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    completion::ToolDefinition,
+    tool::{ToolDyn, ToolError, ToolSet},
+};
+
+/// MCP protocol revision implemented by the client and server
+pub const PROTOCOL_VERSION: &str = "2024-11-05";
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const PARSE_ERROR: i64 = -32700;
+
+#[derive(Debug, thiserror::Error)]
+pub enum McpError {
+    #[error("IoError: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("JsonError: {0}")]
+    JsonError(#[from] serde_json::Error),
+
+    /// The peer answered a request with a JSON-RPC error
+    #[error("RpcError: {message} (code {code})")]
+    RpcError { code: i64, message: String },
+
+    /// The tool reported an error (`isError` result)
+    #[error("ToolError: {0}")]
+    ToolError(String),
+
+    #[error("ConnectionClosed: the MCP peer closed the connection")]
+    ConnectionClosed,
+
+    /// The toolset already has a tool with the same name as a tool of the server
+    #[error("DuplicateTool: {0}")]
+    DuplicateTool(String),
+}
+
+/// A tool as listed by an MCP server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct McpToolInfo {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub input_schema: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CallToolResult {
+    #[serde(default)]
+    content: Vec<Value>,
+    #[serde(default)]
+    is_error: bool,
+}
+
+impl CallToolResult {
+    fn text(text: String, is_error: bool) -> Self {
+        Self {
+            content: vec![json!({ "type": "text", "text": text })],
+            is_error,
+        }
+    }
+
+    /// Text of the result: text items are joined, other items are kept as JSON
+    fn into_text(self) -> String {
+        self.content
+            .into_iter()
+            .map(|item| match item.get("text").and_then(Value::as_str) {
+                Some(text) => text.to_string(),
+                None => item.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Requests waiting for their response, `None` once the server closed the connection
+type PendingRequests = Mutex<Option<HashMap<u64, oneshot::Sender<Result<Value, McpError>>>>>;
+
+/// Both ends of a JSON-RPC connection: lines are written by a dedicated task, responses are
+/// routed back to their request by the reading task. The reading task does not keep the
+/// connection alive: once it is dropped, the writing task closes the server's input.
+struct Connection {
+    outgoing: mpsc::UnboundedSender<String>,
+    pending: PendingRequests,
+    next_id: AtomicU64,
+    /// Server process, killed when the connection is dropped
+    _child: Option<Child>,
+}
+
+impl Connection {
+    fn send(&self, message: Value) -> Result<(), McpError> {
+        self.outgoing
+            .send(message.to_string())
+            .map_err(|_| McpError::ConnectionClosed)
+    }
+
+    async fn request(&self, method: &str, params: Value) -> Result<Value, McpError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(id, sender),
+            None => return Err(McpError::ConnectionClosed),
+        };
+
+        tracing::debug!(target: "rig", "MCP request {id}: {method}");
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))?;
+        receiver.await.map_err(|_| McpError::ConnectionClosed)?
+    }
+
+    fn notify(&self, method: &str, params: Value) -> Result<(), McpError> {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+    }
+
+    /// Route the messages read from the server until it closes the connection or the
+    /// connection is dropped
+    async fn read_loop(connection: Weak<Self>, reader: impl AsyncRead + Unpin) {
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let Some(connection) = connection.upgrade() else {
+                return;
+            };
+            let message: Value = match serde_json::from_str(&line) {
+                Ok(message) => message,
+                Err(error) => {
+                    tracing::warn!(target: "rig", "Ignoring invalid MCP message: {error}");
+                    continue;
+                }
+            };
+
+            match (message.get("id"), message.get("method")) {
+                // Requests from the server: only pings are supported
+                (Some(id), Some(method)) => {
+                    let response = match method.as_str() {
+                        Some("ping") => json!({ "jsonrpc": "2.0", "id": id, "result": {} }),
+                        _ => error_response(id.clone(), METHOD_NOT_FOUND, "Method not found"),
+                    };
+                    let _ = connection.send(response);
+                }
+                (Some(id), None) => {
+                    let Some(sender) = id
+                        .as_u64()
+                        .and_then(|id| connection.pending.lock().unwrap().as_mut()?.remove(&id))
+                    else {
+                        tracing::warn!(target: "rig", "Ignoring MCP response to unknown request {id}");
+                        continue;
+                    };
+                    let _ = sender.send(parse_response(message));
+                }
+                (None, _) => {
+                    tracing::debug!(target: "rig", "Ignoring MCP notification: {line}");
+                }
+            }
+        }
+
+        // Fail the requests still waiting for a response, and the ones made from now on
+        if let Some(connection) = connection.upgrade() {
+            connection.pending.lock().unwrap().take();
+        }
+    }
+}
+
+fn parse_response(mut message: Value) -> Result<Value, McpError> {
+    if let Some(error) = message.get("error") {
+        return Err(McpError::RpcError {
+            code: error["code"].as_i64().unwrap_or_default(),
+            message: error["message"].as_str().unwrap_or_default().to_string(),
+        });
+    }
+    Ok(message["result"].take())
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+/// Write the lines sent on `outgoing` until every sender is dropped
+fn spawn_writer(
+    mut writer: impl AsyncWrite + Unpin + Send + 'static,
+) -> (mpsc::UnboundedSender<String>, tokio::task::JoinHandle<()>) {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+    let task = tokio::spawn(async move {
+        while let Some(line) = receiver.recv().await {
+            let written = async {
+                writer.write_all(line.as_bytes()).await?;
+                writer.write_all(b"\n").await?;
+                writer.flush().await
+            };
+            if let Err(error) = written.await {
+                tracing::warn!(target: "rig", "Failed to write MCP message: {error}");
+                break;
+            }
+        }
+    });
+    (sender, task)
+}
+
+/// Client of an MCP server. The connection, and the server process if it was spawned by the
+/// client, stay alive as long as the client or one of its tools is alive.
+pub struct McpClient {
+    connection: Arc<Connection>,
+    server_info: Value,
+}
+
+impl McpClient {
+    /// Start the MCP server `command` and connect to it over its stdin and stdout
+    pub async fn spawn<S: AsRef<OsStr>>(
+        command: &str,
+        args: impl IntoIterator<Item = S>,
+    ) -> Result<Self, McpError> {
+        let mut child = Command::new(command)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            unreachable!("stdin and stdout are piped");
+        };
+        Self::start(stdout, stdin, Some(child)).await
+    }
+
+    /// Connect to an MCP server reading its messages from `reader` and writing to `writer`,
+    /// and run the initialization handshake
+    pub async fn connect(
+        reader: impl AsyncRead + Unpin + Send + 'static,
+        writer: impl AsyncWrite + Unpin + Send + 'static,
+    ) -> Result<Self, McpError> {
+        Self::start(reader, writer, None).await
+    }
+
+    async fn start(
+        reader: impl AsyncRead + Unpin + Send + 'static,
+        writer: impl AsyncWrite + Unpin + Send + 'static,
+        child: Option<Child>,
+    ) -> Result<Self, McpError> {
+        let (outgoing, _) = spawn_writer(writer);
+        let connection = Arc::new(Connection {
+            outgoing,
+            pending: Mutex::new(Some(HashMap::new())),
+            next_id: AtomicU64::new(1),
+            _child: child,
+        });
+        tokio::spawn(Connection::read_loop(Arc::downgrade(&connection), reader));
+
+        let result = connection
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": { "name": "hydranta", "version": env!("CARGO_PKG_VERSION") },
+                }),
+            )
+            .await?;
+        connection.notify("notifications/initialized", json!({}))?;
+
+        Ok(Self {
+            connection,
+            server_info: result["serverInfo"].clone(),
+        })
+    }
+
+    /// Name and version of the server, as reported during initialization
+    pub fn server_info(&self) -> &Value {
+        &self.server_info
+    }
+
+    /// List the tools of the server, following pagination
+    pub async fn list_tools(&self) -> Result<Vec<McpToolInfo>, McpError> {
+        let mut tools = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let mut result = self.connection.request("tools/list", params).await?;
+            tools.extend(serde_json::from_value::<Vec<McpToolInfo>>(
+                result["tools"].take(),
+            )?);
+
+            cursor = result["nextCursor"].as_str().map(str::to_string);
+            if cursor.is_none() {
+                return Ok(tools);
+            }
+        }
+    }
+
+    /// Call the tool `name`, returning its text output
+    pub async fn call_tool(&self, name: &str, arguments: Value) -> Result<String, McpError> {
+        call_tool(&self.connection, name, arguments).await
+    }
+
+    /// The tools of the server, as tools calling it
+    pub async fn tools(&self) -> Result<Vec<McpTool>, McpError> {
+        Ok(self
+            .list_tools()
+            .await?
+            .into_iter()
+            .map(|info| McpTool {
+                connection: self.connection.clone(),
+                definition: ToolDefinition {
+                    name: info.name,
+                    description: info.description,
+                    parameters: info.input_schema,
+                },
+            })
+            .collect())
+    }
+
+    /// Add the tools of the server to `toolset`. Fails without adding any tool if one of
+    /// them has the same name as a tool of `toolset`.
+    pub async fn register_tools(&self, toolset: &mut ToolSet) -> Result<(), McpError> {
+        let tools = self.tools().await?;
+        if let Some(tool) = tools.iter().find(|tool| toolset.contains(&tool.name())) {
+            return Err(McpError::DuplicateTool(tool.name()));
+        }
+        for tool in tools {
+            toolset.add_tool(tool);
+        }
+        Ok(())
+    }
+}
+
+async fn call_tool(
+    connection: &Connection,
+    name: &str,
+    arguments: Value,
+) -> Result<String, McpError> {
+    let result = connection
+        .request(
+            "tools/call",
+            json!({ "name": name, "arguments": arguments }),
+        )
+        .await?;
+    let result: CallToolResult = serde_json::from_value(result)?;
+
+    match result.is_error {
+        true => Err(McpError::ToolError(result.into_text())),
+        false => Ok(result.into_text()),
+    }
+}
+
+/// A tool of an MCP server. The connection (and the server process, if the client spawned it)
+/// stays open as long as one of its tools is alive.
+pub struct McpTool {
+    connection: Arc<Connection>,
+    definition: ToolDefinition,
+}
+
+impl ToolDyn for McpTool {
+    fn name(&self) -> String {
+        self.definition.name.clone()
+    }
+
+    fn definition(
+        &self,
+        _prompt: String,
+    ) -> Pin<Box<dyn Future<Output = ToolDefinition> + Send + Sync + '_>> {
+        Box::pin(futures::future::ready(self.definition.clone()))
+    }
+
+    fn call(
+        &self,
+        args: String,
+    ) -> Pin<Box<dyn Future<Output = Result<String, ToolError>> + Send + Sync + '_>> {
+        Box::pin(async move {
+            let arguments: Value = serde_json::from_str(&args)?;
+            call_tool(&self.connection, &self.definition.name, arguments)
+                .await
+                .map_err(|error| ToolError::ToolCallError(Box::new(error)))
+        })
+    }
+}
+
+/// MCP server exposing the tools of a [ToolSet]. Tool calls go through [ToolSet::call], so
+/// the toolset's policies and approvals apply.
+pub struct McpServer {
+    name: String,
+    version: String,
+    toolset: Arc<ToolSet>,
+}
+
+impl McpServer {
+    pub fn new(name: &str, version: &str, toolset: ToolSet) -> Self {
+        Self {
+            name: name.to_string(),
+            version: version.to_string(),
+            toolset: Arc::new(toolset),
+        }
+    }
+
+    /// Serve over the process' stdin and stdout until stdin is closed
+    pub async fn serve_stdio(&self) -> Result<(), McpError> {
+        self.serve(tokio::io::stdin(), tokio::io::stdout()).await
+    }
+
+    /// Serve the requests read from `reader`, writing the responses to `writer`, until
+    /// `reader` is closed. Requests are handled concurrently.
+    pub async fn serve(
+        &self,
+        reader: impl AsyncRead + Unpin,
+        writer: impl AsyncWrite + Unpin + Send + 'static,
+    ) -> Result<(), McpError> {
+        let (outgoing, writer_task) = spawn_writer(writer);
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            let message: Value = match serde_json::from_str(&line) {
+                Ok(message) => message,
+                Err(error) => {
+                    let _ = outgoing.send(
+                        error_response(Value::Null, PARSE_ERROR, &error.to_string()).to_string(),
+                    );
+                    continue;
+                }
+            };
+            // Notifications (e.g.: `notifications/initialized`) need no response
+            let Some(id) = message.get("id").cloned() else {
+                continue;
+            };
+
+            let method = message["method"].as_str().unwrap_or_default().to_string();
+            let params = message.get("params").cloned().unwrap_or_default();
+            let server_info = json!({ "name": self.name, "version": self.version });
+            let toolset = self.toolset.clone();
+            let outgoing = outgoing.clone();
+
+            tokio::spawn(async move {
+                let response = match handle_request(&toolset, server_info, &method, params).await {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code, message)) => error_response(id, code, &message),
+                };
+                let _ = outgoing.send(response.to_string());
+            });
+        }
+
+        drop(outgoing);
+        let _ = writer_task.await;
+        Ok(())
+    }
+}
+
+async fn handle_request(
+    toolset: &ToolSet,
+    server_info: Value,
+    method: &str,
+    params: Value,
+) -> Result<Value, (i64, String)> {
+    tracing::debug!(target: "rig", "MCP server request: {method}");
+    match method {
+        "initialize" => Ok(json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": { "tools": {} },
+            "serverInfo": server_info,
+        })),
+        "ping" => Ok(json!({})),
+        "tools/list" => {
+            let mut tools = vec![];
+            for tool in toolset.tools.values() {
+                let definition = tool.definition(String::new()).await;
+                tools.push(McpToolInfo {
+                    name: definition.name,
+                    description: definition.description,
+                    input_schema: definition.parameters,
+                });
+            }
+            tools.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(json!({ "tools": tools }))
+        }
+        "tools/call" => {
+            let Some(name) = params["name"].as_str() else {
+                return Err((INVALID_PARAMS, "Missing tool name".into()));
+            };
+            if !toolset.contains(name) {
+                return Err((INVALID_PARAMS, format!("Unknown tool: {name}")));
+            }
+            let arguments = match &params["arguments"] {
+                Value::Null => json!({}),
+                arguments => arguments.clone(),
+            };
+
+            // Tool failures are results, so the model can see them
+            let result = match toolset.call(name, arguments.to_string()).await {
+                Ok(output) => CallToolResult::text(output, false),
+                Err(error) => CallToolResult::text(error.to_string(), true),
+            };
+            Ok(json!(result))
+        }
+        _ => Err((METHOD_NOT_FOUND, format!("Method not found: {method}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::tests::Adder;
+
+    /// Stand-in MCP server serving `toolset` over in-memory pipes, in place of a child
+    /// process' stdio
+    async fn connect(toolset: ToolSet) -> McpClient {
+        let (client_stdin, server_stdin) = tokio::io::duplex(4096);
+        let (server_stdout, client_stdout) = tokio::io::duplex(4096);
+
+        tokio::spawn(async move {
+            McpServer::new("stand-in", "0.1.0", toolset)
+                .serve(server_stdin, server_stdout)
+                .await
+                .unwrap();
+        });
+        McpClient::connect(client_stdout, client_stdin)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_client_registers_server_tools() {
+        let client = connect(ToolSet::from_tools(vec![Adder])).await;
+        assert_eq!(client.server_info()["name"], "stand-in");
+
+        let mut toolset = ToolSet::default();
+        client.register_tools(&mut toolset).await.unwrap();
+        assert!(toolset.contains("add"));
+        assert_eq!(
+            toolset
+                .call("add", r#"{"x": 40, "y": 2}"#.into())
+                .await
+                .unwrap(),
+            "42"
+        );
+
+        assert!(matches!(
+            client.register_tools(&mut toolset).await,
+            Err(McpError::DuplicateTool(name)) if name == "add"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_reported() {
+        let client = connect(ToolSet::from_tools(vec![Adder])).await;
+
+        let tools = client.list_tools().await.unwrap();
+        assert_eq!(tools[0].input_schema["required"], json!(["x", "y"]));
+
+        // Invalid arguments are a tool error, unknown tools and methods a protocol error
+        assert!(matches!(
+            client.call_tool("add", json!({"x": "one"})).await,
+            Err(McpError::ToolError(message)) if message.contains("ValidationError")
+        ));
+        assert!(matches!(
+            client.call_tool("sub", json!({"x": 1, "y": 2})).await,
+            Err(McpError::RpcError {
+                code: INVALID_PARAMS,
+                ..
+            })
+        ));
+        assert!(matches!(
+            client.connection.request("resources/list", json!({})).await,
+            Err(McpError::RpcError {
+                code: METHOD_NOT_FOUND,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tools_keep_the_connection_alive() {
+        let (client_stdin, server_stdin) = tokio::io::duplex(4096);
+        let (server_stdout, client_stdout) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            McpServer::new("stand-in", "0.1.0", ToolSet::from_tools(vec![Adder]))
+                .serve(server_stdin, server_stdout)
+                .await
+        });
+
+        let client = McpClient::connect(client_stdout, client_stdin)
+            .await
+            .unwrap();
+        let tools = client.tools().await.unwrap();
+        drop(client);
+        assert_eq!(
+            tools[0].call(r#"{"x": 1, "y": 2}"#.into()).await.unwrap(),
+            "3"
+        );
+
+        // The server sees the end of its input once the last tool is dropped
+        drop(tools);
+        tokio::time::timeout(std::time::Duration::from_secs(1), server)
+            .await
+            .expect("the server should stop")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_calls_fail_once_the_server_is_gone() {
+        let (client_stdin, server_stdin) = tokio::io::duplex(4096);
+        let (server_stdout, client_stdout) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            McpServer::new("stand-in", "0.1.0", ToolSet::from_tools(vec![Adder]))
+                .serve(server_stdin, server_stdout)
+                .await
+        });
+
+        let client = McpClient::connect(client_stdout, client_stdin)
+            .await
+            .unwrap();
+        let tools = client.tools().await.unwrap();
+        server.abort();
+        let _ = server.await;
+
+        let call = tools[0].call(r#"{"x": 1, "y": 2}"#.into());
+        let error = tokio::time::timeout(std::time::Duration::from_secs(1), call)
+            .await
+            .expect("the call should not hang")
+            .unwrap_err();
+        assert!(error.to_string().contains("ConnectionClosed"));
+        assert!(matches!(
+            client.call_tool("add", json!({"x": 1, "y": 2})).await,
+            Err(McpError::ConnectionClosed)
+        ));
+    }
+}
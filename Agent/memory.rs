@@ -0,0 +1,955 @@
+//! Conversation memory for agents.
+//!
+//! A [ConversationMemory] stores the messages of conversations, identified by an id, so they
+//! survive across prompts and processes. An [AgentMemory] combines a backend with a
+//! [MemoryStrategy] deciding which part of a (possibly long) conversation is sent to the model.
+//!
+//! # Example
+//! ```
+//! use Hydranta::memory::{JsonFileMemory, MemoryStrategy};
+//!
+//! let agent = AgentBuilder::new(model)
+//!     .preamble("You are a helpful assistant.")
+//!     .memory(JsonFileMemory::new("./conversations"), MemoryStrategy::KeepLast(20))
+//!     .build();
+//!
+//! let answer = agent.converse("user-42", "What did I ask you yesterday?").await?;
+//! ```
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    completion::{Message, Prompt, PromptError},
+    token_budget::{ApproxTokenizer, ContextBudget},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum MemoryError {
+    #[error("IoError: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("JsonError: {0}")]
+    JsonError(#[from] serde_json::Error),
+
+    #[error("DatabaseError: {0}")]
+    DatabaseError(Box<dyn std::error::Error + Send + Sync + 'static>),
+
+    #[error("SummarizationError: {0}")]
+    SummarizationError(#[from] PromptError),
+}
+
+/// Error returned by [Agent::converse](crate::agent::Agent::converse)
+#[derive(Debug, thiserror::Error)]
+pub enum ConversationError {
+    #[error("MemoryError: {0}")]
+    MemoryError(#[from] MemoryError),
+
+    #[error("PromptError: {0}")]
+    PromptError(#[from] PromptError),
+}
+
+/// Storage backend for conversation histories
+pub trait ConversationMemory: Send + Sync {
+    /// Load the messages of a conversation, oldest first. Unknown conversations are empty.
+    fn load<'a>(
+        &'a self,
+        conversation_id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Message>, MemoryError>>;
+
+    /// Append messages at the end of a conversation
+    fn append<'a>(
+        &'a self,
+        conversation_id: &'a str,
+        messages: Vec<Message>,
+    ) -> BoxFuture<'a, Result<(), MemoryError>>;
+
+    /// Replace all the messages of a conversation
+    fn replace<'a>(
+        &'a self,
+        conversation_id: &'a str,
+        messages: Vec<Message>,
+    ) -> BoxFuture<'a, Result<(), MemoryError>>;
+
+    /// Delete a conversation
+    fn clear<'a>(&'a self, conversation_id: &'a str) -> BoxFuture<'a, Result<(), MemoryError>> {
+        self.replace(conversation_id, vec![])
+    }
+
+    /// Load the summary kept for a conversation by [MemoryStrategy::Summarize], if any.
+    /// Summaries are stored apart from the conversations, so no conversation id can clash
+    /// with them.
+    fn load_summary<'a>(
+        &'a self,
+        conversation_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, MemoryError>>;
+
+    /// Store the summary kept for a conversation, replacing the previous one
+    fn save_summary<'a>(
+        &'a self,
+        conversation_id: &'a str,
+        summary: String,
+    ) -> BoxFuture<'a, Result<(), MemoryError>>;
+}
+
+impl<T: ConversationMemory + ?Sized> ConversationMemory for Arc<T> {
+    fn load<'a>(
+        &'a self,
+        conversation_id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Message>, MemoryError>> {
+        (**self).load(conversation_id)
+    }
+
+    fn append<'a>(
+        &'a self,
+        conversation_id: &'a str,
+        messages: Vec<Message>,
+    ) -> BoxFuture<'a, Result<(), MemoryError>> {
+        (**self).append(conversation_id, messages)
+    }
+
+    fn replace<'a>(
+        &'a self,
+        conversation_id: &'a str,
+        messages: Vec<Message>,
+    ) -> BoxFuture<'a, Result<(), MemoryError>> {
+        (**self).replace(conversation_id, messages)
+    }
+
+    fn clear<'a>(&'a self, conversation_id: &'a str) -> BoxFuture<'a, Result<(), MemoryError>> {
+        (**self).clear(conversation_id)
+    }
+
+    fn load_summary<'a>(
+        &'a self,
+        conversation_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, MemoryError>> {
+        (**self).load_summary(conversation_id)
+    }
+
+    fn save_summary<'a>(
+        &'a self,
+        conversation_id: &'a str,
+        summary: String,
+    ) -> BoxFuture<'a, Result<(), MemoryError>> {
+        (**self).save_summary(conversation_id, summary)
+    }
+}
+
+/// Memory backend keeping conversations in process memory
+#[derive(Default)]
+pub struct InMemoryMemory {
+    conversations: Mutex<HashMap<String, Vec<Message>>>,
+    summaries: Mutex<HashMap<String, String>>,
+}
+
+impl ConversationMemory for InMemoryMemory {
+    fn load<'a>(
+        &'a self,
+        conversation_id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Message>, MemoryError>> {
+        let messages = self
+            .conversations
+            .lock()
+            .unwrap()
+            .get(conversation_id)
+            .cloned()
+            .unwrap_or_default();
+        Box::pin(async move { Ok(messages) })
+    }
+
+    fn append<'a>(
+        &'a self,
+        conversation_id: &'a str,
+        messages: Vec<Message>,
+    ) -> BoxFuture<'a, Result<(), MemoryError>> {
+        self.conversations
+            .lock()
+            .unwrap()
+            .entry(conversation_id.to_string())
+            .or_default()
+            .extend(messages);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn replace<'a>(
+        &'a self,
+        conversation_id: &'a str,
+        messages: Vec<Message>,
+    ) -> BoxFuture<'a, Result<(), MemoryError>> {
+        self.conversations
+            .lock()
+            .unwrap()
+            .insert(conversation_id.to_string(), messages);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn load_summary<'a>(
+        &'a self,
+        conversation_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, MemoryError>> {
+        let summary = self.summaries.lock().unwrap().get(conversation_id).cloned();
+        Box::pin(async move { Ok(summary) })
+    }
+
+    fn save_summary<'a>(
+        &'a self,
+        conversation_id: &'a str,
+        summary: String,
+    ) -> BoxFuture<'a, Result<(), MemoryError>> {
+        self.summaries
+            .lock()
+            .unwrap()
+            .insert(conversation_id.to_string(), summary);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Memory backend storing each conversation as a JSON file in a directory
+pub struct JsonFileMemory {
+    directory: PathBuf,
+    /// Serializes read-modify-write cycles on the files
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl JsonFileMemory {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    fn path(&self, conversation_id: &str) -> PathBuf {
+        self.directory
+            .join(format!("{}.json", Self::file_name(conversation_id)))
+    }
+
+    /// Summaries go to `<file name>.summary.json`: encoded ids never contain a `.`, so this
+    /// cannot be the file of a conversation
+    fn summary_path(&self, conversation_id: &str) -> PathBuf {
+        self.directory
+            .join(format!("{}.summary.json", Self::file_name(conversation_id)))
+    }
+
+    fn file_name(conversation_id: &str) -> String {
+        // Conversation ids are used as file names: percent-encode every byte outside of
+        // `[A-Za-z0-9_-]` so ids cannot escape the directory, and distinct ids never share
+        // a file
+        conversation_id
+            .bytes()
+            .map(|byte| {
+                if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+                    (byte as char).to_string()
+                } else {
+                    format!("%{byte:02X}")
+                }
+            })
+            .collect()
+    }
+
+    async fn read(&self, conversation_id: &str) -> Result<Vec<Message>, MemoryError> {
+        match tokio::fs::read(self.path(conversation_id)).await {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn write(&self, conversation_id: &str, messages: &[Message]) -> Result<(), MemoryError> {
+        tokio::fs::create_dir_all(&self.directory).await?;
+        let bytes = serde_json::to_vec_pretty(messages)?;
+        Ok(tokio::fs::write(self.path(conversation_id), bytes).await?)
+    }
+}
+
+impl ConversationMemory for JsonFileMemory {
+    fn load<'a>(
+        &'a self,
+        conversation_id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Message>, MemoryError>> {
+        Box::pin(async move {
+            let _guard = self.lock.lock().await;
+            self.read(conversation_id).await
+        })
+    }
+
+    fn append<'a>(
+        &'a self,
+        conversation_id: &'a str,
+        messages: Vec<Message>,
+    ) -> BoxFuture<'a, Result<(), MemoryError>> {
+        Box::pin(async move {
+            let _guard = self.lock.lock().await;
+            let mut history = self.read(conversation_id).await?;
+            history.extend(messages);
+            self.write(conversation_id, &history).await
+        })
+    }
+
+    fn replace<'a>(
+        &'a self,
+        conversation_id: &'a str,
+        messages: Vec<Message>,
+    ) -> BoxFuture<'a, Result<(), MemoryError>> {
+        Box::pin(async move {
+            let _guard = self.lock.lock().await;
+            self.write(conversation_id, &messages).await
+        })
+    }
+
+    fn clear<'a>(&'a self, conversation_id: &'a str) -> BoxFuture<'a, Result<(), MemoryError>> {
+        Box::pin(async move {
+            let _guard = self.lock.lock().await;
+            match tokio::fs::remove_file(self.path(conversation_id)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn load_summary<'a>(
+        &'a self,
+        conversation_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, MemoryError>> {
+        Box::pin(async move {
+            let _guard = self.lock.lock().await;
+            match tokio::fs::read_to_string(self.summary_path(conversation_id)).await {
+                Ok(summary) => Ok(Some(summary)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn save_summary<'a>(
+        &'a self,
+        conversation_id: &'a str,
+        summary: String,
+    ) -> BoxFuture<'a, Result<(), MemoryError>> {
+        Box::pin(async move {
+            let _guard = self.lock.lock().await;
+            tokio::fs::create_dir_all(&self.directory).await?;
+            Ok(tokio::fs::write(self.summary_path(conversation_id), summary).await?)
+        })
+    }
+}
+
+/// Memory backend storing conversations in a SQLite database
+#[cfg(feature = "sqlite")]
+pub struct SqliteMemory {
+    connection: Arc<Mutex<rusqlite::Connection>>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteMemory {
+    /// Open (or create) the database at `path`
+    pub fn open(path: impl AsRef<std::path::Path>) -> Result<Self, MemoryError> {
+        Self::init(rusqlite::Connection::open(path).map_err(database_error)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, MemoryError> {
+        Self::init(rusqlite::Connection::open_in_memory().map_err(database_error)?)
+    }
+
+    fn init(connection: rusqlite::Connection) -> Result<Self, MemoryError> {
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS messages (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    conversation_id TEXT NOT NULL,
+                    role TEXT NOT NULL,
+                    content TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS messages_by_conversation
+                    ON messages (conversation_id, id);
+                CREATE TABLE IF NOT EXISTS summaries (
+                    conversation_id TEXT PRIMARY KEY,
+                    summary TEXT NOT NULL
+                );",
+            )
+            .map_err(database_error)?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Run a blocking database operation off the async runtime
+    async fn run<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce(&mut rusqlite::Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> Result<T, MemoryError> {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || operation(&mut connection.lock().unwrap()))
+            .await
+            .map_err(database_error)?
+            .map_err(database_error)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn database_error(e: impl std::error::Error + Send + Sync + 'static) -> MemoryError {
+    MemoryError::DatabaseError(Box::new(e))
+}
+
+#[cfg(feature = "sqlite")]
+fn insert_messages(
+    transaction: &rusqlite::Transaction,
+    conversation_id: &str,
+    messages: &[Message],
+) -> rusqlite::Result<()> {
+    let mut statement = transaction
+        .prepare("INSERT INTO messages (conversation_id, role, content) VALUES (?1, ?2, ?3)")?;
+    for message in messages {
+        statement.execute(rusqlite::params![
+            conversation_id,
+            message.role,
+            message.content
+        ])?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+impl ConversationMemory for SqliteMemory {
+    fn load<'a>(
+        &'a self,
+        conversation_id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<Message>, MemoryError>> {
+        let conversation_id = conversation_id.to_string();
+        Box::pin(self.run(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT role, content FROM messages WHERE conversation_id = ?1 ORDER BY id",
+            )?;
+            let messages = statement
+                .query_map([&conversation_id], |row| {
+                    Ok(Message {
+                        role: row.get(0)?,
+                        content: row.get(1)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>();
+            messages
+        }))
+    }
+
+    fn append<'a>(
+        &'a self,
+        conversation_id: &'a str,
+        messages: Vec<Message>,
+    ) -> BoxFuture<'a, Result<(), MemoryError>> {
+        let conversation_id = conversation_id.to_string();
+        Box::pin(self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            insert_messages(&transaction, &conversation_id, &messages)?;
+            transaction.commit()
+        }))
+    }
+
+    fn replace<'a>(
+        &'a self,
+        conversation_id: &'a str,
+        messages: Vec<Message>,
+    ) -> BoxFuture<'a, Result<(), MemoryError>> {
+        let conversation_id = conversation_id.to_string();
+        Box::pin(self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "DELETE FROM messages WHERE conversation_id = ?1",
+                [&conversation_id],
+            )?;
+            insert_messages(&transaction, &conversation_id, &messages)?;
+            transaction.commit()
+        }))
+    }
+
+    fn load_summary<'a>(
+        &'a self,
+        conversation_id: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, MemoryError>> {
+        let conversation_id = conversation_id.to_string();
+        Box::pin(self.run(move |connection| {
+            use rusqlite::OptionalExtension;
+
+            connection
+                .query_row(
+                    "SELECT summary FROM summaries WHERE conversation_id = ?1",
+                    [&conversation_id],
+                    |row| row.get(0),
+                )
+                .optional()
+        }))
+    }
+
+    fn save_summary<'a>(
+        &'a self,
+        conversation_id: &'a str,
+        summary: String,
+    ) -> BoxFuture<'a, Result<(), MemoryError>> {
+        let conversation_id = conversation_id.to_string();
+        Box::pin(self.run(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO summaries (conversation_id, summary) VALUES (?1, ?2)",
+                [&conversation_id, &summary],
+            )?;
+            Ok(())
+        }))
+    }
+}
+
+/// Summarizes the older part of a conversation. Implemented for every [Prompt] type,
+/// so any agent can be used as a summarizer.
+pub trait Summarizer: Send + Sync {
+    fn summarize<'a>(
+        &'a self,
+        messages: &'a [Message],
+    ) -> BoxFuture<'a, Result<String, PromptError>>;
+}
+
+impl<P: Prompt> Summarizer for P {
+    fn summarize<'a>(
+        &'a self,
+        messages: &'a [Message],
+    ) -> BoxFuture<'a, Result<String, PromptError>> {
+        Box::pin(async move {
+            let transcript = messages
+                .iter()
+                .map(|message| format!("{}: {}", message.role, message.content))
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            self.prompt(&format!(
+                "Summarize the following conversation in a few sentences. \
+                Keep every fact, decision and open question that may matter later.\n\n{transcript}"
+            ))
+            .await
+        })
+    }
+}
+
+/// Which part of a stored conversation is sent to the model
+pub enum MemoryStrategy {
+    /// Send the whole conversation
+    Full,
+    /// Send the last N messages
+    KeepLast(usize),
+    /// Send the most recent messages fitting in the given number of tokens
+    TokenBudget(usize),
+    /// Send a summary written by `summarizer` followed by the last `keep_last` messages.
+    /// The summary is stored apart from the conversation (see
+    /// [ConversationMemory::save_summary]), which is kept intact. It is updated with the messages falling out of the window only,
+    /// so each message is summarized once.
+    Summarize {
+        keep_last: usize,
+        summarizer: Box<dyn Summarizer>,
+    },
+}
+
+/// Prefix of the message carrying the summary of older messages
+const SUMMARY_PREFIX: &str = "Summary of the earlier conversation:\n";
+
+/// Summary of the first `covered` messages of a conversation, stored as JSON with
+/// [ConversationMemory::save_summary]
+#[derive(Serialize, Deserialize)]
+struct StoredSummary {
+    covered: usize,
+    summary: String,
+}
+
+fn summary_message(summary: &str) -> Message {
+    Message {
+        role: "user".into(),
+        content: format!("{SUMMARY_PREFIX}{summary}"),
+    }
+}
+
+/// A [ConversationMemory] backend with the [MemoryStrategy] used to build chat histories
+pub struct AgentMemory {
+    backend: Box<dyn ConversationMemory>,
+    strategy: MemoryStrategy,
+}
+
+impl AgentMemory {
+    pub fn new(backend: impl ConversationMemory + 'static, strategy: MemoryStrategy) -> Self {
+        Self {
+            backend: Box::new(backend),
+            strategy,
+        }
+    }
+
+    pub fn backend(&self) -> &dyn ConversationMemory {
+        self.backend.as_ref()
+    }
+
+    /// Load a conversation and apply the strategy to it, returning the chat history to send
+    pub async fn history(&self, conversation_id: &str) -> Result<Vec<Message>, MemoryError> {
+        let mut messages = self.backend.load(conversation_id).await?;
+
+        match &self.strategy {
+            MemoryStrategy::Full => Ok(messages),
+            MemoryStrategy::KeepLast(n) => {
+                let start = messages.len().saturating_sub(*n);
+                Ok(messages.split_off(start))
+            }
+            MemoryStrategy::TokenBudget(budget) => {
+                let counter = ContextBudget::new(*budget, ApproxTokenizer::default());
+                let mut used = 0;
+                let kept = messages
+                    .iter()
+                    .rev()
+                    .take_while(|message| {
+                        used += counter.count_message(message);
+                        used <= *budget
+                    })
+                    .count();
+                Ok(messages.split_off(messages.len() - kept))
+            }
+            MemoryStrategy::Summarize {
+                keep_last,
+                summarizer,
+            } => {
+                let mut stored = match self.backend.load_summary(conversation_id).await? {
+                    Some(summary) => Some(serde_json::from_str::<StoredSummary>(&summary)?),
+                    None => None,
+                };
+                // The conversation was cleared or shortened since: start over
+                if stored
+                    .as_ref()
+                    .is_some_and(|stored| stored.covered > messages.len())
+                {
+                    stored = None;
+                }
+
+                let covered = stored.as_ref().map_or(0, |stored| stored.covered);
+                let start = messages.len().saturating_sub(*keep_last);
+                if start > covered {
+                    tracing::debug!(target: "rig",
+                        "Summarizing {} messages of conversation {conversation_id}",
+                        start - covered
+                    );
+                    // Fold the messages falling out of the window into the previous summary
+                    let mut older = stored
+                        .map(|stored| summary_message(&stored.summary))
+                        .into_iter()
+                        .collect::<Vec<_>>();
+                    older.extend_from_slice(&messages[covered..start]);
+
+                    let summary = StoredSummary {
+                        covered: start,
+                        summary: summarizer.summarize(&older).await?,
+                    };
+                    self.backend
+                        .save_summary(conversation_id, serde_json::to_string(&summary)?)
+                        .await?;
+                    stored = Some(summary);
+                }
+
+                let Some(stored) = stored else {
+                    return Ok(messages);
+                };
+                let mut history = vec![summary_message(&stored.summary)];
+                history.extend(messages.split_off(stored.covered));
+                Ok(history)
+            }
+        }
+    }
+
+    /// Record a prompt and the agent's answer at the end of a conversation
+    pub async fn record(
+        &self,
+        conversation_id: &str,
+        prompt: &str,
+        response: &str,
+    ) -> Result<(), MemoryError> {
+        self.backend
+            .append(
+                conversation_id,
+                vec![
+                    Message {
+                        role: "user".into(),
+                        content: prompt.into(),
+                    },
+                    Message {
+                        role: "assistant".into(),
+                        content: response.into(),
+                    },
+                ],
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::{tests::ScriptedModel, AgentBuilder},
+        completion::ModelChoice,
+    };
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.into(),
+            content: content.into(),
+        }
+    }
+
+    fn contents(messages: &[Message]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect()
+    }
+
+    fn conversation() -> Vec<Message> {
+        vec![
+            message("user", "Hi"),
+            message("assistant", "Hello!"),
+            message("user", "What is the price of SOL?"),
+            message("assistant", "About 200 USDC."),
+        ]
+    }
+
+    /// Summarizer counting the lines of the transcripts it is given, and recording them
+    #[derive(Clone, Default)]
+    struct MockSummarizer(Arc<Mutex<Vec<String>>>);
+
+    impl Prompt for MockSummarizer {
+        async fn prompt(&self, prompt: &str) -> Result<String, PromptError> {
+            self.0.lock().unwrap().push(prompt.to_string());
+            Ok(format!("{} lines", prompt.lines().count() - 2))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keep_last() {
+        let backend = InMemoryMemory::default();
+        backend.append("c", conversation()).await.unwrap();
+        let memory = AgentMemory::new(backend, MemoryStrategy::KeepLast(2));
+
+        assert_eq!(
+            contents(&memory.history("c").await.unwrap()),
+            contents(&conversation()[2..])
+        );
+        assert!(memory.history("unknown").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_token_budget() {
+        let backend = InMemoryMemory::default();
+        backend.append("c", conversation()).await.unwrap();
+        let memory = AgentMemory::new(backend, MemoryStrategy::TokenBudget(12));
+
+        // "About 200 USDC." is 4 + 4 tokens, "What is the price of SOL?" is 7 + 4
+        assert_eq!(
+            contents(&memory.history("c").await.unwrap()),
+            contents(&conversation()[3..])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_summarize_keeps_summary_separate() {
+        let backend = Arc::new(InMemoryMemory::default());
+        backend.append("c", conversation()).await.unwrap();
+        let summarizer = MockSummarizer::default();
+        let memory = AgentMemory::new(
+            backend.clone(),
+            MemoryStrategy::Summarize {
+                keep_last: 2,
+                summarizer: Box::new(summarizer.clone()),
+            },
+        );
+
+        let history = memory.history("c").await.unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].content, format!("{SUMMARY_PREFIX}2 lines"));
+        assert_eq!(contents(&history[1..]), contents(&conversation()[2..]));
+        // The conversation itself is left intact
+        assert_eq!(
+            contents(&backend.load("c").await.unwrap()),
+            contents(&conversation())
+        );
+
+        // Nothing new to summarize
+        assert_eq!(
+            contents(&memory.history("c").await.unwrap()),
+            contents(&history)
+        );
+        assert_eq!(summarizer.0.lock().unwrap().len(), 1);
+
+        // Only the messages falling out of the window are added to the summary
+        backend
+            .append(
+                "c",
+                vec![message("user", "Thanks"), message("assistant", "Bye")],
+            )
+            .await
+            .unwrap();
+        let history = memory.history("c").await.unwrap();
+        assert_eq!(contents(&history[1..]), vec!["Thanks", "Bye"]);
+
+        let prompts = summarizer.0.lock().unwrap();
+        assert_eq!(prompts.len(), 2);
+        assert!(prompts[1].contains(&format!("{SUMMARY_PREFIX}2 lines")));
+        assert!(prompts[1].contains("What is the price of SOL?"));
+        assert!(!prompts[1].contains("Hello!"));
+    }
+
+    #[tokio::test]
+    async fn test_summary_does_not_clash_with_conversations() {
+        let backend = Arc::new(InMemoryMemory::default());
+        backend.append("c", conversation()).await.unwrap();
+        backend
+            .append("c#summary", vec![message("user", "Not a summary")])
+            .await
+            .unwrap();
+        let memory = AgentMemory::new(
+            backend.clone(),
+            MemoryStrategy::Summarize {
+                keep_last: 2,
+                summarizer: Box::new(MockSummarizer::default()),
+            },
+        );
+
+        memory.history("c").await.unwrap();
+        assert_eq!(
+            contents(&backend.load("c#summary").await.unwrap()),
+            vec!["Not a summary"]
+        );
+        assert_eq!(
+            contents(&memory.history("c#summary").await.unwrap()),
+            vec!["Not a summary"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_file_memory() {
+        let directory = std::env::temp_dir().join(format!("rig-memory-{}", std::process::id()));
+        let memory = JsonFileMemory::new(&directory);
+
+        memory.append("../c 1", conversation()).await.unwrap();
+        assert_eq!(
+            contents(&memory.load("../c 1").await.unwrap()),
+            contents(&conversation())
+        );
+        assert!(directory.join("%2E%2E%2Fc%201.json").exists());
+
+        // Ids differing only by characters outside of `[A-Za-z0-9_-]` do not collide
+        assert!(memory.load("../c_1").await.unwrap().is_empty());
+
+        memory.clear("../c 1").await.unwrap();
+        assert!(memory.load("../c 1").await.unwrap().is_empty());
+
+        memory.save_summary("c", "summary".into()).await.unwrap();
+        assert_eq!(
+            memory.load_summary("c").await.unwrap().as_deref(),
+            Some("summary")
+        );
+        assert!(directory.join("c.summary.json").exists());
+        assert!(memory.load("c.summary").await.unwrap().is_empty());
+        assert!(memory.load_summary("c.summary").await.unwrap().is_none());
+
+        tokio::fs::remove_dir_all(directory).await.unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_memory() {
+        let memory = SqliteMemory::open_in_memory().unwrap();
+
+        memory
+            .append("c", conversation()[..2].to_vec())
+            .await
+            .unwrap();
+        memory
+            .append("c", conversation()[2..].to_vec())
+            .await
+            .unwrap();
+        memory
+            .append("d", vec![message("user", "Hey")])
+            .await
+            .unwrap();
+        assert_eq!(
+            contents(&memory.load("c").await.unwrap()),
+            contents(&conversation())
+        );
+        assert_eq!(contents(&memory.load("d").await.unwrap()), vec!["Hey"]);
+
+        memory
+            .replace("c", vec![message("user", "Again")])
+            .await
+            .unwrap();
+        assert_eq!(contents(&memory.load("c").await.unwrap()), vec!["Again"]);
+
+        memory.clear("c").await.unwrap();
+        assert!(memory.load("c").await.unwrap().is_empty());
+        assert_eq!(contents(&memory.load("d").await.unwrap()), vec!["Hey"]);
+
+        assert!(memory.load_summary("c").await.unwrap().is_none());
+        memory.save_summary("c", "first".into()).await.unwrap();
+        memory.save_summary("c", "second".into()).await.unwrap();
+        assert_eq!(
+            memory.load_summary("c").await.unwrap().as_deref(),
+            Some("second")
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_memory_summarize() {
+        let backend = Arc::new(SqliteMemory::open_in_memory().unwrap());
+        backend.append("c", conversation()).await.unwrap();
+        let summarizer = MockSummarizer::default();
+        let memory = AgentMemory::new(
+            backend.clone(),
+            MemoryStrategy::Summarize {
+                keep_last: 2,
+                summarizer: Box::new(summarizer.clone()),
+            },
+        );
+
+        let history = memory.history("c").await.unwrap();
+        assert_eq!(history[0].content, format!("{SUMMARY_PREFIX}2 lines"));
+        assert_eq!(contents(&history[1..]), contents(&conversation()[2..]));
+        assert_eq!(
+            contents(&backend.load("c").await.unwrap()),
+            contents(&conversation())
+        );
+
+        // The summary is read back from the database
+        assert_eq!(
+            contents(&memory.history("c").await.unwrap()),
+            contents(&history)
+        );
+        assert_eq!(summarizer.0.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_agent_converse() {
+        let model = ScriptedModel::new([
+            ModelChoice::Message("Hello!".into()),
+            ModelChoice::Message("You said hi.".into()),
+        ]);
+        let backend = Arc::new(InMemoryMemory::default());
+        let agent = AgentBuilder::new(model.clone())
+            .memory(backend.clone(), MemoryStrategy::Full)
+            .build();
+
+        agent.converse("c", "Hi").await.unwrap();
+        agent.converse("c", "What did I say?").await.unwrap();
+
+        assert_eq!(backend.load("c").await.unwrap().len(), 4);
+
+        let requests = model.requests.lock().unwrap();
+        assert_eq!(
+            contents(&requests[1].chat_history),
+            contents(&conversation()[..2])
+        );
+    }
+}
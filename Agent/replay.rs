@@ -0,0 +1,398 @@
+//! Record and replay of completions, to test agents, extractors and pipelines without network.
+//!
+//! A [RecordingModel] wraps a real completion model and saves every request it sends, with the
+//! model's response, to a JSON fixture file. A [ReplayModel] loads the fixture file and answers
+//! each request with the response recorded for it, identified by a hash of the request. Requests
+//! that were not recorded (e.g.: because a prompt or preamble changed) fail with an error naming
+//! the unexpected prompt.
+//!
+//! # Example
+//! ```
+//! use Hydranta::replay::{RecordingModel, ReplayModel};
+//!
+//! // Record once against the provider...
+//! let model = RecordingModel::new(client.completion_model("qbt-1.a"), "tests/fixtures/weather.json");
+//! let agent = AgentBuilder::new(model).preamble("You are a weather bot").build();
+//! agent.prompt("Weather in Paris?").await?;
+//!
+//! // ...then replay in tests
+//! let model = ReplayModel::from_file("tests/fixtures/weather.json")?;
+//! let agent = AgentBuilder::new(model.clone()).preamble("You are a weather bot").build();
+//! assert_eq!(agent.prompt("Weather in Paris?").await?, "Sunny, 21°C");
+//! model.assert_exhausted();
+//! ```
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::completion::{
+    CompletionError, CompletionModel, CompletionRequest, CompletionResponse, Document, Message,
+    ModelChoice, ToolDefinition,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum FixtureError {
+    #[error("IoError: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("JsonError: {0}")]
+    JsonError(#[from] serde_json::Error),
+}
+
+/// Serializable copy of a [CompletionRequest]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub prompt: String,
+    pub preamble: Option<String>,
+    pub chat_history: Vec<Message>,
+    pub documents: Vec<Document>,
+    pub tools: Vec<ToolDefinition>,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<u64>,
+    pub additional_params: Option<serde_json::Value>,
+}
+
+impl From<&CompletionRequest> for RecordedRequest {
+    fn from(request: &CompletionRequest) -> Self {
+        Self {
+            prompt: request.prompt.clone(),
+            preamble: request.preamble.clone(),
+            chat_history: request.chat_history.clone(),
+            documents: request.documents.clone(),
+            tools: request.tools.clone(),
+            temperature: request.temperature,
+            max_tokens: request.max_tokens,
+            additional_params: request.additional_params.clone(),
+        }
+    }
+}
+
+impl RecordedRequest {
+    /// Stable hash of the request (FNV-1a of its JSON encoding, with object keys sorted so
+    /// maps such as [Document::additional_props] hash the same in every process), used to
+    /// match replayed requests with recorded ones
+    pub fn hash(&self) -> String {
+        let value = serde_json::to_value(self).expect("Request should serialize");
+        let json = canonical(value).to_string();
+        let hash = json.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        format!("{hash:016x}")
+    }
+}
+
+/// Rebuild `value` with the keys of every object in sorted order
+fn canonical(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(object) => {
+            let mut entries = object.into_iter().collect::<Vec<_>>();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            serde_json::Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, canonical(value)))
+                    .collect(),
+            )
+        }
+        serde_json::Value::Array(values) => {
+            serde_json::Value::Array(values.into_iter().map(canonical).collect())
+        }
+        value => value,
+    }
+}
+
+/// Serializable copy of a [ModelChoice]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RecordedChoice {
+    Message {
+        content: String,
+    },
+    ToolCall {
+        name: String,
+        arguments: serde_json::Value,
+    },
+}
+
+impl From<&ModelChoice> for RecordedChoice {
+    fn from(choice: &ModelChoice) -> Self {
+        match choice {
+            ModelChoice::Message(content) => Self::Message {
+                content: content.clone(),
+            },
+            ModelChoice::ToolCall(name, arguments) => Self::ToolCall {
+                name: name.clone(),
+                arguments: arguments.clone(),
+            },
+        }
+    }
+}
+
+impl From<RecordedChoice> for ModelChoice {
+    fn from(choice: RecordedChoice) -> Self {
+        match choice {
+            RecordedChoice::Message { content } => ModelChoice::Message(content),
+            RecordedChoice::ToolCall { name, arguments } => ModelChoice::ToolCall(name, arguments),
+        }
+    }
+}
+
+/// A recorded request with the model's response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    pub hash: String,
+    pub request: RecordedRequest,
+    pub response: RecordedChoice,
+}
+
+/// Content of a fixture file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Fixture {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Fixture {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, FixtureError> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), FixtureError> {
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok(std::fs::write(path, serde_json::to_string_pretty(self)?)?)
+    }
+}
+
+/// Completion model wrapper saving every request and response of `model` to a fixture file.
+/// The file is rewritten after each completion, so it is complete even if a test panics.
+#[derive(Clone)]
+pub struct RecordingModel<M: CompletionModel> {
+    model: M,
+    path: PathBuf,
+    fixture: Arc<Mutex<Fixture>>,
+    /// Serializes the writes of the fixture file, so an older snapshot never overwrites a
+    /// newer one
+    save_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl<M: CompletionModel> RecordingModel<M> {
+    /// Record the completions of `model` to the fixture file at `path`, replacing its content
+    pub fn new(model: M, path: impl Into<PathBuf>) -> Self {
+        Self {
+            model,
+            path: path.into(),
+            fixture: Default::default(),
+            save_lock: Default::default(),
+        }
+    }
+
+    /// The interactions recorded so far
+    pub fn fixture(&self) -> Fixture {
+        self.fixture.lock().unwrap().clone()
+    }
+
+    async fn save(&self) -> Result<(), FixtureError> {
+        let _guard = self.save_lock.lock().await;
+        let json = serde_json::to_string_pretty(&*self.fixture.lock().unwrap())?;
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        Ok(tokio::fs::write(&self.path, json).await?)
+    }
+}
+
+impl<M: CompletionModel> CompletionModel for RecordingModel<M> {
+    type Response = M::Response;
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse<M::Response>, CompletionError> {
+        let recorded = RecordedRequest::from(&request);
+        let response = self.model.completion(request).await?;
+
+        self.fixture.lock().unwrap().interactions.push(Interaction {
+            hash: recorded.hash(),
+            request: recorded,
+            response: RecordedChoice::from(&response.choice),
+        });
+        self.save()
+            .await
+            .map_err(|e| CompletionError::RequestError(Box::new(e)))?;
+
+        Ok(response)
+    }
+}
+
+/// Completion model answering requests with the responses recorded in a fixture file.
+/// A request recorded several times is answered with its recorded responses in order.
+#[derive(Clone)]
+pub struct ReplayModel {
+    responses: Arc<Mutex<HashMap<String, VecDeque<RecordedChoice>>>>,
+}
+
+impl ReplayModel {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, FixtureError> {
+        Ok(Self::new(Fixture::load(path)?))
+    }
+
+    pub fn new(fixture: Fixture) -> Self {
+        let mut responses = HashMap::<_, VecDeque<_>>::new();
+        for interaction in fixture.interactions {
+            responses
+                .entry(interaction.hash)
+                .or_default()
+                .push_back(interaction.response);
+        }
+        Self {
+            responses: Arc::new(Mutex::new(responses)),
+        }
+    }
+
+    /// Number of recorded responses that were not replayed
+    pub fn remaining(&self) -> usize {
+        self.responses
+            .lock()
+            .unwrap()
+            .values()
+            .map(VecDeque::len)
+            .sum()
+    }
+
+    /// Panic if some recorded responses were not replayed, e.g.: because the agent under test
+    /// stopped calling the model earlier than when the fixture was recorded
+    pub fn assert_exhausted(&self) {
+        let remaining = self.remaining();
+        assert!(
+            remaining == 0,
+            "{remaining} recorded responses were not replayed"
+        );
+    }
+}
+
+impl CompletionModel for ReplayModel {
+    type Response = ();
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse<()>, CompletionError> {
+        let recorded = RecordedRequest::from(&request);
+        let hash = recorded.hash();
+
+        let choice = self
+            .responses
+            .lock()
+            .unwrap()
+            .get_mut(&hash)
+            .and_then(VecDeque::pop_front)
+            .ok_or_else(|| {
+                tracing::error!(target: "rig",
+                    "Unexpected request {hash} during replay: {}",
+                    serde_json::to_string(&recorded).unwrap_or_default()
+                );
+                CompletionError::ProviderError(format!(
+                    "No recorded response for request {hash} with prompt {:?}",
+                    recorded.prompt
+                ))
+            })?;
+
+        Ok(CompletionResponse {
+            choice: choice.into(),
+            raw_response: (),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::{tests::ScriptedModel, AgentBuilder},
+        completion::Prompt,
+    };
+
+    #[tokio::test]
+    async fn test_record_then_replay() {
+        let path = std::env::temp_dir().join(format!("replay_test_{}.json", std::process::id()));
+
+        let model = RecordingModel::new(
+            ScriptedModel::new([
+                ModelChoice::Message("Paris".into()),
+                ModelChoice::Message("Rome".into()),
+            ]),
+            &path,
+        );
+        let agent = AgentBuilder::new(model).preamble("Geography").build();
+        assert_eq!(agent.prompt("Capital of France?").await.unwrap(), "Paris");
+        assert_eq!(agent.prompt("Capital of Italy?").await.unwrap(), "Rome");
+
+        let model = ReplayModel::from_file(&path).unwrap();
+        let agent = AgentBuilder::new(model.clone())
+            .preamble("Geography")
+            .build();
+        assert_eq!(agent.prompt("Capital of Italy?").await.unwrap(), "Rome");
+        assert_eq!(model.remaining(), 1);
+        assert_eq!(agent.prompt("Capital of France?").await.unwrap(), "Paris");
+        model.assert_exhausted();
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_request_fails() {
+        let model = ReplayModel::new(Fixture::default());
+        let agent = AgentBuilder::new(model).build();
+
+        let error = agent.prompt("Capital of Spain?").await.unwrap_err();
+        assert!(error.to_string().contains("Capital of Spain?"));
+    }
+
+    #[test]
+    fn test_hash_depends_on_the_whole_request() {
+        let request = |preamble: &str| RecordedRequest {
+            prompt: "Hi".into(),
+            preamble: Some(preamble.into()),
+            chat_history: vec![],
+            documents: vec![],
+            tools: vec![],
+            temperature: None,
+            max_tokens: None,
+            additional_params: None,
+        };
+
+        assert_eq!(request("a").hash(), request("a").hash());
+        assert_ne!(request("a").hash(), request("b").hash());
+    }
+
+    #[test]
+    fn test_hash_ignores_map_order() {
+        let request = |keys: Vec<usize>| RecordedRequest {
+            prompt: "Hi".into(),
+            preamble: None,
+            chat_history: vec![],
+            documents: vec![Document {
+                id: "doc".into(),
+                text: "Text".into(),
+                additional_props: keys
+                    .into_iter()
+                    .map(|key| (format!("key{key}"), key.to_string()))
+                    .collect(),
+            }],
+            tools: vec![],
+            temperature: None,
+            max_tokens: None,
+            additional_params: Some(serde_json::json!({"b": 1, "a": {"d": 2, "c": 3}})),
+        };
+
+        assert_eq!(
+            request((0..32).collect()).hash(),
+            request((0..32).rev().collect()).hash()
+        );
+    }
+}
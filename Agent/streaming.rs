@@ -0,0 +1,331 @@
+//! Streaming completions: models that can deliver their response incrementally,
+//! and the `StreamingPrompt`/`StreamingChat` traits implemented by [Agent](crate::agent::Agent).
+//!
+//! A streaming response is a [futures::Stream] of [StreamingChoice] items: text deltas
+//! and tool call deltas, in the order they are produced by the model.
+use std::{collections::BTreeMap, future::Future, io::Write, pin::Pin};
+
+use futures::{Stream, StreamExt};
+
+use crate::{
+    completion::{CompletionError, CompletionModel, CompletionRequest, Message},
+    tool::ToolCall,
+};
+
+/// A single chunk of a streaming completion
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamingChoice {
+    /// A fragment of the text response
+    Message(String),
+    /// A fragment of a tool call. Deltas sharing the same `index` belong to the same call:
+    /// the first one usually carries the `id` and `name`, the following ones only append
+    /// to the JSON `arguments`.
+    ToolCall(ToolCallDelta),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ToolCallDelta {
+    /// Position of the tool call in the response
+    pub index: usize,
+    /// Provider-assigned id of the tool call, if known
+    pub id: Option<String>,
+    /// Name of the tool, if known
+    pub name: Option<String>,
+    /// Fragment of the JSON encoded arguments
+    pub arguments: String,
+}
+
+pub type StreamingResult =
+    Pin<Box<dyn Stream<Item = Result<StreamingChoice, CompletionError>> + Send>>;
+
+/// Trait for completion models that support streaming responses
+pub trait StreamingCompletionModel: CompletionModel {
+    /// Send a completion request and return the response as a stream of chunks
+    fn stream(
+        &self,
+        request: CompletionRequest,
+    ) -> impl Future<Output = Result<StreamingResult, CompletionError>> + Send;
+}
+
+/// Trait for high-level streaming prompt interface
+pub trait StreamingPrompt: Send + Sync {
+    /// Stream the response to a simple prompt
+    fn stream_prompt(
+        &self,
+        prompt: &str,
+    ) -> impl Future<Output = Result<StreamingResult, CompletionError>> + Send;
+}
+
+/// Trait for high-level streaming chat interface
+pub trait StreamingChat: Send + Sync {
+    /// Stream the response to a prompt given a chat history
+    fn stream_chat(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> impl Future<Output = Result<StreamingResult, CompletionError>> + Send;
+}
+
+/// Collects [ToolCallDelta]s into complete tool calls, in order of their `index`.
+/// Indexes are keyed rather than used as positions, since they come from the model.
+#[derive(Debug, Default)]
+pub struct ToolCallAccumulator {
+    calls: BTreeMap<usize, (Option<String>, Option<String>, String)>,
+}
+
+impl ToolCallAccumulator {
+    pub fn push(&mut self, delta: ToolCallDelta) {
+        let (id, name, arguments) = self.calls.entry(delta.index).or_default();
+        if delta.id.is_some() {
+            *id = delta.id;
+        }
+        if delta.name.is_some() {
+            *name = delta.name;
+        }
+        arguments.push_str(&delta.arguments);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+
+    /// Return the accumulated tool calls. Calls for which no name was streamed are dropped.
+    pub fn finish(self) -> Vec<ToolCall> {
+        self.calls
+            .into_iter()
+            .filter_map(|(index, (id, name, arguments))| {
+                Some(ToolCall {
+                    id: id.unwrap_or_else(|| format!("call_{index}")),
+                    name: name?,
+                    arguments,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The full response of a consumed stream. Multiple tool calls can be dispatched
+/// concurrently with [Agent::call_tools](crate::agent::Agent::call_tools).
+#[derive(Debug, Default, PartialEq)]
+pub struct StreamedResponse {
+    pub text: String,
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// Drain a stream, calling `on_text` for every text delta as it arrives.
+pub async fn collect_stream(
+    mut stream: StreamingResult,
+    mut on_text: impl FnMut(&str),
+) -> Result<StreamedResponse, CompletionError> {
+    let mut text = String::new();
+    let mut tool_calls = ToolCallAccumulator::default();
+
+    while let Some(chunk) = stream.next().await {
+        match chunk? {
+            StreamingChoice::Message(delta) => {
+                on_text(&delta);
+                text.push_str(&delta);
+            }
+            StreamingChoice::ToolCall(delta) => tool_calls.push(delta),
+        }
+    }
+
+    Ok(StreamedResponse {
+        text,
+        tool_calls: tool_calls.finish(),
+    })
+}
+
+/// Drain a stream, printing text deltas to stdout as they arrive.
+pub async fn stream_to_stdout(stream: StreamingResult) -> Result<StreamedResponse, CompletionError> {
+    let mut stdout = std::io::stdout();
+    collect_stream(stream, |delta| {
+        print!("{delta}");
+        let _ = stdout.flush();
+    })
+    .await
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use futures::stream;
+
+    use super::*;
+    use crate::{
+        agent::AgentBuilder,
+        completion::{CompletionResponse, ModelChoice},
+        guardrails::{GuardrailAction, RegexGuardrail},
+        usage::UsageTracker,
+    };
+
+    /// Completion model that replies with a fixed list of chunks
+    #[derive(Clone)]
+    pub struct MockStreamingModel {
+        pub chunks: Vec<StreamingChoice>,
+    }
+
+    impl CompletionModel for MockStreamingModel {
+        type Response = ();
+
+        async fn completion(
+            &self,
+            _request: CompletionRequest,
+        ) -> Result<CompletionResponse<()>, CompletionError> {
+            let text = self
+                .chunks
+                .iter()
+                .filter_map(|chunk| match chunk {
+                    StreamingChoice::Message(delta) => Some(delta.as_str()),
+                    _ => None,
+                })
+                .collect::<String>();
+
+            Ok(CompletionResponse {
+                choice: ModelChoice::Message(text),
+                raw_response: (),
+            })
+        }
+    }
+
+    impl StreamingCompletionModel for MockStreamingModel {
+        async fn stream(&self, _request: CompletionRequest) -> Result<StreamingResult, CompletionError> {
+            Ok(Box::pin(stream::iter(self.chunks.clone().into_iter().map(Ok))))
+        }
+    }
+
+    fn text(delta: &str) -> StreamingChoice {
+        StreamingChoice::Message(delta.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_agent_stream_prompt() {
+        let agent = AgentBuilder::new(MockStreamingModel {
+            chunks: vec![text("Hello"), text(", "), text("world")],
+        })
+        .build();
+
+        let stream = agent.stream_prompt("Hi").await.unwrap();
+
+        let mut deltas = vec![];
+        let response = collect_stream(stream, |delta| deltas.push(delta.to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(deltas, vec!["Hello", ", ", "world"]);
+        assert_eq!(response.text, "Hello, world");
+        assert!(response.tool_calls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_agent_stream_tool_call_deltas() {
+        let agent = AgentBuilder::new(MockStreamingModel {
+            chunks: vec![
+                StreamingChoice::ToolCall(ToolCallDelta {
+                    index: 0,
+                    id: Some("call_a".into()),
+                    name: Some("add".into()),
+                    arguments: "{\"x\":".into(),
+                }),
+                StreamingChoice::ToolCall(ToolCallDelta {
+                    index: 1,
+                    id: Some("call_b".into()),
+                    name: Some("get_block".into()),
+                    arguments: "{}".into(),
+                }),
+                StreamingChoice::ToolCall(ToolCallDelta {
+                    index: 0,
+                    arguments: "1}".into(),
+                    ..Default::default()
+                }),
+            ],
+        })
+        .build();
+
+        let stream = agent.stream_chat("Hi", vec![]).await.unwrap();
+        let response = collect_stream(stream, |_| {}).await.unwrap();
+
+        assert_eq!(
+            response.tool_calls,
+            vec![
+                ToolCall {
+                    id: "call_a".into(),
+                    name: "add".into(),
+                    arguments: "{\"x\":1}".into(),
+                },
+                ToolCall {
+                    id: "call_b".into(),
+                    name: "get_block".into(),
+                    arguments: "{}".into(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_agent_stream_records_usage() {
+        let tracker = UsageTracker::default();
+        let agent = AgentBuilder::new(MockStreamingModel {
+            chunks: vec![text("Hello"), text(", "), text("world")],
+        })
+        .name("streamer")
+        .usage_tracker(tracker.clone(), "mock")
+        .build();
+
+        let stream = agent.stream_prompt("Hi").await.unwrap();
+        assert_eq!(tracker.report().total.requests, 0);
+
+        collect_stream(stream, |_| {}).await.unwrap();
+        let report = tracker.report();
+        assert_eq!(report.agents["streamer"].requests, 1);
+        // "Hello, world" is 12 characters, at 4 characters per token
+        assert_eq!(report.total.completion_tokens, 3);
+    }
+
+    #[tokio::test]
+    async fn test_agent_stream_applies_output_guardrails() {
+        let agent = AgentBuilder::new(MockStreamingModel {
+            chunks: vec![
+                text("Mail ada@"),
+                text("example.com"),
+                StreamingChoice::ToolCall(ToolCallDelta {
+                    index: 0,
+                    id: Some("call_a".into()),
+                    name: Some("add".into()),
+                    arguments: "{}".into(),
+                }),
+            ],
+        })
+        .output_guardrail(RegexGuardrail::pii(), GuardrailAction::Rewrite)
+        .build();
+
+        let stream = agent.stream_prompt("Hi").await.unwrap();
+        let mut deltas = vec![];
+        let response = collect_stream(stream, |delta| deltas.push(delta.to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(deltas, vec!["Mail [REDACTED email]"]);
+        assert_eq!(response.tool_calls.len(), 1);
+        assert_eq!(response.tool_calls[0].id, "call_a");
+    }
+
+    #[test]
+    fn test_accumulator_sparse_indexes() {
+        let mut calls = ToolCallAccumulator::default();
+        calls.push(ToolCallDelta {
+            index: usize::MAX,
+            name: Some("add".into()),
+            arguments: "{}".into(),
+            ..Default::default()
+        });
+        calls.push(ToolCallDelta {
+            index: 3,
+            arguments: "{}".into(),
+            ..Default::default()
+        });
+
+        let calls = calls.finish();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, format!("call_{}", usize::MAX));
+    }
+}
@@ -0,0 +1,708 @@
+//! Prompt templates with variables, conditionals, loops and includes.
+//!
+//! Syntax:
+//! - `{{ user.name }}`: value of a variable, following fields and array indices with dots
+//! - `{% if premium %}...{% elif trial %}...{% else %}...{% endif %}`: conditionals, with
+//!   `not` to negate a condition. `null`, `false`, `0`, `""`, `[]` and `{}` are false.
+//! - `{% for item in items %}...{% endfor %}`: loops over arrays, with `loop.index`
+//!   (starting at 1), `loop.first` and `loop.last` available in the body
+//! - `{% include "name" %}`: inline the partial template registered as `name`
+//! - `{# comment #}`
+//!
+//! A [PromptTemplate] is typed by the [Serialize] struct holding its variables: all the
+//! variables used in the template are checked against the fields of the struct when it is
+//! compiled, so a misspelled or missing variable is an error when the agent is built rather
+//! than an empty string in the prompt.
+//!
+//! # Example
+//! ```
+//! use Hydranta::{agent::AgentBuilder, completion::Prompt};
+//!
+//! #[derive(serde::Serialize, schemars::JsonSchema)]
+//! struct Question {
+//!     question: String,
+//!     wallets: Vec<String>,
+//! }
+//!
+//! let agent = AgentBuilder::new(model)
+//!     .template_partial("rules", "Only answer questions about the wallets below.")
+//!     .preamble("You are a portfolio assistant.")
+//!     .build_templated::<Question>(
+//!         "{% include \"rules\" %}\n{% for wallet in wallets %}- {{ wallet }}\n{% endfor %}{{ question }}",
+//!     )?;
+//!
+//! let answer = agent
+//!     .prompt(&Question {
+//!         question: "What is my SOL balance?".into(),
+//!         wallets: vec!["7xKX...".into()],
+//!     })
+//!     .await?;
+//! ```
+use std::{
+    collections::{BTreeSet, HashMap},
+    marker::PhantomData,
+};
+
+use schemars::{schema::Schema, JsonSchema};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    agent::Agent,
+    completion::{Chat, CompletionError, CompletionModel, Message, PromptError},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum TemplateError {
+    #[error("SyntaxError: line {0}: {1}")]
+    SyntaxError(usize, String),
+
+    /// An `include` refers to a partial that was not registered
+    #[error("UnknownPartial: {0}")]
+    UnknownPartial(String),
+
+    #[error("IncludeCycle: partial {0} includes itself")]
+    IncludeCycle(String),
+
+    /// Variables used by the template are not fields of its variables struct
+    #[error("MissingVariables: {}", .0.join(", "))]
+    MissingVariables(Vec<String>),
+
+    /// A variable has no value at rendering time
+    #[error("UndefinedVariable: {0}")]
+    UndefinedVariable(String),
+
+    #[error("NotIterable: {0} is not an array")]
+    NotIterable(String),
+
+    #[error("JsonError: {0}")]
+    JsonError(#[from] serde_json::Error),
+}
+
+/// Partial templates that can be included by name
+#[derive(Debug, Clone, Default)]
+pub struct Templates {
+    partials: HashMap<String, String>,
+}
+
+impl Templates {
+    /// Register `source` as the partial `name`
+    pub fn partial(mut self, name: &str, source: &str) -> Self {
+        self.partials.insert(name.to_string(), source.to_string());
+        self
+    }
+
+    pub fn add_partial(&mut self, name: &str, source: &str) {
+        self.partials.insert(name.to_string(), source.to_string());
+    }
+
+    /// Parse `source`, inlining its includes
+    pub fn parse(&self, source: &str) -> Result<Template, TemplateError> {
+        let nodes = Parser::new(self, source, vec![])?.parse_all()?;
+        Ok(Template { nodes })
+    }
+
+    /// Parse `source` and check that its variables are all fields of `V`
+    pub fn compile<V: Serialize + JsonSchema>(
+        &self,
+        source: &str,
+    ) -> Result<PromptTemplate<V>, TemplateError> {
+        PromptTemplate::from_template(self.parse(source)?)
+    }
+}
+
+type Path = Vec<String>;
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    Variable(Path),
+    If {
+        branches: Vec<(Condition, Vec<Node>)>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        item: String,
+        items: Path,
+        body: Vec<Node>,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Condition {
+    negated: bool,
+    path: Path,
+}
+
+/// A parsed template
+#[derive(Debug, Clone)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, TemplateError> {
+        Templates::default().parse(source)
+    }
+
+    /// Top-level variables used by the template, excluding loop variables
+    pub fn variables(&self) -> BTreeSet<String> {
+        let mut variables = BTreeSet::new();
+        collect_variables(&self.nodes, &mut vec!["loop".to_string()], &mut variables);
+        variables
+    }
+
+    pub fn render(&self, vars: &impl Serialize) -> Result<String, TemplateError> {
+        let root = serde_json::to_value(vars)?;
+        let mut output = String::new();
+        render_nodes(&self.nodes, &mut Scope::new(&root), &mut output)?;
+        Ok(output)
+    }
+}
+
+fn collect_variables(nodes: &[Node], bound: &mut Vec<String>, variables: &mut BTreeSet<String>) {
+    for node in nodes {
+        match node {
+            Node::Text(_) => {}
+            Node::Variable(path) => add_variable(path, bound, variables),
+            Node::If {
+                branches,
+                otherwise,
+            } => {
+                for (condition, body) in branches {
+                    add_variable(&condition.path, bound, variables);
+                    collect_variables(body, bound, variables);
+                }
+                collect_variables(otherwise, bound, variables);
+            }
+            Node::For { item, items, body } => {
+                add_variable(items, bound, variables);
+                bound.push(item.clone());
+                collect_variables(body, bound, variables);
+                bound.pop();
+            }
+        }
+    }
+}
+
+fn add_variable(path: &Path, bound: &[String], variables: &mut BTreeSet<String>) {
+    if !bound.contains(&path[0]) {
+        variables.insert(path[0].clone());
+    }
+}
+
+/// Variables of the template being rendered: the root struct and the loop variables
+struct Scope<'a> {
+    root: &'a Value,
+    locals: Vec<(String, Value)>,
+}
+
+impl<'a> Scope<'a> {
+    fn new(root: &'a Value) -> Self {
+        Self {
+            root,
+            locals: vec![],
+        }
+    }
+
+    fn lookup(&self, path: &Path) -> Result<&Value, TemplateError> {
+        let undefined = || TemplateError::UndefinedVariable(path.join("."));
+        let start = self
+            .locals
+            .iter()
+            .rev()
+            .find(|(name, _)| *name == path[0])
+            .map(|(_, value)| value)
+            .or_else(|| self.root.get(&path[0]))
+            .ok_or_else(undefined)?;
+
+        path[1..]
+            .iter()
+            .try_fold(start, |value, segment| match value {
+                Value::Array(items) => segment.parse::<usize>().ok().and_then(|i| items.get(i)),
+                _ => value.get(segment),
+            })
+            .ok_or_else(undefined)
+    }
+}
+
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(fields) => !fields.is_empty(),
+    }
+}
+
+fn render_nodes(
+    nodes: &[Node],
+    scope: &mut Scope,
+    output: &mut String,
+) -> Result<(), TemplateError> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => output.push_str(text),
+            Node::Variable(path) => match scope.lookup(path)? {
+                Value::Null => {}
+                Value::String(s) => output.push_str(s),
+                value => output.push_str(&value.to_string()),
+            },
+            Node::If {
+                branches,
+                otherwise,
+            } => {
+                let mut body = otherwise;
+                for (condition, branch) in branches {
+                    // A missing optional field is false rather than an error
+                    let value = scope.lookup(&condition.path).unwrap_or(&Value::Null);
+                    if is_truthy(value) != condition.negated {
+                        body = branch;
+                        break;
+                    }
+                }
+                render_nodes(body, scope, output)?;
+            }
+            Node::For { item, items, body } => {
+                let Value::Array(values) = scope.lookup(items)?.clone() else {
+                    return Err(TemplateError::NotIterable(items.join(".")));
+                };
+                let len = values.len();
+                for (index, value) in values.into_iter().enumerate() {
+                    scope.locals.push((
+                        "loop".into(),
+                        serde_json::json!({
+                            "index": index + 1,
+                            "first": index == 0,
+                            "last": index + 1 == len,
+                        }),
+                    ));
+                    scope.locals.push((item.clone(), value));
+                    let result = render_nodes(body, scope, output);
+                    scope.locals.truncate(scope.locals.len() - 2);
+                    result?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    Expression(&'a str, usize),
+    Tag(&'a str, usize),
+}
+
+struct Parser<'a> {
+    templates: &'a Templates,
+    tokens: std::vec::IntoIter<Token<'a>>,
+    /// Partials being included, to detect cycles
+    includes: Vec<String>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(
+        templates: &'a Templates,
+        source: &'a str,
+        includes: Vec<String>,
+    ) -> Result<Self, TemplateError> {
+        Ok(Self {
+            templates,
+            tokens: tokenize(source)?.into_iter(),
+            includes,
+        })
+    }
+
+    fn parse_all(&mut self) -> Result<Vec<Node>, TemplateError> {
+        match self.parse_block()? {
+            (nodes, None) => Ok(nodes),
+            (_, Some((tag, line))) => Err(TemplateError::SyntaxError(
+                line,
+                format!("unexpected `{tag}`"),
+            )),
+        }
+    }
+
+    /// Parse nodes until the end of the source or a closing tag (`elif`, `else`, `endif`,
+    /// `endfor`), which is returned with its line
+    #[allow(clippy::type_complexity)]
+    fn parse_block(&mut self) -> Result<(Vec<Node>, Option<(&'a str, usize)>), TemplateError> {
+        let mut nodes = vec![];
+        while let Some(token) = self.tokens.next() {
+            match token {
+                Token::Text(text) => nodes.push(Node::Text(text.to_string())),
+                Token::Expression(expression, line) => {
+                    nodes.push(Node::Variable(parse_path(expression, line)?))
+                }
+                Token::Tag(tag, line) => {
+                    let keyword = tag.split_whitespace().next().unwrap_or_default();
+                    match keyword {
+                        "if" => nodes.push(self.parse_if(tag, line)?),
+                        "for" => nodes.push(self.parse_for(tag, line)?),
+                        "include" => nodes.extend(self.parse_include(tag, line)?),
+                        "elif" | "else" | "endif" | "endfor" => {
+                            return Ok((nodes, Some((tag, line))))
+                        }
+                        _ => {
+                            return Err(TemplateError::SyntaxError(
+                                line,
+                                format!("unknown tag `{tag}`"),
+                            ))
+                        }
+                    }
+                }
+            }
+        }
+        Ok((nodes, None))
+    }
+
+    fn parse_if(&mut self, tag: &str, line: usize) -> Result<Node, TemplateError> {
+        let mut branches = vec![];
+        let mut condition = parse_condition(&tag["if".len()..], line)?;
+        loop {
+            let (body, end) = self.parse_block()?;
+            let Some((end, end_line)) = end else {
+                return Err(TemplateError::SyntaxError(line, "unclosed `if`".into()));
+            };
+            branches.push((condition, body));
+
+            match end.split_whitespace().next() {
+                Some("elif") => condition = parse_condition(&end["elif".len()..], end_line)?,
+                Some("else") => {
+                    let (otherwise, end) = self.parse_block()?;
+                    return match end {
+                        Some(("endif", _)) => Ok(Node::If {
+                            branches,
+                            otherwise,
+                        }),
+                        _ => Err(TemplateError::SyntaxError(
+                            end_line,
+                            "unclosed `else`".into(),
+                        )),
+                    };
+                }
+                Some("endif") => {
+                    return Ok(Node::If {
+                        branches,
+                        otherwise: vec![],
+                    })
+                }
+                _ => {
+                    return Err(TemplateError::SyntaxError(
+                        end_line,
+                        format!("unexpected `{end}` in `if`"),
+                    ))
+                }
+            }
+        }
+    }
+
+    fn parse_for(&mut self, tag: &str, line: usize) -> Result<Node, TemplateError> {
+        let [_, item, "in", items] = tag.split_whitespace().collect::<Vec<_>>()[..] else {
+            return Err(TemplateError::SyntaxError(
+                line,
+                format!("expected `for <item> in <items>`, got `{tag}`"),
+            ));
+        };
+        let item = parse_path(item, line)?;
+        if item.len() != 1 {
+            return Err(TemplateError::SyntaxError(
+                line,
+                "invalid loop variable".into(),
+            ));
+        }
+        let items = parse_path(items, line)?;
+
+        match self.parse_block()? {
+            (body, Some(("endfor", _))) => Ok(Node::For {
+                item: item[0].clone(),
+                items,
+                body,
+            }),
+            _ => Err(TemplateError::SyntaxError(line, "unclosed `for`".into())),
+        }
+    }
+
+    fn parse_include(&mut self, tag: &str, line: usize) -> Result<Vec<Node>, TemplateError> {
+        let name = tag["include".len()..].trim();
+        let Some(name) = name
+            .strip_prefix('"')
+            .and_then(|name| name.strip_suffix('"'))
+        else {
+            return Err(TemplateError::SyntaxError(
+                line,
+                format!("expected `include \"<name>\"`, got `{tag}`"),
+            ));
+        };
+        if self.includes.iter().any(|include| include == name) {
+            return Err(TemplateError::IncludeCycle(name.to_string()));
+        }
+        let source = self
+            .templates
+            .partials
+            .get(name)
+            .ok_or_else(|| TemplateError::UnknownPartial(name.to_string()))?;
+
+        let mut includes = self.includes.clone();
+        includes.push(name.to_string());
+        Parser::new(self.templates, source, includes)?.parse_all()
+    }
+}
+
+fn parse_path(expression: &str, line: usize) -> Result<Path, TemplateError> {
+    let path = expression
+        .trim()
+        .split('.')
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    let valid = path.iter().all(|segment| {
+        !segment.is_empty() && segment.chars().all(|c| c.is_alphanumeric() || c == '_')
+    });
+    if !valid {
+        return Err(TemplateError::SyntaxError(
+            line,
+            format!("invalid variable `{}`", expression.trim()),
+        ));
+    }
+    Ok(path)
+}
+
+fn parse_condition(condition: &str, line: usize) -> Result<Condition, TemplateError> {
+    let condition = condition.trim();
+    match condition.strip_prefix("not ") {
+        Some(path) => Ok(Condition {
+            negated: true,
+            path: parse_path(path, line)?,
+        }),
+        None => Ok(Condition {
+            negated: false,
+            path: parse_path(condition, line)?,
+        }),
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token<'_>>, TemplateError> {
+    let mut tokens = vec![];
+    let mut rest = source;
+    let line_of = |rest: &str| source[..source.len() - rest.len()].matches('\n').count() + 1;
+
+    while let Some(start) = rest.find('{') {
+        let close = match rest[start + 1..].chars().next() {
+            Some('{') => "}}",
+            Some('%') => "%}",
+            Some('#') => "#}",
+            _ => {
+                tokens.push(Token::Text(&rest[..start + 1]));
+                rest = &rest[start + 1..];
+                continue;
+            }
+        };
+        if start > 0 {
+            tokens.push(Token::Text(&rest[..start]));
+        }
+
+        let line = line_of(&rest[start..]);
+        let Some(end) = rest[start + 2..].find(close) else {
+            return Err(TemplateError::SyntaxError(
+                line,
+                format!("missing `{close}`"),
+            ));
+        };
+        let content = rest[start + 2..start + 2 + end].trim();
+        match close {
+            "}}" => tokens.push(Token::Expression(content, line)),
+            "%}" => tokens.push(Token::Tag(content, line)),
+            _ => {}
+        }
+        rest = &rest[start + 2 + end + 2..];
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest));
+    }
+    Ok(tokens)
+}
+
+/// A template whose variables are the fields of `V`
+#[derive(Debug, Clone)]
+pub struct PromptTemplate<V> {
+    template: Template,
+    _vars: PhantomData<fn(&V)>,
+}
+
+impl<V: Serialize + JsonSchema> PromptTemplate<V> {
+    pub fn new(source: &str) -> Result<Self, TemplateError> {
+        Templates::default().compile(source)
+    }
+
+    /// Check the variables of `template` against the fields of `V`. Types which are not
+    /// structs (e.g.: maps) are not checked.
+    pub fn from_template(template: Template) -> Result<Self, TemplateError> {
+        let schema = schemars::schema_for!(V);
+        if let Some(object) = &schema.schema.object {
+            let closed = matches!(
+                object.additional_properties.as_deref(),
+                None | Some(Schema::Bool(false))
+            );
+            let missing = template
+                .variables()
+                .into_iter()
+                .filter(|variable| !object.properties.contains_key(variable))
+                .collect::<Vec<_>>();
+
+            if closed && !missing.is_empty() {
+                return Err(TemplateError::MissingVariables(missing));
+            }
+        }
+
+        Ok(Self {
+            template,
+            _vars: PhantomData,
+        })
+    }
+
+    pub fn render(&self, vars: &V) -> Result<String, TemplateError> {
+        self.template.render(vars)
+    }
+}
+
+/// An agent prompted with a variables struct `V` rendered through a [PromptTemplate].
+/// Built with [AgentBuilder::build_templated](crate::agent::AgentBuilder::build_templated).
+pub struct TemplatedAgent<M: CompletionModel, V> {
+    agent: Agent<M>,
+    template: PromptTemplate<V>,
+}
+
+impl<M: CompletionModel, V: Serialize + JsonSchema> TemplatedAgent<M, V> {
+    pub fn new(agent: Agent<M>, template: PromptTemplate<V>) -> Self {
+        Self { agent, template }
+    }
+
+    /// The underlying agent, to prompt it with plain strings
+    pub fn agent(&self) -> &Agent<M> {
+        &self.agent
+    }
+
+    pub async fn prompt(&self, vars: &V) -> Result<String, PromptError> {
+        self.chat(vars, vec![]).await
+    }
+
+    pub async fn chat(&self, vars: &V, chat_history: Vec<Message>) -> Result<String, PromptError> {
+        let prompt = self
+            .template
+            .render(vars)
+            .map_err(|e| CompletionError::RequestError(Box::new(e)))?;
+        self.agent.chat(&prompt, chat_history).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        agent::{tests::ScriptedModel, AgentBuilder},
+        completion::ModelChoice,
+    };
+
+    #[derive(Serialize, JsonSchema)]
+    struct Vars {
+        name: String,
+        premium: bool,
+        wallets: Vec<String>,
+    }
+
+    fn vars() -> Vars {
+        Vars {
+            name: "Ada".into(),
+            premium: false,
+            wallets: vec!["a1".into(), "b2".into()],
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let templates = Templates::default().partial("greeting", "Hello {{ name }}!");
+        let template = templates
+            .compile::<Vars>(
+                "{% include \"greeting\" %}{# comment #} {% if premium %}VIP{% elif not wallets %}New{% else %}Regular{% endif %}\n\
+                 {% for wallet in wallets %}{{ loop.index }}. {{ wallet }}{% if not loop.last %}, {% endif %}{% endfor %}",
+            )
+            .unwrap();
+
+        assert_eq!(
+            template.render(&vars()).unwrap(),
+            "Hello Ada! Regular\n1. a1, 2. b2"
+        );
+    }
+
+    #[test]
+    fn test_missing_variables_are_compile_errors() {
+        let result = PromptTemplate::<Vars>::new(
+            "{{ nme }} {% for w in wallets %}{{ w }}{{ address }}{% endfor %}",
+        );
+        match result {
+            Err(TemplateError::MissingVariables(missing)) => {
+                assert_eq!(missing, vec!["address", "nme"])
+            }
+            _ => panic!("expected missing variables"),
+        }
+
+        // Untyped templates fail when rendering instead
+        let template = Template::parse("{{ user.name }}").unwrap();
+        assert!(matches!(
+            template.render(&json!({"user": {}})),
+            Err(TemplateError::UndefinedVariable(path)) if path == "user.name"
+        ));
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert!(matches!(
+            Template::parse("line\n{% if x %}"),
+            Err(TemplateError::SyntaxError(2, _))
+        ));
+        assert!(matches!(
+            Template::parse("{% endfor %}"),
+            Err(TemplateError::SyntaxError(1, _))
+        ));
+        assert!(matches!(
+            Template::parse("{{ name"),
+            Err(TemplateError::SyntaxError(1, _))
+        ));
+        assert!(matches!(
+            Templates::default()
+                .partial("a", "{% include \"a\" %}")
+                .parse("{% include \"a\" %}"),
+            Err(TemplateError::IncludeCycle(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_templated_agent() {
+        let model = ScriptedModel::new([ModelChoice::Message("Hi Ada".into())]);
+        let builder = || {
+            AgentBuilder::new(model.clone())
+                .template_partial("signature", "-- {{ name }}")
+                .preamble_template("Assistant of {{ name }}", &json!({"name": "Ada"}))
+                .unwrap()
+        };
+
+        assert!(matches!(
+            builder().build_templated::<Vars>("{{ question }}"),
+            Err(TemplateError::MissingVariables(_))
+        ));
+
+        let agent = builder()
+            .build_templated::<Vars>("Say hi {% include \"signature\" %}")
+            .unwrap();
+        assert_eq!(agent.prompt(&vars()).await.unwrap(), "Hi Ada");
+
+        let requests = model.requests.lock().unwrap();
+        assert_eq!(requests[0].prompt, "Say hi -- Ada");
+        assert_eq!(requests[0].preamble.as_deref(), Some("Assistant of Ada"));
+    }
+}
@@ -0,0 +1,378 @@
+//! Token accounting of completion requests and trimming to fit the model's context window.
+//!
+//! A [ContextBudget] counts the tokens of every part of a request (preamble, static and dynamic
+//! context, tool definitions, chat history and prompt) with a [Tokenizer], and reports them in a
+//! [BudgetReport]. When the request does not fit the context window (minus the tokens reserved
+//! for the completion, i.e.: `max_tokens`), it is trimmed: the lowest-scoring dynamic context
+//! documents are dropped first, then the oldest chat history messages.
+//!
+//! # Example
+//! ```
+//! use Hydranta::{agent::AgentBuilder, token_budget::ApproxTokenizer};
+//!
+//! let agent = AgentBuilder::new(model)
+//!     .preamble("System prompt")
+//!     .dynamic_context(4, index)
+//!     .max_tokens(1024)
+//!     .context_window(16_384, ApproxTokenizer::default())
+//!     .build();
+//!
+//! // `None` if the agent has no context window
+//! if let Some(report) = agent.budget_report("What is the price of SOL?", chat_history).await? {
+//!     println!("{} tokens out of {}", report.total(), report.limit);
+//! }
+//! ```
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::completion::{CompletionModel, Document, Message, ToolDefinition};
+
+/// Number of tokens added by providers around each chat message (role, separators)
+const MESSAGE_OVERHEAD: usize = 4;
+
+/// Counts the tokens of a text the way a model does
+pub trait Tokenizer: Send + Sync {
+    fn count_tokens(&self, text: &str) -> usize;
+}
+
+impl<F> Tokenizer for F
+where
+    F: Fn(&str) -> usize + Send + Sync,
+{
+    fn count_tokens(&self, text: &str) -> usize {
+        self(text)
+    }
+}
+
+/// Tokenizer estimating the token count from the length of the text. Good enough for
+/// budgeting when the model's tokenizer is not available.
+#[derive(Debug, Clone, Copy)]
+pub struct ApproxTokenizer {
+    pub chars_per_token: usize,
+}
+
+impl Default for ApproxTokenizer {
+    fn default() -> Self {
+        Self { chars_per_token: 4 }
+    }
+}
+
+impl Tokenizer for ApproxTokenizer {
+    fn count_tokens(&self, text: &str) -> usize {
+        text.chars().count().div_ceil(self.chars_per_token.max(1))
+    }
+}
+
+/// Completion models that know their tokenizer and context window size.
+/// Agents of such models can be budgeted with
+/// [AgentBuilder::model_context_window](crate::agent::AgentBuilder::model_context_window).
+pub trait TokenizedModel: CompletionModel {
+    /// Tokenizer of the model
+    fn tokenizer(&self) -> Arc<dyn Tokenizer>;
+
+    /// Maximum number of tokens of a request, completion included
+    fn context_window(&self) -> usize;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BudgetError {
+    /// The request does not fit the context window even without dynamic context and history
+    #[error("ContextWindowExceeded: request needs {needed} tokens, only {limit} available")]
+    ContextWindowExceeded { needed: usize, limit: usize },
+}
+
+/// Token usage of a single completion request, after trimming
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BudgetReport {
+    /// Tokens available for the request, i.e.: the context window minus `max_tokens`
+    pub limit: usize,
+    pub preamble: usize,
+    pub static_context: usize,
+    pub dynamic_context: usize,
+    pub tools: usize,
+    pub chat_history: usize,
+    pub prompt: usize,
+    /// Number of dynamic context documents dropped to fit the budget
+    pub dropped_documents: usize,
+    /// Number of chat history messages dropped to fit the budget
+    pub dropped_messages: usize,
+}
+
+impl BudgetReport {
+    /// Total number of tokens of the request
+    pub fn total(&self) -> usize {
+        self.preamble
+            + self.static_context
+            + self.dynamic_context
+            + self.tools
+            + self.chat_history
+            + self.prompt
+    }
+
+    /// Number of tokens left for the request
+    pub fn remaining(&self) -> usize {
+        self.limit.saturating_sub(self.total())
+    }
+}
+
+/// The parts of a completion request that can be trimmed, with the resulting report
+pub struct TrimmedContext {
+    pub dynamic_context: Vec<Document>,
+    pub chat_history: Vec<Message>,
+    pub report: BudgetReport,
+}
+
+/// Context window of a model together with its tokenizer
+#[derive(Clone)]
+pub struct ContextBudget {
+    window: usize,
+    tokenizer: Arc<dyn Tokenizer>,
+}
+
+impl ContextBudget {
+    pub fn new(window: usize, tokenizer: impl Tokenizer + 'static) -> Self {
+        Self {
+            window,
+            tokenizer: Arc::new(tokenizer),
+        }
+    }
+
+    pub fn for_model(model: &impl TokenizedModel) -> Self {
+        Self {
+            window: model.context_window(),
+            tokenizer: model.tokenizer(),
+        }
+    }
+
+    pub fn tokenizer(&self) -> &dyn Tokenizer {
+        self.tokenizer.as_ref()
+    }
+
+    pub fn count_document(&self, document: &Document) -> usize {
+        self.tokenizer.count_tokens(&document.to_string())
+    }
+
+    pub fn count_message(&self, message: &Message) -> usize {
+        self.tokenizer.count_tokens(&message.content) + MESSAGE_OVERHEAD
+    }
+
+    pub fn count_tool(&self, tool: &ToolDefinition) -> usize {
+        self.tokenizer
+            .count_tokens(&serde_json::to_string(tool).unwrap_or_default())
+    }
+
+    /// Count the tokens of a request and trim it to fit the window, reserving `max_tokens`
+    /// for the completion. `dynamic_context` holds the retrieved documents with their
+    /// similarity score: the lowest-scoring ones are dropped first, then the oldest messages
+    /// of `chat_history`. The order of the kept documents and messages is preserved.
+    #[allow(clippy::too_many_arguments)]
+    pub fn fit(
+        &self,
+        prompt: &str,
+        preamble: &str,
+        static_context: &[Document],
+        tools: &[ToolDefinition],
+        dynamic_context: Vec<(f64, Document)>,
+        chat_history: Vec<Message>,
+        max_tokens: Option<u64>,
+    ) -> Result<TrimmedContext, BudgetError> {
+        let limit = self
+            .window
+            .saturating_sub(max_tokens.unwrap_or_default() as usize);
+
+        let mut report = BudgetReport {
+            limit,
+            preamble: self.tokenizer.count_tokens(preamble),
+            static_context: static_context
+                .iter()
+                .map(|doc| self.count_document(doc))
+                .sum(),
+            tools: tools.iter().map(|tool| self.count_tool(tool)).sum(),
+            prompt: self.tokenizer.count_tokens(prompt) + MESSAGE_OVERHEAD,
+            ..Default::default()
+        };
+
+        let mut documents = dynamic_context
+            .into_iter()
+            .map(|(score, doc)| (score, self.count_document(&doc), Some(doc)))
+            .collect::<Vec<_>>();
+        let messages = chat_history
+            .into_iter()
+            .map(|message| (self.count_message(&message), message))
+            .collect::<Vec<_>>();
+        report.dynamic_context = documents.iter().map(|(_, tokens, _)| tokens).sum();
+        report.chat_history = messages.iter().map(|(tokens, _)| tokens).sum();
+
+        // Drop the lowest-scoring documents first
+        let mut by_score = (0..documents.len()).collect::<Vec<_>>();
+        by_score.sort_by(|a, b| documents[*a].0.total_cmp(&documents[*b].0));
+        for index in by_score {
+            if report.total() <= limit {
+                break;
+            }
+            let (_, tokens, doc) = &mut documents[index];
+            *doc = None;
+            report.dynamic_context -= *tokens;
+            report.dropped_documents += 1;
+        }
+
+        // Then the oldest messages. The history must not start with an assistant message,
+        // so the reply following a dropped message is dropped with it.
+        let mut start = 0;
+        while start < messages.len()
+            && (report.total() > limit || (start > 0 && messages[start].1.role == "assistant"))
+        {
+            report.chat_history -= messages[start].0;
+            report.dropped_messages += 1;
+            start += 1;
+        }
+
+        if report.total() > limit {
+            return Err(BudgetError::ContextWindowExceeded {
+                needed: report.total(),
+                limit,
+            });
+        }
+
+        if report.dropped_documents > 0 || report.dropped_messages > 0 {
+            tracing::debug!(target: "rig",
+                "Trimmed request to {} tokens: dropped {} documents and {} messages",
+                report.total(),
+                report.dropped_documents,
+                report.dropped_messages
+            );
+        }
+
+        Ok(TrimmedContext {
+            dynamic_context: documents
+                .into_iter()
+                .filter_map(|(_, _, doc)| doc)
+                .collect(),
+            chat_history: messages
+                .into_iter()
+                .skip(start)
+                .map(|(_, message)| message)
+                .collect(),
+            report,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// One token per word, so token counts are easy to follow
+    fn words(text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    fn document(id: &str, text: &str) -> Document {
+        Document {
+            id: id.into(),
+            text: text.into(),
+            additional_props: HashMap::new(),
+        }
+    }
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.into(),
+            content: content.into(),
+        }
+    }
+
+    #[test]
+    fn test_fits_without_trimming() {
+        let budget = ContextBudget::new(1000, words);
+        let trimmed = budget
+            .fit(
+                "one two",
+                "system prompt",
+                &[],
+                &[],
+                vec![(0.5, document("a", "some text"))],
+                vec![message("user", "hi"), message("assistant", "hello")],
+                Some(100),
+            )
+            .unwrap();
+
+        assert_eq!(trimmed.report.limit, 900);
+        assert_eq!(trimmed.report.preamble, 2);
+        assert_eq!(trimmed.report.prompt, 2 + MESSAGE_OVERHEAD);
+        assert_eq!(trimmed.report.chat_history, 2 + 2 * MESSAGE_OVERHEAD);
+        assert_eq!(trimmed.report.dropped_documents, 0);
+        assert_eq!(trimmed.dynamic_context.len(), 1);
+        assert_eq!(trimmed.chat_history.len(), 2);
+    }
+
+    #[test]
+    fn test_drops_lowest_scoring_documents_then_oldest_messages() {
+        // Each document is 12 tokens, the history 17 and the prompt 5: 58 in total
+        let long = "word ".repeat(8);
+        let fit = |window| {
+            ContextBudget::new(window, words)
+                .fit(
+                    "prompt",
+                    "",
+                    &[],
+                    &[],
+                    vec![
+                        (0.9, document("best", &long)),
+                        (0.1, document("worst", &long)),
+                        (0.5, document("middle", &long)),
+                    ],
+                    vec![
+                        message("user", "first question"),
+                        message("assistant", "first answer"),
+                        message("user", "second"),
+                    ],
+                    None,
+                )
+                .unwrap()
+        };
+
+        let trimmed = fit(40);
+        assert_eq!(
+            trimmed
+                .dynamic_context
+                .iter()
+                .map(|doc| doc.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["best"]
+        );
+        assert_eq!(trimmed.report.dropped_documents, 2);
+        assert_eq!(trimmed.report.dropped_messages, 0);
+        assert_eq!(trimmed.report.total(), 34);
+
+        let trimmed = fit(18);
+        assert!(trimmed.dynamic_context.is_empty());
+        // The assistant reply goes with the dropped question
+        assert_eq!(trimmed.report.dropped_messages, 2);
+        assert_eq!(trimmed.chat_history.len(), 1);
+        assert_eq!(trimmed.chat_history[0].content, "second");
+        assert_eq!(trimmed.report.total(), 10);
+    }
+
+    #[test]
+    fn test_exceeded_when_fixed_parts_do_not_fit() {
+        let budget = ContextBudget::new(10, words);
+        let result = budget.fit(
+            "prompt",
+            &"word ".repeat(20),
+            &[],
+            &[],
+            vec![],
+            vec![],
+            None,
+        );
+
+        assert!(matches!(
+            result,
+            Err(BudgetError::ContextWindowExceeded { limit: 10, .. })
+        ));
+    }
+}
@@ -0,0 +1,244 @@
+//! Per-tool execution policies: timeout, retries with exponential backoff and circuit breaking.
+//!
+//! A [ToolPolicy] is attached to a tool of a [ToolSet](crate::tool::ToolSet) by name, either
+//! with [ToolSet::set_policy](crate::tool::ToolSet::set_policy) or
+//! [AgentBuilder::tool_policy](crate::agent::AgentBuilder::tool_policy).
+//!
+//! # Example
+//! ```
+//! use std::time::Duration;
+//! use Hydranta::tool_policy::{ToolErrorKind, ToolPolicy};
+//!
+//! let policy = ToolPolicy::default()
+//!     .timeout(Duration::from_secs(10))
+//!     .retries(3, Duration::from_millis(200))
+//!     .retry_on([ToolErrorKind::ToolCall, ToolErrorKind::Timeout])
+//!     .circuit_breaker(5, Duration::from_secs(60));
+//! ```
+use std::{
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::tool::ToolSetError;
+
+/// Kind of failure of a single tool call attempt, used to decide whether it is retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolErrorKind {
+    /// The tool returned an error ([ToolError::ToolCallError](crate::tool::ToolError::ToolCallError))
+    ToolCall,
+    /// The arguments or output could not be (de)serialized ([ToolError::JsonError](crate::tool::ToolError::JsonError))
+    Json,
+    /// The arguments do not match the tool's schema ([ToolError::ValidationError](crate::tool::ToolError::ValidationError))
+    Validation,
+    /// The call did not complete within the policy's timeout
+    Timeout,
+}
+
+#[derive(Debug, Clone)]
+pub struct ToolPolicy {
+    /// Timeout applied to each attempt
+    timeout: Option<Duration>,
+    /// Number of retries after the first failed attempt
+    max_retries: usize,
+    /// Delay before the first retry, doubled after each retry
+    backoff: Duration,
+    /// Failures that are retried
+    retry_on: Vec<ToolErrorKind>,
+    /// Number of consecutive failed calls after which the circuit opens, and for how long
+    circuit_breaker: Option<(usize, Duration)>,
+}
+
+impl Default for ToolPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            max_retries: 0,
+            backoff: Duration::from_millis(100),
+            retry_on: vec![ToolErrorKind::ToolCall, ToolErrorKind::Timeout],
+            circuit_breaker: None,
+        }
+    }
+}
+
+impl ToolPolicy {
+    /// Fail an attempt that does not complete within `timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Retry a failed call up to `max_retries` times, waiting `backoff` before the first
+    /// retry and doubling the delay after each one
+    pub fn retries(mut self, max_retries: usize, backoff: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Set which failures are retried. Defaults to tool errors and timeouts; JSON and
+    /// validation errors are not retried since the same arguments would fail again.
+    pub fn retry_on(mut self, kinds: impl IntoIterator<Item = ToolErrorKind>) -> Self {
+        self.retry_on = kinds.into_iter().collect();
+        self
+    }
+
+    /// Reject calls to the tool for `cooldown` after `threshold` consecutive failed calls.
+    /// Only failures of the tool itself (tool errors and timeouts) are counted, not invalid
+    /// arguments. Once the cooldown has elapsed, a single trial call is let through while the
+    /// others are still rejected: the circuit closes again if it succeeds, and reopens if it
+    /// fails.
+    pub fn circuit_breaker(mut self, threshold: usize, cooldown: Duration) -> Self {
+        self.circuit_breaker = Some((threshold.max(1), cooldown));
+        self
+    }
+
+    fn is_retryable(&self, error: &ToolSetError) -> bool {
+        let kind = match error {
+            ToolSetError::ToolCallError(error) => error.kind(),
+            ToolSetError::TimeoutError(..) => ToolErrorKind::Timeout,
+            _ => return false,
+        };
+        self.retry_on.contains(&kind)
+    }
+}
+
+/// Whether `error` is a failure of the tool itself, counted by the circuit breaker
+fn is_execution_failure(error: &ToolSetError) -> bool {
+    match error {
+        ToolSetError::ToolCallError(error) => error.kind() == ToolErrorKind::ToolCall,
+        ToolSetError::TimeoutError(..) => true,
+        _ => false,
+    }
+}
+
+#[derive(Default)]
+struct CircuitState {
+    consecutive_failures: usize,
+    opened_at: Option<Instant>,
+    /// Start of the trial call let through once the circuit is half-open. A trial that does
+    /// not report back within the cooldown (e.g. cancelled) is replaced by a new one.
+    trial_started_at: Option<Instant>,
+}
+
+/// A [ToolPolicy] together with the circuit breaker state of the tool it is attached to
+pub(crate) struct PolicyState {
+    policy: ToolPolicy,
+    circuit: Mutex<CircuitState>,
+}
+
+impl PolicyState {
+    pub(crate) fn new(policy: ToolPolicy) -> Self {
+        Self {
+            policy,
+            circuit: Mutex::new(CircuitState::default()),
+        }
+    }
+
+    /// Execute a call to the tool `toolname` with `call`, which is invoked again for each
+    /// retry
+    pub(crate) async fn call<F, Fut>(
+        &self,
+        toolname: &str,
+        args: String,
+        call: F,
+    ) -> Result<String, ToolSetError>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<String, ToolSetError>>,
+    {
+        self.check_circuit(toolname)?;
+
+        let mut backoff = self.policy.backoff;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let call = call(args.clone());
+            let result = match self.policy.timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, call).await {
+                    Ok(result) => result,
+                    Err(_) => Err(ToolSetError::TimeoutError(toolname.to_string(), timeout)),
+                },
+                None => call.await,
+            };
+
+            match result {
+                Ok(output) => {
+                    self.record_success();
+                    return Ok(output);
+                }
+                Err(error)
+                    if attempt <= self.policy.max_retries && self.policy.is_retryable(&error) =>
+                {
+                    tracing::warn!(target: "rig",
+                        "Tool {toolname} failed (attempt {attempt}), retrying in {backoff:?}: {error}"
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(error) => {
+                    if is_execution_failure(&error) {
+                        self.record_failure(toolname);
+                    } else {
+                        self.release_trial();
+                    }
+                    return Err(if attempt > 1 {
+                        ToolSetError::RetriesExhaustedError(
+                            toolname.to_string(),
+                            attempt,
+                            Box::new(error),
+                        )
+                    } else {
+                        error
+                    });
+                }
+            }
+        }
+    }
+
+    fn check_circuit(&self, toolname: &str) -> Result<(), ToolSetError> {
+        let Some((_, cooldown)) = self.policy.circuit_breaker else {
+            return Ok(());
+        };
+        let mut circuit = self.circuit.lock().unwrap();
+        let Some(opened_at) = circuit.opened_at else {
+            return Ok(());
+        };
+        let trial_pending = circuit
+            .trial_started_at
+            .is_some_and(|started_at| started_at.elapsed() < cooldown);
+        if opened_at.elapsed() < cooldown || trial_pending {
+            return Err(ToolSetError::CircuitOpenError(toolname.to_string()));
+        }
+
+        // Half-open: let this call through as the trial
+        circuit.trial_started_at = Some(Instant::now());
+        Ok(())
+    }
+
+    fn record_success(&self) {
+        *self.circuit.lock().unwrap() = CircuitState::default();
+    }
+
+    /// The trial call ended without telling whether the tool works again
+    fn release_trial(&self) {
+        self.circuit.lock().unwrap().trial_started_at = None;
+    }
+
+    fn record_failure(&self, toolname: &str) {
+        let Some((threshold, _)) = self.policy.circuit_breaker else {
+            return;
+        };
+        let mut circuit = self.circuit.lock().unwrap();
+        circuit.consecutive_failures += 1;
+        circuit.trial_started_at = None;
+        if circuit.consecutive_failures >= threshold {
+            tracing::warn!(target: "rig",
+                "Opening circuit of tool {toolname} after {} consecutive failures",
+                circuit.consecutive_failures
+            );
+            circuit.opened_at = Some(Instant::now());
+        }
+    }
+}
@@ -0,0 +1,470 @@
+//! Registry of named tools shared between agents.
+//!
+//! Tools are registered once in a [ToolRegistry], optionally under a namespace (e.g.:
+//! `chain.get_block`, `social.post`), and each agent gets the tools it needs, either:
+//! - as a [ToolSet] built from tool names with [ToolRegistry::toolset] (e.g.: from an
+//!   [AgentSpec](crate::agent_spec::AgentSpec)), fixed when the agent is built
+//! - as a [ToolView] selecting tools by name or namespace with [ToolRegistry::view], which
+//!   follows the tools registered, removed, enabled and disabled while the agent runs
+//!
+//! The registry is a handle: its clones, toolsets and views share the same tools.
+//! Calls to a disabled or removed tool fail, and views stop offering it to the model.
+//!
+//! # Example
+//! ```
+//! use Hydranta::{agent::AgentBuilder, tool_registry::ToolRegistry};
+//!
+//! let registry = ToolRegistry::default();
+//! registry.register_in("chain", GetBlock)?;
+//! registry.register_in("chain", GetBalance)?;
+//! registry.register_in("social", Post)?;
+//!
+//! let analyst = AgentBuilder::new(model.clone())
+//!     .tool_view(registry.view(["chain.*"]))
+//!     .build();
+//! let community_manager = AgentBuilder::new(model)
+//!     .tools(registry.toolset(["social.post", "chain.get_balance"])?)
+//!     .build();
+//!
+//! // Stop every agent from posting
+//! registry.disable("social.post")?;
+//! ```
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    completion::ToolDefinition,
+    tool::{ToolDyn, ToolError, ToolSet, ToolSetError, ToolType, ValidatedTool},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ToolRegistryError {
+    /// A tool with the same qualified name is already registered
+    #[error("DuplicateTool: {0}")]
+    DuplicateTool(String),
+
+    #[error("ToolNotFound: {0}")]
+    ToolNotFound(String),
+
+    /// The tool was disabled in the registry
+    #[error("ToolDisabled: {0}")]
+    ToolDisabled(String),
+}
+
+struct Entry {
+    tool: Arc<dyn ToolDyn>,
+    enabled: bool,
+}
+
+#[derive(Clone)]
+pub struct ToolRegistry {
+    tools: Arc<RwLock<BTreeMap<String, Entry>>>,
+    separator: String,
+}
+
+impl Default for ToolRegistry {
+    fn default() -> Self {
+        Self::with_separator(".")
+    }
+}
+
+impl ToolRegistry {
+    /// Create a registry joining namespaces and tool names with `separator` instead of `.`,
+    /// e.g.: for providers which only accept `[a-zA-Z0-9_-]` in tool names
+    pub fn with_separator(separator: &str) -> Self {
+        Self {
+            tools: Default::default(),
+            separator: separator.to_string(),
+        }
+    }
+
+    /// Register `tool` under its name
+    pub fn register(&self, tool: impl ToolDyn + 'static) -> Result<(), ToolRegistryError> {
+        self.insert(tool.name(), Arc::new(ValidatedTool::new(tool)))
+    }
+
+    /// Register `tool` under its name in `namespace`, e.g.: `chain.get_block`
+    pub fn register_in(
+        &self,
+        namespace: &str,
+        tool: impl ToolDyn + 'static,
+    ) -> Result<(), ToolRegistryError> {
+        self.insert(
+            format!("{namespace}{}{}", self.separator, tool.name()),
+            Arc::new(ValidatedTool::new(tool)),
+        )
+    }
+
+    fn insert(&self, name: String, tool: Arc<dyn ToolDyn>) -> Result<(), ToolRegistryError> {
+        let mut tools = self.tools.write().unwrap();
+        if tools.contains_key(&name) {
+            return Err(ToolRegistryError::DuplicateTool(name));
+        }
+        tracing::debug!(target: "rig", "Registering tool {name}");
+        tools.insert(
+            name,
+            Entry {
+                tool,
+                enabled: true,
+            },
+        );
+        Ok(())
+    }
+
+    /// Remove the tool `toolname` from the registry and from the toolsets and views using it
+    pub fn remove(&self, toolname: &str) -> Result<(), ToolRegistryError> {
+        self.tools
+            .write()
+            .unwrap()
+            .remove(toolname)
+            .map(|_| ())
+            .ok_or_else(|| ToolRegistryError::ToolNotFound(toolname.to_string()))
+    }
+
+    pub fn enable(&self, toolname: &str) -> Result<(), ToolRegistryError> {
+        self.set_enabled(toolname, true)
+    }
+
+    /// Disable the tool `toolname` until it is enabled again: calls to it fail, and views do
+    /// not offer it to the model
+    pub fn disable(&self, toolname: &str) -> Result<(), ToolRegistryError> {
+        self.set_enabled(toolname, false)
+    }
+
+    fn set_enabled(&self, toolname: &str, enabled: bool) -> Result<(), ToolRegistryError> {
+        let mut tools = self.tools.write().unwrap();
+        let entry = tools
+            .get_mut(toolname)
+            .ok_or_else(|| ToolRegistryError::ToolNotFound(toolname.to_string()))?;
+        entry.enabled = enabled;
+        Ok(())
+    }
+
+    pub fn contains(&self, toolname: &str) -> bool {
+        self.tools.read().unwrap().contains_key(toolname)
+    }
+
+    pub fn is_enabled(&self, toolname: &str) -> bool {
+        self.tools
+            .read()
+            .unwrap()
+            .get(toolname)
+            .is_some_and(|entry| entry.enabled)
+    }
+
+    /// Qualified names of the registered tools, in alphabetical order
+    pub fn names(&self) -> Vec<String> {
+        self.tools.read().unwrap().keys().cloned().collect()
+    }
+
+    /// The tool `toolname` if it is registered and enabled
+    fn get(&self, toolname: &str) -> Result<Arc<dyn ToolDyn>, ToolRegistryError> {
+        match self.tools.read().unwrap().get(toolname) {
+            Some(entry) if entry.enabled => Ok(entry.tool.clone()),
+            Some(_) => Err(ToolRegistryError::ToolDisabled(toolname.to_string())),
+            None => Err(ToolRegistryError::ToolNotFound(toolname.to_string())),
+        }
+    }
+
+    /// Build a toolset with the tools named `toolnames` (qualified names for namespaced
+    /// tools). The toolset is fixed, but calls to tools disabled or removed since then fail.
+    pub fn toolset<S: AsRef<str>>(
+        &self,
+        toolnames: impl IntoIterator<Item = S>,
+    ) -> Result<ToolSet, ToolRegistryError> {
+        let mut toolset = ToolSet::default();
+        for toolname in toolnames {
+            let toolname = toolname.as_ref();
+            let tool = self
+                .tools
+                .read()
+                .unwrap()
+                .get(toolname)
+                .map(|entry| entry.tool.clone())
+                .ok_or_else(|| ToolRegistryError::ToolNotFound(toolname.to_string()))?;
+
+            // Registered tools already validate their arguments
+            toolset.tools.insert(
+                toolname.to_string(),
+                ToolType::Simple(Box::new(SharedTool {
+                    name: toolname.to_string(),
+                    tool,
+                    registry: self.clone(),
+                })),
+            );
+        }
+        Ok(toolset)
+    }
+
+    /// A view of the tools matching `patterns`: qualified tool names, `namespace.*` for all
+    /// the tools of a namespace, or `*` for all tools
+    pub fn view<S: AsRef<str>>(&self, patterns: impl IntoIterator<Item = S>) -> ToolView {
+        ToolView {
+            registry: self.clone(),
+            patterns: patterns
+                .into_iter()
+                .map(|pattern| pattern.as_ref().to_string())
+                .collect(),
+        }
+    }
+}
+
+/// A subset of the tools of a [ToolRegistry], selected by name or namespace, seeing the
+/// changes made to the registry
+#[derive(Clone)]
+pub struct ToolView {
+    registry: ToolRegistry,
+    patterns: Vec<String>,
+}
+
+impl ToolView {
+    fn matches(&self, toolname: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => toolname.starts_with(prefix),
+                None => pattern == toolname,
+            })
+    }
+
+    /// Whether the tool `toolname` is in the view and enabled
+    pub fn contains(&self, toolname: &str) -> bool {
+        self.matches(toolname) && self.registry.is_enabled(toolname)
+    }
+
+    /// Names of the enabled tools in the view, in alphabetical order
+    pub fn names(&self) -> Vec<String> {
+        self.registry
+            .tools
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(name, entry)| entry.enabled && self.matches(name))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Definitions of the enabled tools in the view, under their qualified names
+    pub async fn definitions(&self, prompt: &str) -> Vec<ToolDefinition> {
+        let tools = self
+            .names()
+            .into_iter()
+            .filter_map(|name| Some((self.registry.get(&name).ok()?, name)))
+            .collect::<Vec<_>>();
+
+        let mut definitions = vec![];
+        for (tool, name) in tools {
+            let mut definition = tool.definition(prompt.to_string()).await;
+            definition.name = name;
+            definitions.push(definition);
+        }
+        definitions
+    }
+
+    pub async fn call(&self, toolname: &str, args: String) -> Result<String, ToolSetError> {
+        if !self.matches(toolname) {
+            return Err(ToolSetError::ToolNotFoundError(toolname.to_string()));
+        }
+        let tool = self.registry.get(toolname).map_err(|error| match error {
+            ToolRegistryError::ToolDisabled(_) => {
+                ToolSetError::ToolCallError(ToolError::ToolCallError(Box::new(error)))
+            }
+            _ => ToolSetError::ToolNotFoundError(toolname.to_string()),
+        })?;
+        Ok(tool.call(args).await?)
+    }
+}
+
+/// A tool of a [ToolRegistry], shared between toolsets under its qualified name
+struct SharedTool {
+    name: String,
+    tool: Arc<dyn ToolDyn>,
+    registry: ToolRegistry,
+}
+
+impl ToolDyn for SharedTool {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn definition(
+        &self,
+        prompt: String,
+    ) -> Pin<Box<dyn Future<Output = ToolDefinition> + Send + Sync + '_>> {
+        Box::pin(async move {
+            let mut definition = self.tool.definition(prompt).await;
+            definition.name = self.name.clone();
+            definition
+        })
+    }
+
+    fn call(
+        &self,
+        args: String,
+    ) -> Pin<Box<dyn Future<Output = Result<String, ToolError>> + Send + Sync + '_>> {
+        Box::pin(async move {
+            let tool = self
+                .registry
+                .get(&self.name)
+                .map_err(|error| ToolError::ToolCallError(Box::new(error)))?;
+            tool.call(args).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        agent::{
+            tests::{Adder, ScriptedModel},
+            AgentBuilder, StopCondition,
+        },
+        approval::Approval,
+        completion::{Completion, ModelChoice, Prompt},
+        tool::{
+            tests::{flaky, Decide},
+            ToolCall,
+        },
+        tool_policy::ToolPolicy,
+    };
+
+    fn registry() -> ToolRegistry {
+        let registry = ToolRegistry::default();
+        registry.register_in("math", Adder).unwrap();
+        registry.register_in("chain", Adder).unwrap();
+        registry.register(Adder).unwrap();
+        registry
+    }
+
+    #[test]
+    fn test_namespaces_and_duplicates() {
+        let registry = registry();
+        assert_eq!(registry.names(), ["add", "chain.add", "math.add"]);
+        assert!(matches!(
+            registry.register_in("math", Adder),
+            Err(ToolRegistryError::DuplicateTool(name)) if name == "math.add"
+        ));
+
+        let underscored = ToolRegistry::with_separator("__");
+        underscored.register_in("math", Adder).unwrap();
+        assert!(underscored.contains("math__add"));
+    }
+
+    #[tokio::test]
+    async fn test_toolset_follows_registry_state() {
+        let registry = registry();
+        let toolset = registry.toolset(["math.add"]).unwrap();
+        assert!(matches!(
+            registry.toolset(["math.sub"]),
+            Err(ToolRegistryError::ToolNotFound(_))
+        ));
+
+        let args = r#"{"x": 1, "y": 2}"#.to_string();
+        assert_eq!(toolset.call("math.add", args.clone()).await.unwrap(), "3");
+
+        registry.disable("math.add").unwrap();
+        let error = toolset.call("math.add", args.clone()).await.unwrap_err();
+        assert!(error.to_string().contains("ToolDisabled"));
+
+        registry.enable("math.add").unwrap();
+        assert!(toolset.call("math.add", args.clone()).await.is_ok());
+
+        registry.remove("math.add").unwrap();
+        assert!(toolset.call("math.add", args).await.is_err());
+        assert!(matches!(
+            registry.remove("math.add"),
+            Err(ToolRegistryError::ToolNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_views_select_subsets() {
+        let registry = registry();
+        let math = registry.view(["math.*"]);
+        let everything = registry.view(["*"]);
+
+        assert_eq!(math.names(), ["math.add"]);
+        assert_eq!(everything.names().len(), 3);
+        assert_eq!(math.definitions("").await[0].name, "math.add");
+        assert!(matches!(
+            math.call("chain.add", "{}".into()).await,
+            Err(ToolSetError::ToolNotFoundError(_))
+        ));
+
+        registry.disable("math.add").unwrap();
+        assert!(math.names().is_empty());
+        assert!(!everything.contains("math.add"));
+
+        registry.enable("math.add").unwrap();
+        assert_eq!(
+            math.call("math.add", r#"{"x": 2, "y": 2}"#.into())
+                .await
+                .unwrap(),
+            "4"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_agent_sees_view_changes() {
+        let registry = registry();
+        let model = ScriptedModel::new([ModelChoice::ToolCall(
+            "math.add".into(),
+            json!({"x": 20, "y": 22}),
+        )]);
+        let agent = AgentBuilder::new(model)
+            .tool_view(registry.view(["math.*"]))
+            .stop_when(StopCondition::AnyTool)
+            .build();
+
+        let request = agent.completion("Add", vec![]).await.unwrap().build();
+        assert_eq!(request.tools[0].name, "math.add");
+        assert_eq!(agent.prompt("What is 20 + 22?").await.unwrap(), "42");
+
+        registry.disable("math.add").unwrap();
+        let request = agent.completion("Add", vec![]).await.unwrap().build();
+        assert!(request.tools.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_agent_guards_view_calls() {
+        let registry = registry();
+        let model = ScriptedModel::new([ModelChoice::ToolCall(
+            "math.add".into(),
+            json!({"x": 20, "y": 22}),
+        )]);
+        let agent = AgentBuilder::new(model)
+            .tool_view(registry.view(["*"]))
+            .stop_when(StopCondition::AnyTool)
+            .require_approval("math.add")
+            .approval_handler(Decide(Approval::Reject("not today".into())))
+            .tool_policy(
+                "flaky",
+                ToolPolicy::default().retries(1, Duration::from_millis(1)),
+            )
+            .build();
+
+        assert!(agent
+            .prompt("What is 20 + 22?")
+            .await
+            .unwrap()
+            .contains("rejected by the user: not today"));
+
+        // The first call fails and is retried by the policy
+        registry.register(flaky(1)).unwrap();
+        let call = ToolCall {
+            id: "call_0".into(),
+            name: "flaky".into(),
+            arguments: "{}".into(),
+        };
+        assert_eq!(agent.call_tools(vec![call]).await[0].as_ref().unwrap(), "2");
+    }
+}
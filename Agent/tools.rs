@@ -1,11 +1,19 @@
-use std::{collections::HashMap, pin::Pin};
+use std::{
+    collections::{HashMap, HashSet},
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
-use futures::Future;
+use futures::{stream, Future, StreamExt};
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    approval::{rejection_message, Approval, ApprovalHandler},
     completion::{self, ToolDefinition},
     embeddings::{embed::EmbedError, tool::ToolSchema},
+    json_schema::{self, ValidationError},
+    tool_policy::{PolicyState, ToolErrorKind, ToolPolicy},
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -15,6 +23,20 @@ pub enum ToolError {
 
     #[error("JsonError: {0}")]
     JsonError(#[from] serde_json::Error),
+
+    /// The arguments do not match the `parameters` schema of the tool's definition
+    #[error("ValidationError: {0}")]
+    ValidationError(#[from] ValidationError),
+}
+
+impl ToolError {
+    pub fn kind(&self) -> ToolErrorKind {
+        match self {
+            ToolError::ToolCallError(_) => ToolErrorKind::ToolCall,
+            ToolError::JsonError(_) => ToolErrorKind::Json,
+            ToolError::ValidationError(_) => ToolErrorKind::Validation,
+        }
+    }
 }
 
 pub trait Tool: Sized + Send + Sync {
@@ -110,6 +132,65 @@ impl<T: ToolEmbedding> ToolEmbeddingDyn for T {
     }
 }
 
+/// Wraps a tool to check the arguments of its calls against the `parameters` schema of its
+/// definition before they are deserialized, so the model can be told precisely what is wrong
+/// with them. The schema is computed on the first call and reused for the following ones.
+pub(crate) struct ValidatedTool<T> {
+    tool: T,
+    schema: OnceLock<serde_json::Value>,
+}
+
+impl<T> ValidatedTool<T> {
+    pub(crate) fn new(tool: T) -> Self {
+        Self {
+            tool,
+            schema: OnceLock::new(),
+        }
+    }
+}
+
+impl<T: ToolDyn> ToolDyn for ValidatedTool<T> {
+    fn name(&self) -> String {
+        self.tool.name()
+    }
+
+    fn definition(
+        &self,
+        prompt: String,
+    ) -> Pin<Box<dyn Future<Output = ToolDefinition> + Send + Sync + '_>> {
+        self.tool.definition(prompt)
+    }
+
+    fn call(
+        &self,
+        args: String,
+    ) -> Pin<Box<dyn Future<Output = Result<String, ToolError>> + Send + Sync + '_>> {
+        Box::pin(async move {
+            let value: serde_json::Value = serde_json::from_str(&args)?;
+            let schema = match self.schema.get() {
+                Some(schema) => schema,
+                None => {
+                    let parameters = self.tool.definition(String::new()).await.parameters;
+                    self.schema.get_or_init(|| parameters)
+                }
+            };
+            json_schema::validate(schema, &value)?;
+
+            self.tool.call(args).await
+        })
+    }
+}
+
+impl<T: ToolEmbeddingDyn> ToolEmbeddingDyn for ValidatedTool<T> {
+    fn context(&self) -> serde_json::Result<serde_json::Value> {
+        self.tool.context()
+    }
+
+    fn embedding_docs(&self) -> Vec<String> {
+        self.tool.embedding_docs()
+    }
+}
+
 pub(crate) enum ToolType {
     Simple(Box<dyn ToolDyn>),
     Embedding(Box<dyn ToolEmbeddingDyn>),
@@ -138,6 +219,43 @@ impl ToolType {
     }
 }
 
+/// A tool call requested by a model
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    /// Provider-assigned id of the tool call
+    pub id: String,
+    /// Name of the tool to call
+    pub name: String,
+    /// JSON encoded arguments
+    pub arguments: String,
+}
+
+/// Trait for provider responses which can carry several tool calls, of which
+/// [ModelChoice](crate::completion::ModelChoice) only holds the first one.
+/// See [AgentBuilder::parallel_tool_calls](crate::agent::AgentBuilder::parallel_tool_calls).
+pub trait ResponseToolCalls {
+    /// All the tool calls of the response, in order
+    fn tool_calls(&self) -> Vec<ToolCall>;
+}
+
+/// Execute `calls` with `call`, with at most `concurrency` calls in flight. Results are
+/// returned in the order of `calls`.
+pub(crate) async fn dispatch_concurrently<F, Fut>(
+    calls: Vec<ToolCall>,
+    concurrency: usize,
+    call: F,
+) -> Vec<Result<String, ToolSetError>>
+where
+    F: FnMut(ToolCall) -> Fut,
+    Fut: Future<Output = Result<String, ToolSetError>>,
+{
+    stream::iter(calls)
+        .map(call)
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum ToolSetError {
 
@@ -150,12 +268,26 @@ pub enum ToolSetError {
 
     #[error("JsonError: {0}")]
     JsonError(#[from] serde_json::Error),
+
+    #[error("TimeoutError: tool {0} did not complete within {1:?}")]
+    TimeoutError(String, Duration),
+
+    /// A call still failed after all the retries allowed by the tool's [ToolPolicy]
+    #[error("RetriesExhaustedError: tool {0} failed after {1} attempts: {2}")]
+    RetriesExhaustedError(String, usize, Box<ToolSetError>),
+
+    /// The tool's circuit breaker is open after too many consecutive failures
+    #[error("CircuitOpenError: tool {0} is temporarily disabled after repeated failures")]
+    CircuitOpenError(String),
 }
 
 
 #[derive(Default)]
 pub struct ToolSet {
     pub(crate) tools: HashMap<String, ToolType>,
+    pub(crate) policies: HashMap<String, PolicyState>,
+    pub(crate) approval_required: HashSet<String>,
+    pub(crate) approval_handler: Option<Arc<dyn ApprovalHandler>>,
 }
 
 impl ToolSet {
@@ -180,13 +312,43 @@ impl ToolSet {
 
 
     pub fn add_tool(&mut self, tool: impl ToolDyn + 'static) {
-        self.tools
-            .insert(tool.name(), ToolType::Simple(Box::new(tool)));
+        self.tools.insert(
+            tool.name(),
+            ToolType::Simple(Box::new(ValidatedTool::new(tool))),
+        );
     }
 
 
     pub fn add_tools(&mut self, toolset: ToolSet) {
         self.tools.extend(toolset.tools);
+        self.policies.extend(toolset.policies);
+        self.approval_required.extend(toolset.approval_required);
+        if self.approval_handler.is_none() {
+            self.approval_handler = toolset.approval_handler;
+        }
+    }
+
+    /// Mark the tool `toolname` as requiring approval: each call is submitted to the
+    /// toolset's [ApprovalHandler] before being executed
+    pub fn require_approval(&mut self, toolname: &str) {
+        self.approval_required.insert(toolname.to_string());
+    }
+
+    pub fn requires_approval(&self, toolname: &str) -> bool {
+        self.approval_required.contains(toolname)
+    }
+
+    /// Set the handler deciding on calls to tools requiring approval. Without a handler,
+    /// these calls are rejected.
+    pub fn set_approval_handler(&mut self, handler: impl ApprovalHandler + 'static) {
+        self.approval_handler = Some(Arc::new(handler));
+    }
+
+    /// Attach an execution policy (timeout, retries, circuit breaker) to the tool `toolname`,
+    /// replacing any previous policy for that tool
+    pub fn set_policy(&mut self, toolname: &str, policy: ToolPolicy) {
+        self.policies
+            .insert(toolname.to_string(), PolicyState::new(policy));
     }
 
     pub(crate) fn get(&self, toolname: &str) -> Option<&ToolType> {
@@ -195,17 +357,106 @@ impl ToolSet {
 
 
     pub async fn call(&self, toolname: &str, args: String) -> Result<String, ToolSetError> {
+        self.call_with_timeout(toolname, args, None).await
+    }
+
+    /// Call a tool, failing with [ToolSetError::TimeoutError] if it does not complete within `timeout`.
+    /// Time spent waiting for the approval of the call is not counted.
+    pub async fn call_with_timeout(
+        &self,
+        toolname: &str,
+        args: String,
+        timeout: Option<Duration>,
+    ) -> Result<String, ToolSetError> {
         if let Some(tool) = self.tools.get(toolname) {
-            tracing::info!(target: "rig",
-                "Calling tool {toolname} with args:\n{}",
-                serde_json::to_string_pretty(&args).unwrap_or_else(|_| args.clone())
-            );
-            Ok(tool.call(args).await?)
+            self.call_guarded(toolname, args, timeout, |args| async move {
+                Ok(tool.call(args).await?)
+            })
+            .await
         } else {
             Err(ToolSetError::ToolNotFoundError(toolname.to_string()))
         }
     }
 
+    /// Execute a call to the tool `toolname` with `call`, after submitting it to the approval
+    /// handler if the tool requires approval, and with the tool's policy and `timeout`. This
+    /// lets the toolset's approvals and policies apply to tools it does not own (e.g.: tools
+    /// of a [ToolView](crate::tool_registry::ToolView)).
+    pub(crate) async fn call_guarded<F, Fut>(
+        &self,
+        toolname: &str,
+        args: String,
+        timeout: Option<Duration>,
+        call: F,
+    ) -> Result<String, ToolSetError>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<String, ToolSetError>>,
+    {
+        let args = match self.check_approval(toolname, args).await {
+            Ok(args) => args,
+            Err(rejection) => return Ok(rejection),
+        };
+
+        tracing::info!(target: "rig",
+            "Calling tool {toolname} with args:\n{}",
+            serde_json::to_string_pretty(&args).unwrap_or_else(|_| args.clone())
+        );
+        let execution = async {
+            match self.policies.get(toolname) {
+                Some(policy) => policy.call(toolname, args, call).await,
+                None => call(args).await,
+            }
+        };
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, execution)
+                .await
+                .unwrap_or_else(|_| Err(ToolSetError::TimeoutError(toolname.to_string(), timeout))),
+            None => execution.await,
+        }
+    }
+
+    /// Ask the approval handler about a call to a tool requiring approval. Returns the arguments
+    /// to execute the call with, or the message to send back to the model if it was rejected.
+    async fn check_approval(&self, toolname: &str, args: String) -> Result<String, String> {
+        if !self.approval_required.contains(toolname) {
+            return Ok(args);
+        }
+        let Some(handler) = &self.approval_handler else {
+            tracing::warn!(target: "rig",
+                "Tool {toolname} requires approval but no approval handler is set"
+            );
+            return Err(rejection_message(
+                toolname,
+                "no approval handler is configured",
+            ));
+        };
+
+        match handler.approve(toolname, &args).await {
+            Approval::Approve => Ok(args),
+            Approval::Edit(args) => Ok(args),
+            Approval::Reject(reason) => {
+                tracing::info!(target: "rig", "Call to tool {toolname} rejected: {reason}");
+                Err(rejection_message(toolname, &reason))
+            }
+        }
+    }
+
+    /// Execute several tool calls concurrently, with at most `concurrency` calls in flight
+    /// and `timeout` applied to each call. Results are returned in the order of `calls`.
+    pub async fn call_many(
+        &self,
+        calls: Vec<ToolCall>,
+        concurrency: usize,
+        timeout: Option<Duration>,
+    ) -> Vec<Result<String, ToolSetError>> {
+        dispatch_concurrently(calls, concurrency, |call| async move {
+            self.call_with_timeout(&call.name, call.arguments, timeout)
+                .await
+        })
+        .await
+    }
+
 
     pub async fn documents(&self) -> Result<Vec<completion::Document>, ToolSetError> {
         let mut docs = Vec::new();
@@ -263,16 +514,37 @@ impl ToolSet {
 #[derive(Default)]
 pub struct ToolSetBuilder {
     tools: Vec<ToolType>,
+    policies: HashMap<String, PolicyState>,
+    approval_required: HashSet<String>,
+    approval_handler: Option<Arc<dyn ApprovalHandler>>,
 }
 
 impl ToolSetBuilder {
     pub fn static_tool(mut self, tool: impl ToolDyn + 'static) -> Self {
-        self.tools.push(ToolType::Simple(Box::new(tool)));
+        self.tools
+            .push(ToolType::Simple(Box::new(ValidatedTool::new(tool))));
         self
     }
 
     pub fn dynamic_tool(mut self, tool: impl ToolEmbeddingDyn + 'static) -> Self {
-        self.tools.push(ToolType::Embedding(Box::new(tool)));
+        self.tools
+            .push(ToolType::Embedding(Box::new(ValidatedTool::new(tool))));
+        self
+    }
+
+    pub fn policy(mut self, toolname: &str, policy: ToolPolicy) -> Self {
+        self.policies
+            .insert(toolname.to_string(), PolicyState::new(policy));
+        self
+    }
+
+    pub fn require_approval(mut self, toolname: &str) -> Self {
+        self.approval_required.insert(toolname.to_string());
+        self
+    }
+
+    pub fn approval_handler(mut self, handler: impl ApprovalHandler + 'static) -> Self {
+        self.approval_handler = Some(Arc::new(handler));
         self
     }
 
@@ -283,6 +555,477 @@ impl ToolSetBuilder {
                 .into_iter()
                 .map(|tool| (tool.name(), tool))
                 .collect(),
+            policies: self.policies,
+            approval_required: self.approval_required,
+            approval_handler: self.approval_handler,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct SleepArgs {
+        millis: u64,
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("Sleep error")]
+    struct SleepError;
+
+    struct Sleep;
+
+    impl Tool for Sleep {
+        const NAME: &'static str = "sleep";
+        type Error = SleepError;
+        type Args = SleepArgs;
+        type Output = u64;
+
+        async fn definition(&self, _prompt: String) -> ToolDefinition {
+            ToolDefinition {
+                name: Self::NAME.to_string(),
+                description: "Sleep for the given number of milliseconds".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "millis": { "type": "integer" }
+                    },
+                    "required": ["millis"]
+                }),
+            }
+        }
+
+        async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+            tokio::time::sleep(Duration::from_millis(args.millis)).await;
+            Ok(args.millis)
+        }
+    }
+
+    fn sleep_call(id: &str, millis: u64) -> ToolCall {
+        ToolCall {
+            id: id.to_string(),
+            name: Sleep::NAME.to_string(),
+            arguments: json!({ "millis": millis }).to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_call_many_preserves_order() {
+        let toolset = ToolSet::from_tools(vec![Sleep]);
+
+        let results = toolset
+            .call_many(
+                vec![sleep_call("a", 30), sleep_call("b", 1), sleep_call("c", 10)],
+                3,
+                None,
+            )
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(results, vec!["30", "1", "10"]);
+    }
+
+    /// Tool returning only once `n` calls are waiting on its barrier
+    struct Rendezvous(Arc<tokio::sync::Barrier>);
+
+    impl Tool for Rendezvous {
+        const NAME: &'static str = "rendezvous";
+        type Error = std::convert::Infallible;
+        type Args = serde_json::Value;
+        type Output = bool;
+
+        async fn definition(&self, _prompt: String) -> ToolDefinition {
+            ToolDefinition {
+                name: Self::NAME.to_string(),
+                description: "Wait for the other calls".to_string(),
+                parameters: json!({ "type": "object" }),
+            }
+        }
+
+        async fn call(&self, _args: Self::Args) -> Result<Self::Output, Self::Error> {
+            Ok(self.0.wait().await.is_leader())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_call_many_runs_concurrently() {
+        // The barrier only opens once all four calls are in flight at the same time; run
+        // sequentially, the first call would hit the timeout
+        let barrier = Arc::new(tokio::sync::Barrier::new(4));
+        let toolset = ToolSet::from_tools(vec![Rendezvous(barrier)]);
+        let calls = (0..4)
+            .map(|i| ToolCall {
+                id: i.to_string(),
+                name: Rendezvous::NAME.to_string(),
+                arguments: "{}".to_string(),
+            })
+            .collect();
+
+        let results = toolset
+            .call_many(calls, 4, Some(Duration::from_secs(5)))
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(results.iter().filter(|leader| *leader == "true").count(), 1);
+    }
+
+    /// Tool failing on its first `failures` calls
+    pub(crate) struct Flaky {
+        failures: usize,
+        calls: std::sync::atomic::AtomicUsize,
+        delay: Duration,
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("Flaky error")]
+    pub(crate) struct FlakyError;
+
+    impl Tool for Flaky {
+        const NAME: &'static str = "flaky";
+        type Error = FlakyError;
+        type Args = serde_json::Value;
+        type Output = usize;
+
+        async fn definition(&self, _prompt: String) -> ToolDefinition {
+            ToolDefinition {
+                name: Self::NAME.to_string(),
+                description: "Fails a few times before succeeding".to_string(),
+                parameters: json!({ "type": "object" }),
+            }
+        }
+
+        async fn call(&self, _args: Self::Args) -> Result<Self::Output, Self::Error> {
+            let call = self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            tokio::time::sleep(self.delay).await;
+            if call <= self.failures {
+                Err(FlakyError)
+            } else {
+                Ok(call)
+            }
+        }
+    }
+
+    pub(crate) fn flaky(failures: usize) -> Flaky {
+        Flaky {
+            failures,
+            calls: Default::default(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_policy_retries() {
+        let toolset = ToolSet::builder()
+            .static_tool(flaky(2))
+            .policy(
+                "flaky",
+                ToolPolicy::default().retries(2, Duration::from_millis(1)),
+            )
+            .build();
+
+        assert_eq!(toolset.call("flaky", "{}".into()).await.unwrap(), "3");
+    }
+
+    #[tokio::test]
+    async fn test_policy_retries_exhausted() {
+        let toolset = ToolSet::builder()
+            .static_tool(flaky(5))
+            .policy(
+                "flaky",
+                ToolPolicy::default().retries(2, Duration::from_millis(1)),
+            )
+            .build();
+
+        assert!(matches!(
+            toolset.call("flaky", "{}".into()).await,
+            Err(ToolSetError::RetriesExhaustedError(_, 3, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_policy_json_errors_not_retried() {
+        let toolset = ToolSet::builder()
+            .static_tool(Sleep)
+            .policy(
+                "sleep",
+                ToolPolicy::default().retries(2, Duration::from_millis(1)),
+            )
+            .build();
+
+        assert!(matches!(
+            toolset.call("sleep", "not json".into()).await,
+            Err(ToolSetError::ToolCallError(ToolError::JsonError(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_policy_circuit_breaker() {
+        let toolset = ToolSet::builder()
+            .static_tool(flaky(2))
+            .policy(
+                "flaky",
+                ToolPolicy::default().circuit_breaker(2, Duration::from_millis(50)),
+            )
+            .build();
+
+        assert!(toolset.call("flaky", "{}".into()).await.is_err());
+        assert!(toolset.call("flaky", "{}".into()).await.is_err());
+        assert!(matches!(
+            toolset.call("flaky", "{}".into()).await,
+            Err(ToolSetError::CircuitOpenError(_))
+        ));
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(toolset.call("flaky", "{}".into()).await.unwrap(), "3");
+    }
+
+    #[tokio::test]
+    async fn test_policy_circuit_half_open_single_trial() {
+        let toolset = ToolSet::builder()
+            .static_tool(Flaky {
+                delay: Duration::from_millis(20),
+                ..flaky(1)
+            })
+            .policy(
+                "flaky",
+                ToolPolicy::default().circuit_breaker(1, Duration::from_millis(50)),
+            )
+            .build();
+
+        assert!(toolset.call("flaky", "{}".into()).await.is_err());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        // Only the first call is let through while the trial is running
+        let (trial, other) = futures::join!(
+            toolset.call("flaky", "{}".into()),
+            toolset.call("flaky", "{}".into())
+        );
+        assert_eq!(trial.unwrap(), "2");
+        assert!(matches!(other, Err(ToolSetError::CircuitOpenError(_))));
+
+        // The successful trial closed the circuit
+        assert_eq!(toolset.call("flaky", "{}".into()).await.unwrap(), "3");
+    }
+
+    #[tokio::test]
+    async fn test_policy_circuit_ignores_invalid_arguments() {
+        let toolset = ToolSet::builder()
+            .static_tool(Sleep)
+            .policy(
+                "sleep",
+                ToolPolicy::default().circuit_breaker(1, Duration::from_secs(60)),
+            )
+            .build();
+
+        assert!(toolset.call("sleep", "not json".into()).await.is_err());
+        assert!(toolset
+            .call("sleep", json!({ "millis": "1" }).to_string())
+            .await
+            .is_err());
+        assert_eq!(
+            toolset
+                .call("sleep", json!({ "millis": 1 }).to_string())
+                .await
+                .unwrap(),
+            "1"
+        );
+    }
+
+    /// Approval handler answering every call with the same decision
+    pub(crate) struct Decide(pub(crate) Approval);
+
+    impl ApprovalHandler for Decide {
+        fn approve<'a>(
+            &'a self,
+            _toolname: &'a str,
+            _args: &'a str,
+        ) -> Pin<Box<dyn Future<Output = Approval> + Send + 'a>> {
+            Box::pin(async move { self.0.clone() })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_approval_rejection_is_tool_result() {
+        let toolset = ToolSet::builder()
+            .static_tool(flaky(0))
+            .require_approval("flaky")
+            .approval_handler(Decide(Approval::Reject("not today".into())))
+            .build();
+
+        let result = toolset.call("flaky", "{}".into()).await.unwrap();
+        assert_eq!(result, rejection_message("flaky", "not today"));
+    }
+
+    #[tokio::test]
+    async fn test_approval_edit_arguments() {
+        let toolset = ToolSet::builder()
+            .static_tool(Sleep)
+            .require_approval("sleep")
+            .approval_handler(Decide(Approval::Edit(json!({ "millis": 2 }).to_string())))
+            .build();
+
+        let result = toolset
+            .call("sleep", json!({ "millis": 1000 }).to_string())
+            .await
+            .unwrap();
+        assert_eq!(result, "2");
+    }
+
+    #[tokio::test]
+    async fn test_approval_without_handler_rejects() {
+        let toolset = ToolSet::builder()
+            .static_tool(flaky(0))
+            .require_approval("flaky")
+            .build();
+
+        let result = toolset.call("flaky", "{}".into()).await.unwrap();
+        assert!(result.starts_with("The call to tool `flaky` was rejected"));
+    }
+
+    /// Tool counting how many times its definition is requested
+    #[derive(Default)]
+    struct CountDefinitions(std::sync::atomic::AtomicUsize);
+
+    impl Tool for CountDefinitions {
+        const NAME: &'static str = "count";
+        type Error = std::convert::Infallible;
+        type Args = serde_json::Value;
+        type Output = ();
+
+        async fn definition(&self, _prompt: String) -> ToolDefinition {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            ToolDefinition {
+                name: Self::NAME.to_string(),
+                description: "Count definitions".to_string(),
+                parameters: json!({ "type": "object" }),
+            }
+        }
+
+        async fn call(&self, _args: Self::Args) -> Result<Self::Output, Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_schema_computed_once() {
+        let tool = ValidatedTool::new(CountDefinitions::default());
+        for _ in 0..3 {
+            ToolDyn::call(&tool, "{}".into()).await.unwrap();
+        }
+        assert!(ToolDyn::call(&tool, "[]".into()).await.is_err());
+        assert_eq!(tool.tool.0.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_arguments_validated_against_schema() {
+        let toolset = ToolSet::from_tools(vec![Sleep]);
+
+        let result = toolset
+            .call("sleep", json!({ "millis": "ten" }).to_string())
+            .await;
+
+        let Err(ToolSetError::ToolCallError(ToolError::ValidationError(error))) = result else {
+            panic!("expected a validation error, got {result:?}");
+        };
+        assert_eq!(error.violations.len(), 1);
+        assert_eq!(error.violations[0].path, "$.millis");
+    }
+
+    #[tokio::test]
+    async fn test_call_many_timeout() {
+        let toolset = ToolSet::from_tools(vec![Sleep]);
+
+        let results = toolset
+            .call_many(
+                vec![sleep_call("a", 1), sleep_call("b", 500)],
+                2,
+                Some(Duration::from_millis(50)),
+            )
+            .await;
+
+        assert_eq!(results[0].as_deref().unwrap(), "1");
+        assert!(matches!(
+            results[1],
+            Err(ToolSetError::TimeoutError(ref name, _)) if name == "sleep"
+        ));
+    }
+
+    #[cfg(feature = "derive")]
+    mod tool_macro {
+        use serde_json::json;
+
+        use crate::tool::{Tool, ToolSet};
+
+        #[derive(Debug, thiserror::Error)]
+        #[error("Division by zero")]
+        pub struct DivisionByZero;
+
+        /// Divide x by y.
+        /// Fails if y is zero.
+        #[crate::tool]
+        pub async fn divide(
+            /// The dividend
+            x: f64,
+            /// The divisor
+            y: f64,
+        ) -> Result<f64, DivisionByZero> {
+            if y == 0.0 {
+                return Err(DivisionByZero);
+            }
+            Ok(x / y)
+        }
+
+        #[crate::tool(name = "shout", description = "Uppercase a text")]
+        async fn to_upper(text: String) -> Result<String, DivisionByZero> {
+            Ok(text.to_uppercase())
+        }
+
+        #[tokio::test]
+        async fn test_definition_from_function() {
+            let definition = Divide.definition(String::new()).await;
+            assert_eq!(definition.name, "divide");
+            assert_eq!(
+                definition.description,
+                "Divide x by y.\nFails if y is zero."
+            );
+            assert_eq!(definition.parameters["type"], "object");
+            assert_eq!(
+                definition.parameters["properties"]["x"]["description"],
+                "The dividend"
+            );
+            assert_eq!(definition.parameters["required"], json!(["x", "y"]));
+
+            let definition = ToUpper.definition(String::new()).await;
+            assert_eq!(definition.name, "shout");
+            assert_eq!(definition.description, "Uppercase a text");
+        }
+
+        #[tokio::test]
+        async fn test_call_generated_tool() {
+            let toolset = ToolSet::from_tools(vec![Divide]);
+
+            let result = toolset
+                .call("divide", json!({ "x": 3.0, "y": 2.0 }).to_string())
+                .await
+                .unwrap();
+            assert_eq!(result, "1.5");
+
+            assert!(toolset
+                .call("divide", json!({ "x": 3.0, "y": 0.0 }).to_string())
+                .await
+                .is_err());
+            assert!(toolset
+                .call("divide", json!({ "x": 3.0 }).to_string())
+                .await
+                .is_err());
         }
     }
 }
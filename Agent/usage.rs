@@ -0,0 +1,358 @@
+//! Token usage, latency and cost accounting of agents.
+//!
+//! A [UsageTracker] is attached to one or more agents with
+//! [AgentBuilder::usage_tracker](crate::agent::AgentBuilder::usage_tracker). Every completion
+//! sent by these agents is recorded with its token usage, latency and estimated cost (from the
+//! tracker's [PriceTable]), and every tool call with its latency and outcome. Usage is
+//! aggregated per agent, per model, per tool and per pipeline run, and can be exported as JSON.
+//!
+//! Token usage is taken from the provider's response when the model's response type implements
+//! [ResponseUsage] (see [AgentBuilder::provider_usage](crate::agent::AgentBuilder::provider_usage)),
+//! and estimated with a [Tokenizer] otherwise.
+//!
+//! # Example
+//! ```
+//! use Hydranta::usage::{ModelPrice, PriceTable, UsageTracker};
+//!
+//! let tracker = UsageTracker::new(
+//!     PriceTable::default().price("qbt-1.a", ModelPrice::per_million(0.15, 0.60)),
+//! );
+//!
+//! let agent = Hydranta
+//!     .agent("qbt-1.a")
+//!     .name("researcher")
+//!     .usage_tracker(tracker.clone(), "qbt-1.a")
+//!     .build();
+//!
+//! // Attribute all completions of the pipeline to the run "daily-report"
+//! let report = tracker.pipeline_run("daily-report", pipeline.call(input)).await;
+//!
+//! println!("{}", tracker.to_json());
+//! ```
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    completion::{CompletionRequest, ModelChoice},
+    token_budget::Tokenizer,
+};
+
+tokio::task_local! {
+    /// Name of the pipeline run the current task is part of
+    static PIPELINE_RUN: String;
+}
+
+/// Wrap `future` to run it in the pipeline run of the current task, if any, e.g.: to carry
+/// the run over to a spawned task
+pub(crate) fn in_current_pipeline_run<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let run = PIPELINE_RUN.try_with(|run| run.clone()).ok();
+    async move {
+        match run {
+            Some(run) => PIPELINE_RUN.scope(run, future).await,
+            None => future.await,
+        }
+    }
+}
+
+/// Number of tokens of a completion request and of its response
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl TokenUsage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    /// Estimate the number of prompt tokens of `request` with `tokenizer`
+    pub fn estimate_prompt(tokenizer: &dyn Tokenizer, request: &CompletionRequest) -> u64 {
+        let preamble = request
+            .preamble
+            .as_deref()
+            .map_or(0, |preamble| tokenizer.count_tokens(preamble));
+        let chat_history = request
+            .chat_history
+            .iter()
+            .map(|message| tokenizer.count_tokens(&message.content))
+            .sum::<usize>();
+        let tools = request
+            .tools
+            .iter()
+            .map(|tool| tokenizer.count_tokens(&serde_json::to_string(tool).unwrap_or_default()))
+            .sum::<usize>();
+
+        (preamble + chat_history + tools + tokenizer.count_tokens(&request.prompt_with_context()))
+            as u64
+    }
+
+    /// Estimate the number of completion tokens of `choice` with `tokenizer`
+    pub fn estimate_completion(tokenizer: &dyn Tokenizer, choice: &ModelChoice) -> u64 {
+        let tokens = match choice {
+            ModelChoice::Message(text) => tokenizer.count_tokens(text),
+            ModelChoice::ToolCall(name, args) => {
+                tokenizer.count_tokens(name) + tokenizer.count_tokens(&args.to_string())
+            }
+        };
+        tokens as u64
+    }
+}
+
+/// Implemented by provider response types that report the token usage of the completion
+pub trait ResponseUsage {
+    fn token_usage(&self) -> Option<TokenUsage>;
+}
+
+/// Price of a model, in dollars per token
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl ModelPrice {
+    /// Price from the dollars per million prompt and completion tokens, as usually listed
+    /// by providers
+    pub fn per_million(prompt: f64, completion: f64) -> Self {
+        Self {
+            prompt: prompt / 1_000_000.0,
+            completion: completion / 1_000_000.0,
+        }
+    }
+
+    pub fn cost(&self, usage: &TokenUsage) -> f64 {
+        usage.prompt_tokens as f64 * self.prompt + usage.completion_tokens as f64 * self.completion
+    }
+}
+
+/// Prices of the models, by model name. Usage of models missing from the table costs nothing.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PriceTable {
+    prices: HashMap<String, ModelPrice>,
+}
+
+impl PriceTable {
+    /// Set the price of `model`
+    pub fn price(mut self, model: &str, price: ModelPrice) -> Self {
+        self.prices.insert(model.to_string(), price);
+        self
+    }
+
+    pub fn cost(&self, model: &str, usage: &TokenUsage) -> f64 {
+        self.prices
+            .get(model)
+            .map_or(0.0, |price| price.cost(usage))
+    }
+}
+
+/// Aggregated usage of completions
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct UsageStats {
+    pub requests: u64,
+    pub failed_requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Total latency of the requests
+    #[serde(rename = "latency_ms", serialize_with = "serialize_millis")]
+    pub latency: Duration,
+    /// Estimated cost, in dollars
+    pub cost: f64,
+}
+
+impl UsageStats {
+    fn add(&mut self, usage: &TokenUsage, latency: Duration, cost: f64) {
+        self.requests += 1;
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        self.latency += latency;
+        self.cost += cost;
+    }
+
+    fn add_failure(&mut self, latency: Duration) {
+        self.requests += 1;
+        self.failed_requests += 1;
+        self.latency += latency;
+    }
+}
+
+/// Aggregated usage of a tool
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ToolStats {
+    pub calls: u64,
+    pub failed_calls: u64,
+    /// Total latency of the calls
+    #[serde(rename = "latency_ms", serialize_with = "serialize_millis")]
+    pub latency: Duration,
+}
+
+/// Serialize a duration as a (fractional) number of milliseconds
+pub(crate) fn serialize_millis<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+/// Snapshot of the usage recorded by a [UsageTracker]
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct UsageReport {
+    pub total: UsageStats,
+    pub agents: BTreeMap<String, UsageStats>,
+    pub models: BTreeMap<String, UsageStats>,
+    pub tools: BTreeMap<String, ToolStats>,
+    pub pipeline_runs: BTreeMap<String, UsageStats>,
+}
+
+/// Shared recorder of the usage of agents. Clones record into the same report.
+#[derive(Clone, Default)]
+pub struct UsageTracker {
+    prices: Arc<PriceTable>,
+    report: Arc<Mutex<UsageReport>>,
+}
+
+impl UsageTracker {
+    pub fn new(prices: PriceTable) -> Self {
+        Self {
+            prices: Arc::new(prices),
+            report: Default::default(),
+        }
+    }
+
+    /// Run `future`, attributing the completions it sends to the pipeline run `name`.
+    /// Completions sent from tasks spawned by `future` are not attributed to the run, except
+    /// for agents called through an [AgentTool](crate::agent_tool::AgentTool).
+    pub async fn pipeline_run<F: Future>(&self, name: &str, future: F) -> F::Output {
+        PIPELINE_RUN.scope(name.to_string(), future).await
+    }
+
+    /// Record a completion of `agent` using `model`, returning its estimated cost
+    pub fn record_completion(
+        &self,
+        agent: &str,
+        model: &str,
+        usage: TokenUsage,
+        latency: Duration,
+    ) -> f64 {
+        let cost = self.prices.cost(model, &usage);
+        tracing::debug!(target: "rig",
+            "Agent {agent} used {} prompt and {} completion tokens of {model} in {latency:?} (${cost:.6})",
+            usage.prompt_tokens,
+            usage.completion_tokens
+        );
+
+        self.update(agent, model, |stats| stats.add(&usage, latency, cost));
+        cost
+    }
+
+    /// Record a failed completion request of `agent` using `model`
+    pub fn record_failed_completion(&self, agent: &str, model: &str, latency: Duration) {
+        self.update(agent, model, |stats| stats.add_failure(latency));
+    }
+
+    /// Record a call of the tool `toolname`
+    pub fn record_tool_call(&self, toolname: &str, latency: Duration, success: bool) {
+        let mut report = self.report.lock().unwrap();
+        let stats = report.tools.entry(toolname.to_string()).or_default();
+        stats.calls += 1;
+        stats.latency += latency;
+        if !success {
+            stats.failed_calls += 1;
+        }
+    }
+
+    /// Snapshot of the usage recorded so far
+    pub fn report(&self) -> UsageReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    /// Usage recorded so far, as JSON
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self.report()).expect("Usage report should serialize")
+    }
+
+    /// Clear the usage recorded so far
+    pub fn reset(&self) {
+        *self.report.lock().unwrap() = UsageReport::default();
+    }
+
+    fn update(&self, agent: &str, model: &str, mut f: impl FnMut(&mut UsageStats)) {
+        let run = PIPELINE_RUN.try_with(|run| run.clone()).ok();
+
+        let mut report = self.report.lock().unwrap();
+        f(&mut report.total);
+        f(report.agents.entry(agent.to_string()).or_default());
+        f(report.models.entry(model.to_string()).or_default());
+        if let Some(run) = run {
+            f(report.pipeline_runs.entry(run).or_default());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn tracker() -> UsageTracker {
+        UsageTracker::new(PriceTable::default().price("small", ModelPrice::per_million(1.0, 2.0)))
+    }
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens,
+            completion_tokens,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_aggregates_per_agent_model_and_run() {
+        let tracker = tracker();
+        let cost = tracker.record_completion(
+            "researcher",
+            "small",
+            usage(1000, 500),
+            Duration::from_millis(200),
+        );
+        assert!((cost - 0.002).abs() < 1e-12);
+
+        tracker
+            .pipeline_run("run-1", async {
+                tracker.record_completion("writer", "small", usage(10, 10), Duration::ZERO);
+                tracker.record_completion("writer", "unknown", usage(10, 10), Duration::ZERO);
+            })
+            .await;
+        tracker.record_failed_completion("writer", "small", Duration::from_millis(50));
+
+        let report = tracker.report();
+        assert_eq!(report.total.requests, 4);
+        assert_eq!(report.total.failed_requests, 1);
+        assert_eq!(report.total.prompt_tokens, 1020);
+        assert_eq!(report.agents["writer"].requests, 3);
+        assert_eq!(report.models["unknown"].cost, 0.0);
+        assert_eq!(report.pipeline_runs["run-1"].requests, 2);
+        assert_eq!(report.pipeline_runs.len(), 1);
+    }
+
+    #[test]
+    fn test_json_export() {
+        let tracker = tracker();
+        tracker.record_tool_call("add", Duration::from_millis(3), true);
+        tracker.record_tool_call("add", Duration::from_millis(1), false);
+
+        assert_eq!(
+            tracker.to_json()["tools"],
+            json!({"add": {"calls": 2, "failed_calls": 1, "latency_ms": 4.0}})
+        );
+
+        tracker.reset();
+        assert_eq!(tracker.report(), UsageReport::default());
+    }
+}
@@ -0,0 +1,77 @@
+//! Example MCP server exposing a [ToolSet] over stdio, to be launched by MCP hosts (e.g.:
+//! Claude Desktop, IDEs or another agent using [McpClient](rig::mcp::McpClient)).
+//!
+//! It serves a single `add` tool: copy it and replace [toolset] with the tools to expose;
+//! static and embedding tools are served alike. Stdout carries the protocol messages,
+//! nothing else may be printed to it.
+//!
+//! Build it with `cargo build --example mcp_server` and point the host to the binary:
+//! ```json
+//! {
+//!     "mcpServers": {
+//!         "hydranta": { "command": "/path/to/target/debug/examples/mcp_server" }
+//!     }
+//! }
+//! ```
+use serde::Deserialize;
+use serde_json::json;
+
+use rig::{
+    completion::ToolDefinition,
+    mcp::McpServer,
+    tool::{Tool, ToolSet},
+};
+
+#[derive(Deserialize)]
+struct OperationArgs {
+    x: i64,
+    y: i64,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Math error: {0}")]
+struct MathError(String);
+
+struct Adder;
+
+impl Tool for Adder {
+    const NAME: &'static str = "add";
+
+    type Error = MathError;
+    type Args = OperationArgs;
+    type Output = i64;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        ToolDefinition {
+            name: Self::NAME.to_string(),
+            description: "Add x and y together".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "x": { "type": "integer", "description": "The first number to add" },
+                    "y": { "type": "integer", "description": "The second number to add" }
+                },
+                "required": ["x", "y"]
+            }),
+        }
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<i64, MathError> {
+        args.x
+            .checked_add(args.y)
+            .ok_or_else(|| MathError("overflow".into()))
+    }
+}
+
+/// The tools served
+fn toolset() -> ToolSet {
+    ToolSet::builder().static_tool(Adder).build()
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    McpServer::new("hydranta", env!("CARGO_PKG_VERSION"), toolset())
+        .serve_stdio()
+        .await?;
+    Ok(())
+}